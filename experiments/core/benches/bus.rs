@@ -0,0 +1,104 @@
+//! Benchmarks of message flows built from the `testing` handlers: a deep send chain, a wide
+//! publish fan-out and subscription churn. Run with `cargo bench --features testing`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use core::testing::{create_actor_handler, run_and_collect_trace, ActorGraph, Trace};
+use core::{MessageBus, PublishTask, Task, TaskRunner};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// The chain lengths, subscriber counts and churn sizes benchmarked.
+const SIZES: [usize; 3] = [10, 100, 1000];
+
+/// Actor `i` sends to actor `i + 1`, the last sending nowhere.
+fn chain(len: usize) -> ActorGraph {
+    ActorGraph {
+        edges: (0..len)
+            .map(|actor| {
+                if actor + 1 < len {
+                    vec![actor + 1]
+                } else {
+                    Vec::new()
+                }
+            })
+            .collect(),
+    }
+}
+
+fn send_chain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_chain");
+    for len in SIZES {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        let graph = chain(len);
+        graph.register(&mut runner, &trace);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, _| {
+            b.iter(|| {
+                let root = graph.root(&runner);
+                run_and_collect_trace(&mut runner, root, &trace)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn publish_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish_fan_out");
+    for subscribers in SIZES {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        for i in 0..subscribers {
+            runner.msg_bus.subscribe(create_actor_handler(
+                format!("sub_{i}"),
+                "fan_out".to_string(),
+                Vec::new(),
+                trace.clone(),
+            ));
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    let root = Task::Publish(PublishTask::new("fan_out", Rc::new(())));
+                    run_and_collect_trace(&mut runner, root, &trace)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn subscription_churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("subscription_churn");
+    for subscriptions in SIZES {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let handler_ids: Vec<String> = (0..subscriptions).map(|i| format!("sub_{i}")).collect();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(subscriptions),
+            &subscriptions,
+            |b, _| {
+                b.iter(|| {
+                    let mut bus = MessageBus::new();
+                    for handler_id in &handler_ids {
+                        bus.subscribe(create_actor_handler(
+                            handler_id.clone(),
+                            "churn".to_string(),
+                            Vec::new(),
+                            trace.clone(),
+                        ));
+                    }
+                    for handler_id in &handler_ids {
+                        bus.remove_subscription("churn", handler_id);
+                    }
+                    bus
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, send_chain, publish_fan_out, subscription_churn);
+criterion_main!(benches);
@@ -4,9 +4,7 @@ extern crate cbindgen;
 
 use std::env;
     // Posei Experiments: Import optimization
-use std::fs;
     // Posei Experiments: Import optimization
-use std::io::Write;
     // Posei Experiments: Import optimization
 use std::path::PathBuf;
 
@@ -37,31 +35,6 @@ fn main() {
     cbindgen::generate_with_config(&crate_dir, config_cython)
         .expect("Unable to generate bindings")
         .write_to_file(&pxd_path);
-
-
-        // Posei Experiments: Enhancement for Posei Experiments integration - 20260101
-    let content = fs::read_to_string(&pxd_path).expect("Unable to read .pxd file");
-    let lines: Vec<&str> = content.lines().collect();
-
-    let mut output = String::new();
-    let mut found_extern = false;
-    let mut found_extern = false;
-
-    for line in lines {
-        output.push_str(line);
-        output.push('\n');
-
-        if line.trim().starts_with("cdef extern from") && !found_extern {
-            output.push_str("    ctypedef unsigned long long uint128_t\n");
-            output.push_str("    ctypedef long long int128_t\n");
-            found_extern = true;
-        }
-    }
-
-    // Write the modified content back to the file
-    let mut file = fs::File::create(&pxd_path).expect("Unable to open .pxd file for writing");
-    file.write_all(output.as_bytes())
-        .expect("Unable to write to .pxd file");
 }
 
 
@@ -0,0 +1,52 @@
+//! Types for passing values across the C and Cython boundary.
+//!
+//! C has no portable 128-bit integer, and Cython maps one onto a 64-bit type, silently
+//! truncating it. 128-bit values therefore cross the boundary as a pair of 64-bit halves,
+//! which cbindgen exports as `uint128_t` and `int128_t`.
+
+/// A `u128` split into its high and low 64 bits.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct UInt128 {
+    pub hi: u64,
+    pub lo: u64,
+}
+
+impl From<u128> for UInt128 {
+    fn from(value: u128) -> Self {
+        Self {
+            hi: (value >> 64) as u64,
+            lo: value as u64,
+        }
+    }
+}
+
+impl From<UInt128> for u128 {
+    fn from(value: UInt128) -> Self {
+        (u128::from(value.hi) << 64) | u128::from(value.lo)
+    }
+}
+
+/// An `i128` split into the high and low 64 bits of its two's complement representation.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Int128 {
+    pub hi: u64,
+    pub lo: u64,
+}
+
+impl From<i128> for Int128 {
+    fn from(value: i128) -> Self {
+        let UInt128 { hi, lo } = UInt128::from(value as u128);
+        Self { hi, lo }
+    }
+}
+
+impl From<Int128> for i128 {
+    fn from(value: Int128) -> Self {
+        u128::from(UInt128 {
+            hi: value.hi,
+            lo: value.lo,
+        }) as i128
+    }
+}
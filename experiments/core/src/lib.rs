@@ -1,690 +1,6478 @@
-#![feature(coroutines)]
-#![feature(coroutine_trait)]
-#![feature(stmt_expr_attributes)]
-#![feature(trait_alias)]
+#![cfg_attr(all(test, not(feature = "stable")), feature(coroutines))]
+#![cfg_attr(not(feature = "stable"), feature(coroutine_trait))]
+#![cfg_attr(all(test, not(feature = "stable")), feature(stmt_expr_attributes))]
 
 use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::cell::RefCell;
     // Posei Experiments: Import optimization
 use std::boxed::Box;
     // Posei Experiments: Import optimization
+use std::cmp::Reverse;
+use std::collections::hash_map;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
     // Posei Experiments: Import optimization
 use std::fmt;
     // Posei Experiments: Import optimization
 use std::fmt::Display;
+#[cfg(feature = "serde")]
+use std::io::{self, BufRead, BufReader, Read, Write};
     // Posei Experiments: Import optimization
 use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
     // Posei Experiments: Import optimization
 use std::pin::Pin;
     // Posei Experiments: Import optimization
 use std::rc::Rc;
+use std::str::Split;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
     // Posei Experiments: Import optimization
 
 // We use futures' LocalBoxFuture only in earlier examples; here we use experimental coroutines.
 // (The coroutine feature requires that you compile with nightly.)
 // The Coroutine and CoroutineState traits (and its implementations) are provided by the compiler.
 // (Their definitions are shown in your attached snippet.)
+//
+// With the `stable` feature the crate builds on a stable toolchain instead: the traits come
+// from the `stable` module and handlers are state machines implementing them by hand, since
+// `#[coroutine]` closures are unavailable. Either way they are re-exported here, so handler
+// code can name `Coroutine` and `CoroutineState` through this crate on both backends.
 
-use core::ops::Coroutine;
-use core::ops::CoroutineState;
+#[cfg(not(feature = "stable"))]
+pub use core::ops::{Coroutine, CoroutineState};
+#[cfg(feature = "stable")]
+pub use stable::{Coroutine, CoroutineState};
     // Posei Experiments: Enhancement for Posei Experiments integration - 20260101
 
-/// A command that a coroutine can yield. Here we support sending a message and handler registration.
-    // Posei Experiments: Type enhancement for Posei Experiments
-    // Posei Experiments: Type enhancement for Posei Experiments
-    // Posei Experiments: Type enhancement for Posei Experiments
-    // Posei Experiments: Type enhancement for Posei Experiments
-pub enum Command {
-    Send {
-        topic: String,
-        // Boxed dynamic message.
-        msg: Rc<dyn Any>,
-    },
-    Publish {
-        pattern: String,
-            // Posei Experiments: Enhancement for Posei Experiments integration - 20260101
-        msg: Rc<dyn Any>,
-    },
-    /// Register an endpoint subscription
-    Register(Subscription),
-    /// Deregister an endpoint subscription
-    Deregister(String),
-    /// Subscribe to a topic
-    Subscribe(Subscription),
-    /// Unsubscribe from a topic
-    Unsubscribe((String, String)),
+// Structured runner events, compiled out unless the `logging` feature is enabled.
+#[cfg(feature = "logging")]
+macro_rules! bus_trace {
+    ($($arg:tt)+) => { log::trace!($($arg)+) };
+}
+#[cfg(not(feature = "logging"))]
+macro_rules! bus_trace {
+    // Only borrows the values in a closure that is never called, so they count as used
+    // without being evaluated.
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {{
+        $(let _ = || {
+            let _ = &$value;
+        };)+
+    }};
+}
+#[cfg(feature = "logging")]
+macro_rules! bus_debug {
+    ($($arg:tt)+) => { log::debug!($($arg)+) };
+}
+#[cfg(not(feature = "logging"))]
+macro_rules! bus_debug {
+    // Only borrows the values in a closure that is never called, so they count as used
+    // without being evaluated.
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {{
+        $(let _ = || {
+            let _ = &$value;
+        };)+
+    }};
 }
 
-pub type ActorCoroutine = Pin<Box<dyn Coroutine<Rc<dyn Any>, Yield = Command, Return = ()>>>;
-pub type ActorFn = Box<dyn Fn() -> ActorCoroutine>;
+pub mod ffi;
+#[cfg(feature = "stable")]
+pub mod stable;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-pub struct PublishTask {
-    pattern: String,
-    msg: Rc<dyn Any>,
-    idx: usize,
+/// A topic name. Cheap to clone, and constructible in `const` context so topics can be
+/// declared once (e.g. as associated consts) instead of repeated as string literals.
+#[derive(Clone)]
+pub struct Topic(TopicRepr);
+
+#[derive(Clone)]
+enum TopicRepr {
+    Static(&'static str),
+    Shared(Rc<str>),
 }
 
-    // Posei Experiments: Implementation enhancement for Posei Experiments
-    // Posei Experiments: Implementation enhancement for Posei Experiments
-impl Display for PublishTask {
-    // Posei Experiments: Enhanced function documentation
-    // Posei Experiments: Enhanced function documentation
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "PublishTask(pattern: {}, idx: {})",
-            self.pattern, self.idx
-        )
+impl Topic {
+    pub const fn from_static(name: &'static str) -> Self {
+        Self(TopicRepr::Static(name))
     }
-}
 
-impl PublishTask {
-    pub fn new(pattern: String, msg: Rc<dyn Any>) -> Self {
-        Self {
-            pattern,
-            msg,
-            idx: 0,
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            TopicRepr::Static(name) => name,
+            TopicRepr::Shared(name) => name,
         }
     }
+}
 
-    // Dummy implementation
-    pub fn next_task(&mut self, msg_bus: &MessageBus) -> Option<SendTask> {
-        let sub = msg_bus
-            .subscriptions
-            .iter()
-            .filter(|(_sub, pattern)| pattern.contains(&self.pattern))
-            .map(|(sub, _)| sub)
-            .nth(self.idx);
+/// Builds a `Topic` from a string literal, usable in `const` items.
+#[macro_export]
+macro_rules! topic {
+    ($name:expr) => {
+        $crate::Topic::from_static($name)
+    };
+}
 
-        sub.map(|sub| {
-            self.idx += 1;
-            let actor_fn = (sub.actor_fn)();
-            Some(SendTask::new(
-                self.pattern.clone(),
-                actor_fn,
-                self.msg.clone(),
-            ))
-        })
-        .flatten()
+impl Deref for Topic {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
     }
 }
 
-pub struct SendTask {
-    pattern: String,
-    coro: ActorCoroutine,
-    msg: Rc<dyn Any>,
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
 }
 
-impl Display for SendTask {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "SendTask: {}", self.pattern)
+impl std::borrow::Borrow<str> for Topic {
+    fn borrow(&self) -> &str {
+        self.as_str()
     }
 }
 
-impl SendTask {
-    pub fn new(pattern: String, coro: ActorCoroutine, msg: Rc<dyn Any>) -> Self {
-        Self { pattern, coro, msg }
+impl Hash for Topic {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
     }
+}
 
-    pub fn resume(&mut self) -> CoroutineState<Command, ()> {
-        let msg = self.msg.clone();
-        self.coro.as_mut().resume(msg)
+impl PartialEq for Topic {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
     }
 }
 
-pub enum Task {
-    Send(SendTask),
-    Publish(PublishTask),
-}
+impl Eq for Topic {}
 
-impl Display for Task {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Task::Send(send) => writeln!(f, "{}", send),
-            Task::Publish(publish) => writeln!(f, "{}", publish),
-        }
+impl PartialEq<str> for Topic {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
     }
 }
 
-#[derive(Default)]
-pub struct TaskRunner {
-    pub tasks: Vec<Task>,
-    pub msg_bus: MessageBus,
+impl PartialEq<&str> for Topic {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
 }
 
-impl Display for TaskRunner {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "TaskRunner")?;
-        writeln!(f, "tasks:")?;
-        for task in &self.tasks {
-            writeln!(f, "{}", task)?;
-        }
-        Ok(())
+impl PartialOrd for Topic {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl TaskRunner {
-    pub fn new() -> Self {
-        Self {
-            tasks: Vec::new(),
-            msg_bus: MessageBus::new(),
-        }
+impl Ord for Topic {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
     }
+}
 
-    pub fn push(&mut self, task: Task) {
-        self.tasks.push(task);
+impl From<&str> for Topic {
+    fn from(name: &str) -> Self {
+        Self(TopicRepr::Shared(Rc::from(name)))
     }
+}
 
-    pub fn pop(&mut self) -> Option<Task> {
-        self.tasks.pop()
+impl From<String> for Topic {
+    fn from(name: String) -> Self {
+        Self(TopicRepr::Shared(Rc::from(name)))
     }
+}
 
-    pub fn step(&mut self) {
-        match self.tasks.last_mut() {
-            Some(Task::Send(send)) => {
-                match send.resume() {
-                    CoroutineState::Yielded(cmd) => {
-                        // Process the yielded command.
-                        match cmd {
-                            Command::Send { topic, msg } => {
-                                if let Some(sub) = self.msg_bus.endpoints.get(&topic) {
-                                    let coro = (sub.actor_fn)();
-                                    self.push(Task::Send(SendTask::new(topic, coro, msg)));
-                                }
-                            }
-                            Command::Register(subscription) => {
-                                self.msg_bus.register(subscription);
-                            }
-                            Command::Deregister(topic) => {
-                                self.msg_bus.deregister(&topic);
-                            }
-                            Command::Subscribe(subscription) => {
-                                self.msg_bus.subscribe(subscription);
-                            }
-                            Command::Unsubscribe((topic, handler_id)) => {
-                                self.msg_bus.remove_subscription(&topic, &handler_id);
-                            }
-                            Command::Publish { pattern, msg } => {
-                                self.push(Task::Publish(PublishTask::new(pattern, msg)));
-                            }
-                        }
-                    }
-                    CoroutineState::Complete(_) => {
-                        self.tasks.pop();
-                    }
-                }
-            }
-            Some(Task::Publish(publish)) => match publish.next_task(&self.msg_bus) {
-                Some(send) => self.push(Task::Send(send)),
-                None => {
-                    self.tasks.pop();
-                }
-            },
-            None => {}
-        }
+impl From<Rc<str>> for Topic {
+    fn from(name: Rc<str>) -> Self {
+        Self(TopicRepr::Shared(name))
     }
+}
 
-    pub fn run(&mut self) {
-        while !self.tasks.is_empty() {
-            self.step();
+/// A borrowed name is kept as a static topic without allocating.
+impl From<Cow<'static, str>> for Topic {
+    fn from(name: Cow<'static, str>) -> Self {
+        match name {
+            Cow::Borrowed(name) => Self::from_static(name),
+            Cow::Owned(name) => Self::from(name),
         }
     }
 }
 
-pub struct Subscription {
-    /// The shareable message handler for the subscription.
-    pub actor_fn: ActorFn,
-    /// Store a copy of the handler ID for faster equality checks.
-    pub handler_id: String,
-    /// The topic for the subscription.
-    pub topic: String,
-    /// The priority for the subscription determines the ordering of handlers receiving
-    /// messages being processed, higher priority handlers will receive messages before
-    /// lower priority handlers.
-    pub priority: u8,
+impl fmt::Debug for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
 }
 
-impl Hash for Subscription {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.topic.hash(state);
-        self.handler_id.hash(state);
+impl Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
-impl PartialEq for Subscription {
-    fn eq(&self, other: &Self) -> bool {
-        self.topic == other.topic && self.handler_id == other.handler_id
+/// A command that a coroutine can yield. Here we support sending a message and handler registration.
+    // Posei Experiments: Type enhancement for Posei Experiments
+    // Posei Experiments: Type enhancement for Posei Experiments
+    // Posei Experiments: Type enhancement for Posei Experiments
+    // Posei Experiments: Type enhancement for Posei Experiments
+#[non_exhaustive]
+pub enum Command {
+    Send {
+        topic: Topic,
+        // Boxed dynamic message.
+        msg: Rc<dyn Any>,
+    },
+    /// Send to the endpoint on `topic`, moving the payload into its handler instead of
+    /// sharing it. The handler takes it with `Envelope::take_owned`.
+    SendOwned {
+        topic: Topic,
+        msg: Box<dyn Any>,
+    },
+    /// Send like `Send`, adding `headers` to those the yielding handler was delivered with
+    /// and replacing any with the same name. The yielding handler's own headers are
+    /// unchanged.
+    SendWithHeaders {
+        topic: Topic,
+        msg: Rc<dyn Any>,
+        headers: Headers,
+    },
+    /// Send like `Send`, with a message priority that a `PriorityStore` takes the spawned
+    /// sends in. Unlike a subscription's priority, which orders the handlers a message is
+    /// delivered to, it orders messages pending for any handler. Plain sends and the
+    /// tasks the handlers spawn have priority 0.
+    SendPrioritized {
+        topic: Topic,
+        msg: Rc<dyn Any>,
+        msg_priority: u8,
+    },
+    /// Send to the highest-priority endpoint on `topic` only, rather than to all of them.
+    SendBest {
+        topic: Topic,
+        msg: Rc<dyn Any>,
+    },
+    /// Send a copy to the endpoints on `topic` without waiting on them. With a store that
+    /// takes the newest task first, such as the default `StackStore`, a `Send`'s endpoints
+    /// run before the yielding handler is resumed, while a tee's run only once the handler
+    /// has been resumed again, so it carries on first. A topic without endpoints
+    /// dead-letters the copy.
+    Tee { topic: Topic, msg: Rc<dyn Any> },
+    /// Send to the endpoints on `topic`, then publish to `topic` so its subscribers observe
+    /// the message too. The endpoints are delivered to first. The message is dead-lettered
+    /// only if `topic` has neither endpoints nor subscribers.
+    SendAndNotify { topic: Topic, msg: Rc<dyn Any> },
+    /// Deliver to every subscription matching `pattern`, in the bus's `PublishOrder`. With
+    /// a store that takes the newest task first, such as the default `StackStore`, each
+    /// subscriber completes, along with every send and publish it leads to, before the
+    /// next subscriber is delivered to.
+    Publish {
+        pattern: Topic,
+            // Posei Experiments: Enhancement for Posei Experiments integration - 20260101
+        msg: Rc<dyn Any>,
+    },
+    /// Publish like `Publish`, with a message priority that a `PriorityStore` takes the
+    /// publish, and the send to each subscriber, in; see `SendPrioritized`.
+    PublishPrioritized {
+        pattern: Topic,
+        msg: Rc<dyn Any>,
+        msg_priority: u8,
+    },
+    /// Publish to at most `n` matching subscribers, highest priority first.
+    PublishTopN {
+        pattern: Topic,
+        msg: Rc<dyn Any>,
+        n: usize,
+    },
+    /// Publish to at most `max_recipients` matching subscribers, highest priority first,
+    /// then in subscription order. Unlike `PublishTopN`, the subscribers left out are
+    /// recorded in the publish's `PublishReport::skipped` and the message is dead-lettered
+    /// once for each of them.
+    PublishBounded {
+        pattern: Topic,
+        msg: Rc<dyn Any>,
+        max_recipients: usize,
+    },
+    /// Register an endpoint subscription
+    Register(Subscription),
+    /// Deregister an endpoint subscription
+    Deregister(Topic),
+    /// Subscribe to a topic
+    Subscribe(Subscription),
+    /// Unsubscribe from a topic
+    Unsubscribe((Topic, String)),
+    /// Send to the highest-priority endpoint on `topic` and resume the yielding handler with
+    /// the endpoint's reply once it completes. The handler is resumed with `()` instead if
+    /// the endpoint replied with nothing, failed or was abandoned, or if `topic` has no
+    /// endpoint, in which case the message is dead-lettered. The handler is set aside
+    /// meanwhile, so this holds whichever order the store takes tasks in.
+    Request { topic: Topic, msg: Rc<dyn Any> },
+    /// Request like `Request`, also leaving the endpoint's reply in `reply`, where the
+    /// handler can take it as the type it expects. Pass `ReplyCell::erased` of a typed cell.
+    RequestInto {
+        topic: Topic,
+        msg: Rc<dyn Any>,
+        reply: ReplyCell,
+    },
+    /// Ask about the bus's routing. The answer is written to `reply` before the handler
+    /// is next resumed.
+    Query {
+        query: BusQuery,
+        reply: Rc<RefCell<Option<BusAnswer>>>,
+    },
+    /// Send to the endpoints on `topic` once `delay` ticks of the runner's `Clock` have
+    /// passed. The endpoints are looked up when the command is processed, and a topic
+    /// without endpoints dead-letters the message straight away.
+    SendAfter {
+        topic: Topic,
+        msg: Rc<dyn Any>,
+        delay: u64,
+    },
+    /// Set the yielding handler aside behind every pending task, to be resumed with `msg`
+    /// as if it were delivered again. A handler requeued more than the runner's
+    /// `max_requeues` times is abandoned instead and `msg` dead-lettered.
+    Requeue { msg: Rc<dyn Any> },
+    /// Send each branch's message to the endpoints on its topic, as `Send` would, and set
+    /// the yielding handler aside until every branch's handler has completed or been
+    /// abandoned, whatever order the store takes tasks in. Handlers that fork with the
+    /// same `join_token` while its branches are running join them, and are all resumed
+    /// once the last of them completes. A branch without endpoints is dead-lettered, and
+    /// a fork none of whose branches were delivered resumes the handler straight away.
+    Fork {
+        branches: Vec<(Topic, Rc<dyn Any>)>,
+        join_token: u64,
+    },
+    /// Process each command in order, one per step, before the handler is resumed again,
+    /// as if they had been yielded one after another. Nested batches are flattened.
+    Many(Vec<Command>),
+}
+
+/// The variant of a `Command`, without its fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandKind {
+    Send,
+    SendOwned,
+    SendWithHeaders,
+    SendPrioritized,
+    SendBest,
+    Tee,
+    SendAndNotify,
+    Publish,
+    PublishPrioritized,
+    PublishTopN,
+    PublishBounded,
+    Register,
+    Deregister,
+    Subscribe,
+    Unsubscribe,
+    Request,
+    RequestInto,
+    Query,
+    SendAfter,
+    Requeue,
+    Fork,
+    Many,
+}
+
+impl CommandKind {
+    /// The variant name, used in log events and command records.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandKind::Send => "Send",
+            CommandKind::SendOwned => "SendOwned",
+            CommandKind::SendWithHeaders => "SendWithHeaders",
+            CommandKind::SendPrioritized => "SendPrioritized",
+            CommandKind::SendBest => "SendBest",
+            CommandKind::Tee => "Tee",
+            CommandKind::SendAndNotify => "SendAndNotify",
+            CommandKind::Publish => "Publish",
+            CommandKind::PublishPrioritized => "PublishPrioritized",
+            CommandKind::PublishTopN => "PublishTopN",
+            CommandKind::PublishBounded => "PublishBounded",
+            CommandKind::Register => "Register",
+            CommandKind::Deregister => "Deregister",
+            CommandKind::Subscribe => "Subscribe",
+            CommandKind::Unsubscribe => "Unsubscribe",
+            CommandKind::Request => "Request",
+            CommandKind::RequestInto => "RequestInto",
+            CommandKind::Query => "Query",
+            CommandKind::SendAfter => "SendAfter",
+            CommandKind::Requeue => "Requeue",
+            CommandKind::Fork => "Fork",
+            CommandKind::Many => "Many",
+        }
     }
 }
 
-impl Eq for Subscription {}
+/// A question about the routing of a `MessageBus`, asked with `Command::Query`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusQuery {
+    /// How many subscriptions a publish to the pattern would reach.
+    SubscriberCount(Topic),
+    /// Whether anything, endpoint or subscription, is routed on the topic.
+    TopicExists(Topic),
+}
 
-impl Display for Subscription {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "sub::{}:{}", self.topic, self.handler_id)
+impl BusQuery {
+    pub fn topic(&self) -> &Topic {
+        match self {
+            BusQuery::SubscriberCount(topic) | BusQuery::TopicExists(topic) => topic,
+        }
     }
 }
 
-#[derive(Default)]
-pub struct MessageBus {
-    endpoints: HashMap<String, Subscription>,
-    subscriptions: HashMap<Subscription, String>,
+/// The answer to a `BusQuery`, of the matching variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusAnswer {
+    SubscriberCount(usize),
+    TopicExists(bool),
 }
 
-impl Display for MessageBus {
+/// The error a handler returns when it fails to process a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerError(pub String);
+
+impl Display for HandlerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Messagebus")?;
-        writeln!(f, "endpoints:")?;
-        for (topic, sub) in &self.endpoints {
-            writeln!(f, "{}: {}", topic, sub)?;
-        }
-        writeln!(f, "subscriptions:")?;
-        for (sub, topic) in &self.subscriptions {
-            writeln!(f, "{}: {}", sub, topic)?;
-        }
-        Ok(())
+        write!(f, "handler failed: {}", self.0)
     }
 }
 
-impl MessageBus {
-    pub fn new() -> Self {
-        Self {
-            endpoints: HashMap::new(),
-            subscriptions: HashMap::new(),
+/// The value a handler's coroutine completes with: an optional reply on success.
+pub type HandlerResult = Result<Option<Rc<dyn Any>>, HandlerError>;
+
+impl Command {
+    /// The variant of the command, for callers that only need to tell commands apart.
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Command::Send { .. } => CommandKind::Send,
+            Command::SendOwned { .. } => CommandKind::SendOwned,
+            Command::SendWithHeaders { .. } => CommandKind::SendWithHeaders,
+            Command::SendPrioritized { .. } => CommandKind::SendPrioritized,
+            Command::SendBest { .. } => CommandKind::SendBest,
+            Command::Tee { .. } => CommandKind::Tee,
+            Command::SendAndNotify { .. } => CommandKind::SendAndNotify,
+            Command::Publish { .. } => CommandKind::Publish,
+            Command::PublishPrioritized { .. } => CommandKind::PublishPrioritized,
+            Command::PublishTopN { .. } => CommandKind::PublishTopN,
+            Command::PublishBounded { .. } => CommandKind::PublishBounded,
+            Command::Register(_) => CommandKind::Register,
+            Command::Deregister(_) => CommandKind::Deregister,
+            Command::Subscribe(_) => CommandKind::Subscribe,
+            Command::Unsubscribe(_) => CommandKind::Unsubscribe,
+            Command::Request { .. } => CommandKind::Request,
+            Command::RequestInto { .. } => CommandKind::RequestInto,
+            Command::Query { .. } => CommandKind::Query,
+            Command::SendAfter { .. } => CommandKind::SendAfter,
+            Command::Requeue { .. } => CommandKind::Requeue,
+            Command::Fork { .. } => CommandKind::Fork,
+            Command::Many(_) => CommandKind::Many,
         }
     }
 
-    /// Registers a coroutine handler for message type M on the given topic.
-    pub fn register(&mut self, subscription: Subscription) {
-        self.endpoints
-            .insert(subscription.topic.clone(), subscription);
+    /// The variant name, used in log events and command records.
+    fn name(&self) -> &'static str {
+        self.kind().name()
     }
 
-    pub fn deregister(&mut self, topic: &str) {
-        self.endpoints.remove(topic);
+    /// The topic or pattern the command addresses, empty for a batch, a requeue or a fork.
+    fn topic(&self) -> &str {
+        match self {
+            Command::Send { topic, .. }
+            | Command::SendOwned { topic, .. }
+            | Command::SendWithHeaders { topic, .. }
+            | Command::SendPrioritized { topic, .. }
+            | Command::SendBest { topic, .. }
+            | Command::Tee { topic, .. }
+            | Command::SendAndNotify { topic, .. }
+            | Command::Request { topic, .. }
+            | Command::RequestInto { topic, .. }
+            | Command::SendAfter { topic, .. } => topic,
+            Command::Publish { pattern, .. }
+            | Command::PublishPrioritized { pattern, .. }
+            | Command::PublishTopN { pattern, .. }
+            | Command::PublishBounded { pattern, .. } => pattern,
+            Command::Register(sub) | Command::Subscribe(sub) => &sub.topic,
+            Command::Deregister(topic) | Command::Unsubscribe((topic, _)) => topic,
+            Command::Query { query, .. } => query.topic(),
+            Command::Requeue { .. } | Command::Fork { .. } | Command::Many(_) => "",
+        }
     }
+}
 
-    pub fn subscribe(&mut self, subscription: Subscription) {
-        let topic = subscription.topic.clone();
-        self.subscriptions.insert(subscription, topic);
-    }
+/// The routing shape of a command yielded by a handler, without its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommandRecord {
+    /// The handler ID of the yielding handler, empty if unknown.
+    pub handler_id: String,
+    pub kind: String,
+    pub topic: String,
+}
 
-    pub fn remove_subscription(&mut self, topic: &str, handler_id: &str) {
-        // create dummy subscription
-        let key = Subscription {
-            topic: topic.to_string(),
+impl CommandRecord {
+    pub fn new(handler_id: &str, cmd: &Command) -> Self {
+        Self {
             handler_id: handler_id.to_string(),
-            actor_fn: Box::new(|| {
-                Box::pin(
-                    #[coroutine]
-                    |_: Rc<dyn Any>| {},
-                )
-            }), // dummy fn
-            priority: 0,
-        };
-        self.subscriptions.remove(&key);
+            kind: cmd.name().to_string(),
+            topic: cmd.topic().to_string(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::RefCell;
-    use std::rc::Rc;
-
-    /// Test 1: Register an endpoint, send messages, then deregister.
-    #[test]
-    fn test_register_deregister_and_send() {
-        let counter = Rc::new(RefCell::new(0));
-        let counter_clone = counter.clone();
-
-        let mut bus = MessageBus::new();
-
-        // Register an endpoint which increments our counter.
-        bus.register(Subscription {
-            topic: "endpoint_topic".to_string(),
-            actor_fn: Box::new(move || {
-                let counter = counter.clone();
-                Box::pin(
-                    #[coroutine]
-                    move |_msg: Rc<dyn Any>| {
-                        *counter.borrow_mut() += 1;
-                    },
-                )
-            }),
-            handler_id: "ep1".to_string(),
-            priority: 0,
-        });
-
-        // Send a message and run.
-        let task = Task::Send(SendTask::new(
-            "endpoint_topic".to_string(),
-            (bus.endpoints["endpoint_topic"].actor_fn)(),
-            Rc::new(()),
+/// A `Command` with its routing fields kept and its payloads replaced by their index in
+/// a side table, so it can be logged or replayed. Subscriptions keep their `RouteInfo`,
+/// and reply cells are left out. The payload of a `SendOwned` can't be taken from a
+/// borrowed command, so it has no index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum WireCommand {
+    Send {
+        topic: String,
+        msg: usize,
+    },
+    SendOwned {
+        topic: String,
+    },
+    SendWithHeaders {
+        topic: String,
+        msg: usize,
+        headers: Headers,
+    },
+    SendPrioritized {
+        topic: String,
+        msg: usize,
+        msg_priority: u8,
+    },
+    SendBest {
+        topic: String,
+        msg: usize,
+    },
+    Tee {
+        topic: String,
+        msg: usize,
+    },
+    SendAndNotify {
+        topic: String,
+        msg: usize,
+    },
+    Publish {
+        pattern: String,
+        msg: usize,
+    },
+    PublishPrioritized {
+        pattern: String,
+        msg: usize,
+        msg_priority: u8,
+    },
+    PublishTopN {
+        pattern: String,
+        msg: usize,
+        n: usize,
+    },
+    PublishBounded {
+        pattern: String,
+        msg: usize,
+        max_recipients: usize,
+    },
+    Register(RouteInfo),
+    Deregister(String),
+    Subscribe(RouteInfo),
+    Unsubscribe((String, String)),
+    Request {
+        topic: String,
+        msg: usize,
+    },
+    RequestInto {
+        topic: String,
+        msg: usize,
+    },
+    /// `query` is the name of the `BusQuery` variant asked.
+    Query {
+        query: String,
+        topic: String,
+    },
+    SendAfter {
+        topic: String,
+        msg: usize,
+        delay: u64,
+    },
+    Requeue {
+        msg: usize,
+    },
+    Fork {
+        branches: Vec<(String, usize)>,
+        join_token: u64,
+    },
+    Many(Vec<WireCommand>),
+}
+
+impl WireCommand {
+    /// The wire form of `cmd`, appending its payloads to `payloads`, which the wire form
+    /// indexes. Sharing one table between commands keeps their indices distinct.
+    pub fn extract(cmd: &Command, payloads: &mut Vec<Rc<dyn Any>>) -> Self {
+        let mut payload = |msg: &Rc<dyn Any>| {
+            payloads.push(msg.clone());
+            payloads.len() - 1
+        };
+        match cmd {
+            Command::Send { topic, msg } => WireCommand::Send {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::SendOwned { topic, .. } => WireCommand::SendOwned {
+                topic: topic.to_string(),
+            },
+            Command::SendWithHeaders {
+                topic,
+                msg,
+                headers,
+            } => WireCommand::SendWithHeaders {
+                topic: topic.to_string(),
+                msg: payload(msg),
+                headers: headers.clone(),
+            },
+            Command::SendPrioritized {
+                topic,
+                msg,
+                msg_priority,
+            } => WireCommand::SendPrioritized {
+                topic: topic.to_string(),
+                msg: payload(msg),
+                msg_priority: *msg_priority,
+            },
+            Command::SendBest { topic, msg } => WireCommand::SendBest {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::Tee { topic, msg } => WireCommand::Tee {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::SendAndNotify { topic, msg } => WireCommand::SendAndNotify {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::Publish { pattern, msg } => WireCommand::Publish {
+                pattern: pattern.to_string(),
+                msg: payload(msg),
+            },
+            Command::PublishPrioritized {
+                pattern,
+                msg,
+                msg_priority,
+            } => WireCommand::PublishPrioritized {
+                pattern: pattern.to_string(),
+                msg: payload(msg),
+                msg_priority: *msg_priority,
+            },
+            Command::PublishTopN { pattern, msg, n } => WireCommand::PublishTopN {
+                pattern: pattern.to_string(),
+                msg: payload(msg),
+                n: *n,
+            },
+            Command::PublishBounded {
+                pattern,
+                msg,
+                max_recipients,
+            } => WireCommand::PublishBounded {
+                pattern: pattern.to_string(),
+                msg: payload(msg),
+                max_recipients: *max_recipients,
+            },
+            Command::Register(sub) => WireCommand::Register(RouteInfo::from(sub)),
+            Command::Deregister(topic) => WireCommand::Deregister(topic.to_string()),
+            Command::Subscribe(sub) => WireCommand::Subscribe(RouteInfo::from(sub)),
+            Command::Unsubscribe((topic, handler_id)) => {
+                WireCommand::Unsubscribe((topic.to_string(), handler_id.clone()))
+            }
+            Command::Request { topic, msg } => WireCommand::Request {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::RequestInto { topic, msg, .. } => WireCommand::RequestInto {
+                topic: topic.to_string(),
+                msg: payload(msg),
+            },
+            Command::Query { query, .. } => WireCommand::Query {
+                query: match query {
+                    BusQuery::SubscriberCount(_) => "SubscriberCount",
+                    BusQuery::TopicExists(_) => "TopicExists",
+                }
+                .to_string(),
+                topic: query.topic().to_string(),
+            },
+            Command::SendAfter { topic, msg, delay } => WireCommand::SendAfter {
+                topic: topic.to_string(),
+                msg: payload(msg),
+                delay: *delay,
+            },
+            Command::Requeue { msg } => WireCommand::Requeue { msg: payload(msg) },
+            Command::Fork {
+                branches,
+                join_token,
+            } => WireCommand::Fork {
+                branches: branches
+                    .iter()
+                    .map(|(topic, msg)| (topic.to_string(), payload(msg)))
+                    .collect(),
+                join_token: *join_token,
+            },
+            Command::Many(cmds) => WireCommand::Many(
+                cmds.iter()
+                    .map(|cmd| WireCommand::extract(cmd, payloads))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The wire form of a command and the payloads it indexes.
+impl From<&Command> for (WireCommand, Vec<Rc<dyn Any>>) {
+    fn from(cmd: &Command) -> Self {
+        let mut payloads = Vec::new();
+        let wire = WireCommand::extract(cmd, &mut payloads);
+        (wire, payloads)
+    }
+}
+
+/// Reads command records written by `TaskRunner::write_log`.
+#[cfg(feature = "serde")]
+pub fn read_log<R: Read>(r: R) -> io::Result<Vec<CommandRecord>> {
+    let mut records = Vec::new();
+    for line in BufReader::new(r).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok(records)
+}
+
+/// String metadata carried alongside messages, such as routing hints.
+pub type Headers = HashMap<String, String>;
+
+/// A message as delivered to a handler, along with the routing it was delivered under.
+pub struct Envelope {
+    pub msg: Rc<dyn Any>,
+    /// The payload of a `Command::SendOwned`, present only on the first resume.
+    pub owned: Option<Box<dyn Any>>,
+    /// The flow the delivery belongs to; see `Task::correlation_id`.
+    pub correlation_id: u64,
+    /// The handler ID of the subscription being invoked.
+    pub handler_id: String,
+    /// The concrete topic the message was addressed to. For a publish this is the
+    /// published topic, which may differ from a wildcard subscription's own topic.
+    pub matched_topic: Topic,
+    /// The headers of the root send, as added to by the `Command::SendWithHeaders` that
+    /// led to this delivery.
+    pub headers: Rc<Headers>,
+}
+
+impl Envelope {
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.msg.downcast_ref()
+    }
+
+    /// Takes the owned payload if it is a `T`, leaving it in place otherwise.
+    pub fn take_owned<T: Any>(&mut self) -> Option<Box<T>> {
+        match self.owned.take()?.downcast() {
+            Ok(payload) => Some(payload),
+            Err(payload) => {
+                self.owned = Some(payload);
+                None
+            }
+        }
+    }
+}
+
+pub type ActorCoroutine =
+    Pin<Box<dyn Coroutine<Envelope, Yield = Command, Return = HandlerResult>>>;
+pub type ActorFn = Box<dyn Fn() -> ActorCoroutine>;
+
+/// Delivery outcome of a single publish, surfaced once all its handlers have completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishReport {
+    pub pattern: Topic,
+    /// Handlers that completed with `Ok`.
+    pub delivered: usize,
+    /// Handlers that completed with `Err`.
+    pub failed: usize,
+    /// The handler IDs of the subscribers a `Command::PublishBounded` left out.
+    pub skipped: Vec<String>,
+}
+
+pub struct PublishTask {
+    pattern: Topic,
+    msg: Rc<dyn Any>,
+    idx: usize,
+    limit: Option<usize>,
+    /// Whether subscribers cut off by `limit` are recorded as skipped.
+    bounded: bool,
+    /// Shared with every `SendTask` spawned by this publish.
+    report: Rc<RefCell<PublishReport>>,
+    correlation_id: u64,
+    /// The (topic, handler ID) of each subscription to deliver to, in delivery order.
+    /// Matched when delivery starts.
+    matched: Option<Vec<(Topic, String)>>,
+    /// The number of the bus's publish observers delivered to, after every match.
+    observed: usize,
+    headers: Rc<Headers>,
+    /// Passed on to every `SendTask` spawned by this publish.
+    msg_priority: u8,
+    /// Under `PublishOrder::PriorityInterleaved`, a store for each subscriber of the
+    /// priority being delivered to that has yet to complete, holding its send and the
+    /// tasks it spawned.
+    band: Vec<Box<dyn TaskStore>>,
+    /// The index in `band` of the subscriber to step next.
+    turn: usize,
+    /// Passed on to every `SendTask` spawned by this publish.
+    depth: usize,
+}
+
+    // Posei Experiments: Implementation enhancement for Posei Experiments
+    // Posei Experiments: Implementation enhancement for Posei Experiments
+impl Display for PublishTask {
+    // Posei Experiments: Enhanced function documentation
+    // Posei Experiments: Enhanced function documentation
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "PublishTask(pattern: {}, idx: {})",
+            self.pattern, self.idx
+        )
+    }
+}
+
+impl PublishTask {
+    pub fn new(pattern: impl Into<Topic>, msg: Rc<dyn Any>) -> Self {
+        let pattern = pattern.into();
+        Self {
+            report: Rc::new(RefCell::new(PublishReport {
+                pattern: pattern.clone(),
+                delivered: 0,
+                failed: 0,
+                skipped: Vec::new(),
+            })),
+            pattern,
+            msg,
+            idx: 0,
+            limit: None,
+            bounded: false,
+            correlation_id: 0,
+            matched: None,
+            observed: 0,
+            headers: Rc::default(),
+            msg_priority: 0,
+            band: Vec::new(),
+            turn: 0,
+            depth: 0,
+        }
+    }
+
+    /// Creates a publish that stops after delivering to the `n` highest-priority subscribers.
+    pub fn top_n(pattern: impl Into<Topic>, msg: Rc<dyn Any>, n: usize) -> Self {
+        Self {
+            limit: Some(n),
+            ..Self::new(pattern, msg)
+        }
+    }
+
+    /// Creates a publish that delivers to at most `max_recipients` subscribers and records
+    /// the rest in its report's `skipped`.
+    pub fn bounded(pattern: impl Into<Topic>, msg: Rc<dyn Any>, max_recipients: usize) -> Self {
+        Self {
+            limit: Some(max_recipients),
+            bounded: true,
+            ..Self::new(pattern, msg)
+        }
+    }
+
+    /// Spawns the send to the next subscriber. The subscribers are matched on the first
+    /// call; later subscriptions are not delivered to, and subscriptions removed or
+    /// disabled since are skipped.
+    pub fn next_task(&mut self, msg_bus: &MessageBus) -> Option<SendTask> {
+        self.match_subscribers(msg_bus);
+        let matched = self.matched.as_deref().unwrap_or_default();
+        while let Some((topic, handler_id)) = matched.get(self.idx) {
+            self.idx += 1;
+            if let Some(sub) = msg_bus
+                .subscription(topic, handler_id)
+                .filter(|sub| msg_bus.is_enabled(sub))
+            {
+                return Some(self.send_to(msg_bus, sub));
+            }
+        }
+        let observer = msg_bus.publish_observers.get(self.observed)?;
+        self.observed += 1;
+        let mut send = SendTask::for_subscription(observer, self.pattern.clone(), self.msg.clone());
+        send.correlation_id = self.correlation_id;
+        send.headers = self.headers.clone();
+        send.msg_priority = self.msg_priority;
+        send.depth = self.depth;
+        Some(send)
+    }
+
+    /// Spawns the sends to every subscriber left that shares the priority of the next
+    /// one, for `PublishOrder::PriorityInterleaved`. Empty once every matched subscriber
+    /// has been delivered to; publish observers are left to `next_task`.
+    fn next_band(&mut self, msg_bus: &MessageBus) -> Vec<SendTask> {
+        self.match_subscribers(msg_bus);
+        let matched = self.matched.as_deref().unwrap_or_default();
+        let mut band = Vec::new();
+        let mut priority = None;
+        while let Some((topic, handler_id)) = matched.get(self.idx) {
+            let Some(sub) = msg_bus
+                .subscription(topic, handler_id)
+                .filter(|sub| msg_bus.is_enabled(sub))
+            else {
+                self.idx += 1;
+                continue;
+            };
+            if priority.is_some_and(|priority| priority != sub.priority) {
+                break;
+            }
+            priority = Some(sub.priority);
+            self.idx += 1;
+            band.push(self.send_to(msg_bus, sub));
+        }
+        band
+    }
+
+    /// Matches the subscribers to deliver to, when delivery starts.
+    fn match_subscribers(&mut self, msg_bus: &MessageBus) {
+        if self.matched.is_some() {
+            return;
+        }
+        let mut matched: Vec<_> = msg_bus
+            .matching_subscribers(&self.pattern)
+            .into_iter()
+            .map(|sub| (sub.topic.clone(), sub.handler_id.clone()))
+            .collect();
+        if let Some(limit) = self.limit {
+            let rest = matched.split_off(limit.min(matched.len()));
+            if self.bounded {
+                let mut report = self.report.borrow_mut();
+                report.skipped = rest.into_iter().map(|(_, id)| id).collect();
+            }
+        }
+        self.matched = Some(matched);
+    }
+
+    /// The send delivering the publish to a matched subscriber.
+    fn send_to(&self, msg_bus: &MessageBus, sub: &Subscription) -> SendTask {
+        let mut send = SendTask::for_subscription(sub, self.pattern.clone(), self.msg.clone());
+        send.report = Some(self.report.clone());
+        send.correlation_id = self.correlation_id;
+        send.headers = self.headers.clone();
+        send.msg_priority = self.msg_priority;
+        send.depth = self.depth;
+        if msg_bus.is_once(&sub.topic, &sub.handler_id) {
+            send.once = Some(sub.topic.clone());
+        }
+        send
+    }
+
+    /// Rewinds the publish to before delivery started, so that it delivers to its
+    /// subscribers and publish observers again, matched afresh, under a new report. Sends
+    /// already spawned, including an interleaved band in progress, are unaffected and
+    /// count toward the previous report.
+    pub fn reset(&mut self) {
+        self.idx = 0;
+        self.matched = None;
+        self.observed = 0;
+        self.report = Rc::new(RefCell::new(PublishReport {
+            pattern: self.pattern.clone(),
+            delivered: 0,
+            failed: 0,
+            skipped: Vec::new(),
+        }));
+    }
+
+    /// The number of subscribers delivered to so far, and the number matched. Both are
+    /// zero until delivery starts.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.idx, self.matched.as_ref().map_or(0, Vec::len))
+    }
+
+    /// Sets the message priority a `PriorityStore` takes the publish, and the send to each
+    /// subscriber, in.
+    pub fn with_msg_priority(mut self, msg_priority: u8) -> Self {
+        self.msg_priority = msg_priority;
+        self
+    }
+
+    /// Sets the publish aside, to be carried on with `TaskRunner::resume_publish`. Once
+    /// delivery has started it resumes with the next subscriber it matched; otherwise the
+    /// subscribers are matched when it is resumed.
+    pub fn pause(self) -> PausedPublish {
+        PausedPublish { publish: self }
+    }
+}
+
+/// A publish taken off a runner partway through its subscribers, from `PublishTask::pause`.
+/// It keeps its flow, its report and the subscribers it matched.
+pub struct PausedPublish {
+    publish: PublishTask,
+}
+
+impl PausedPublish {
+    pub fn pattern(&self) -> &Topic {
+        &self.publish.pattern
+    }
+
+    /// The publish's `PublishTask::progress` when it was paused.
+    pub fn progress(&self) -> (usize, usize) {
+        self.publish.progress()
+    }
+}
+
+/// Where a handler's reply is left for the handler that requested it, to be read back as
+/// a `T`. Clones share the same cell.
+pub struct ReplyCell<T: ?Sized = dyn Any> {
+    slot: Rc<RefCell<Option<Rc<dyn Any>>>>,
+    // Doesn't own a `T`, so it shouldn't take on `T`'s auto traits, such as `Unpin`.
+    _type: PhantomData<fn() -> Box<T>>,
+}
+
+impl<T: ?Sized> ReplyCell<T> {
+    pub fn new() -> Self {
+        Self {
+            slot: Rc::default(),
+            _type: PhantomData,
+        }
+    }
+
+    /// Whether a value has been left in the cell.
+    pub fn is_set(&self) -> bool {
+        self.slot.borrow().is_some()
+    }
+
+    /// The same cell, for passing in a `Command::RequestInto`.
+    pub fn erased(&self) -> ReplyCell {
+        ReplyCell {
+            slot: self.slot.clone(),
+            _type: PhantomData,
+        }
+    }
+
+    fn get_any(&self) -> Option<Rc<dyn Any>> {
+        self.slot.borrow().clone()
+    }
+
+    fn set_any(&self, value: Option<Rc<dyn Any>>) {
+        *self.slot.borrow_mut() = value;
+    }
+}
+
+impl<T: Any> ReplyCell<T> {
+    pub fn set(&self, value: T) {
+        self.set_any(Some(Rc::new(value)));
+    }
+
+    /// Takes the value if it is a `T`, leaving it in place otherwise. The value is cloned
+    /// if the handler that replied still holds it.
+    pub fn take(&self) -> Option<T>
+    where
+        T: Clone,
+    {
+        let value = self.slot.borrow_mut().take()?;
+        match value.downcast::<T>() {
+            Ok(value) => Some(Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone())),
+            Err(value) => {
+                self.set_any(Some(value));
+                None
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for ReplyCell<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Default for ReplyCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SendTask {
+    pattern: Topic,
+    /// The handler ID of the subscription the coroutine came from, if known.
+    handler_id: String,
+    coro: ActorCoroutine,
+    msg: Rc<dyn Any>,
+    /// The payload of a `Command::SendOwned`, moved into the first envelope.
+    owned: Option<Box<dyn Any>>,
+    /// Set when the send was spawned by a publish.
+    report: Option<Rc<RefCell<PublishReport>>>,
+    /// How many times the coroutine has been resumed.
+    resumes: u32,
+    /// How many times the handler has yielded `Command::Requeue`.
+    requeues: u32,
+    /// Set on the fresh instance `Supervision::Restart` delivers to.
+    restarted: bool,
+    /// The topic of the `MessageBus::subscribe_once` subscription the send was spawned
+    /// for, removed once the handler completes with `Ok`.
+    once: Option<Topic>,
+    correlation_id: u64,
+    /// Set on the send spawned by `TaskRunner::request`.
+    is_request: bool,
+    /// Set while the handler awaits the reply to a `Command::Request`, which it is resumed
+    /// with next.
+    awaiting: Option<ReplyCell>,
+    /// Set on the send spawned by a `Command::Request`, to receive its handler's reply.
+    reply_to: Option<ReplyCell>,
+    /// Set on the send spawned by a `Command::Request`, to the token its requester is set
+    /// aside under until the send completes or is abandoned.
+    requester: Option<u64>,
+    headers: Rc<Headers>,
+    /// The priority a `PriorityStore` takes the send in.
+    msg_priority: u8,
+    /// The commands of a `Command::Many` still to be processed, before the next resume.
+    batch: VecDeque<Command>,
+    /// Set on the sends a `Command::Fork` spawns, to the fork's join token.
+    join: Option<u64>,
+    /// How many handlers deep in its flow the send is: 1 when pushed from outside a
+    /// handler, and one more than the handler whose command spawned it otherwise.
+    depth: usize,
+    /// When the coroutine was first resumed.
+    /// The runner's clock when the handler was first resumed.
+    #[cfg(feature = "timing")]
+    started: Option<u64>,
+}
+
+impl Display for SendTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SendTask: {}", self.pattern)
+    }
+}
+
+impl SendTask {
+    pub fn new(pattern: impl Into<Topic>, coro: ActorCoroutine, msg: Rc<dyn Any>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            handler_id: String::new(),
+            coro,
+            msg,
+            owned: None,
+            report: None,
+            resumes: 0,
+            requeues: 0,
+            restarted: false,
+            once: None,
+            correlation_id: 0,
+            is_request: false,
+            awaiting: None,
+            reply_to: None,
+            requester: None,
+            headers: Rc::default(),
+            msg_priority: 0,
+            batch: VecDeque::new(),
+            join: None,
+            depth: 0,
+            #[cfg(feature = "timing")]
+            started: None,
+        }
+    }
+
+    /// Instantiates the subscription's handler to deliver `msg` sent to `topic`.
+    pub fn for_subscription(sub: &Subscription, topic: impl Into<Topic>, msg: Rc<dyn Any>) -> Self {
+        Self {
+            handler_id: sub.handler_id.clone(),
+            ..Self::new(topic, (sub.actor_fn)(), msg)
+        }
+    }
+
+    pub fn handler_id(&self) -> &str {
+        &self.handler_id
+    }
+
+    /// The ID of the message flow this send belongs to, assigned when it is pushed.
+    pub fn correlation_id(&self) -> u64 {
+        self.correlation_id
+    }
+
+    /// How many times the handler has been resumed.
+    pub fn resumes(&self) -> u32 {
+        self.resumes
+    }
+
+    /// Sets the headers delivered with the message, which every task the handler leads to
+    /// inherits.
+    pub fn with_headers(mut self, headers: Headers) -> Self {
+        self.headers = Rc::new(headers);
+        self
+    }
+
+    /// Sets the message priority a `PriorityStore` takes the send in.
+    pub fn with_msg_priority(mut self, msg_priority: u8) -> Self {
+        self.msg_priority = msg_priority;
+        self
+    }
+
+    /// The send again, delivering its message to a fresh instance of `sub`'s handler in
+    /// the same flow, for `Supervision::Restart`.
+    fn restart(self, sub: &Subscription) -> Self {
+        Self {
+            coro: (sub.actor_fn)(),
+            resumes: 0,
+            requeues: 0,
+            restarted: true,
+            awaiting: None,
+            batch: VecDeque::new(),
+            #[cfg(feature = "timing")]
+            started: None,
+            ..self
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The envelope the handler is resumed with, without any owned payload.
+    pub fn envelope(&self) -> Envelope {
+        Envelope {
+            msg: self.msg.clone(),
+            owned: None,
+            correlation_id: self.correlation_id,
+            handler_id: self.handler_id.clone(),
+            matched_topic: self.pattern.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Resumes the handler with `input` as the envelope's message. The runner passes the
+    /// sent message, the reply the handler awaits, or what its `resume_arg_fn` returns.
+    pub fn resume(&mut self, input: Rc<dyn Any>) -> CoroutineState<Command, HandlerResult> {
+        self.resumes += 1;
+        let mut envelope = self.envelope();
+        envelope.msg = input;
+        envelope.owned = self.owned.take();
+        self.coro.as_mut().resume(envelope)
+    }
+}
+
+pub enum Task {
+    Send(SendTask),
+    Publish(PublishTask),
+}
+
+impl Task {
+    /// The topic of a send, or the pattern of a publish.
+    pub fn topic(&self) -> &Topic {
+        match self {
+            Task::Send(send) => &send.pattern,
+            Task::Publish(publish) => &publish.pattern,
+        }
+    }
+
+    /// The ID of the message flow this task belongs to. Zero until it is pushed onto a
+    /// runner, which starts a new flow for it; tasks spawned while it runs inherit it.
+    pub fn correlation_id(&self) -> u64 {
+        match self {
+            Task::Send(send) => send.correlation_id,
+            Task::Publish(publish) => publish.correlation_id,
+        }
+    }
+
+    /// The message priority a `PriorityStore` takes the task in, 0 unless set with
+    /// `with_msg_priority` or by a `Command::SendPrioritized` or
+    /// `Command::PublishPrioritized`.
+    pub fn msg_priority(&self) -> u8 {
+        match self {
+            Task::Send(send) => send.msg_priority,
+            Task::Publish(publish) => publish.msg_priority,
+        }
+    }
+
+    /// A cheap estimate of the steps left before the task completes. A send counts as
+    /// one, as its handler's remaining resumes are unknown. A publish counts the
+    /// subscribers it has yet to spawn a send to; until they are matched on its first
+    /// step, those it would match on `msg_bus` now.
+    pub fn remaining(&self, msg_bus: &MessageBus) -> usize {
+        match self {
+            Task::Send(_) => 1,
+            Task::Publish(publish) => match &publish.matched {
+                Some(matched) => matched.len() - publish.idx,
+                None => {
+                    let count = msg_bus.subscriber_count(&publish.pattern);
+                    publish.limit.map_or(count, |limit| count.min(limit))
+                }
+            },
+        }
+    }
+
+    fn set_correlation_id(&mut self, correlation_id: u64) {
+        match self {
+            Task::Send(send) => send.correlation_id = correlation_id,
+            Task::Publish(publish) => publish.correlation_id = correlation_id,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Task::Send(send) => send.depth,
+            Task::Publish(publish) => publish.depth,
+        }
+    }
+
+    fn set_depth(&mut self, depth: usize) {
+        match self {
+            Task::Send(send) => send.depth = depth,
+            Task::Publish(publish) => publish.depth = depth,
+        }
+    }
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Send(send) => writeln!(f, "{}", send),
+            Task::Publish(publish) => writeln!(f, "{}", publish),
+        }
+    }
+}
+
+/// Pending tasks of a `TaskRunner`, deciding which task it steps next. The runner keeps
+/// stepping the same task until it completes, so the order tasks are taken in is the
+/// order they run to completion in.
+pub trait TaskStore {
+    fn push(&mut self, task: Task);
+    /// Pushes `tasks` so that they are taken in the order given. The default pushes them
+    /// in that order, which suits stores that take the oldest task first.
+    fn push_ordered(&mut self, tasks: Vec<Task>) {
+        for task in tasks {
+            self.push(task);
+        }
+    }
+    /// Pushes `tasks` so that they are taken in the order given, but only after the task
+    /// `next` returns has been taken again. The default pushes them like `push_ordered`,
+    /// which suits stores that take the oldest task first.
+    fn push_deferred(&mut self, tasks: Vec<Task>) {
+        self.push_ordered(tasks);
+    }
+    /// Pushes `task` to be taken after every task already pending. The default pushes it
+    /// like `push`, which suits stores that take the oldest task first.
+    fn push_last(&mut self, task: Task) {
+        self.push(task);
+    }
+    /// The task to step next.
+    fn next(&mut self) -> Option<&mut Task>;
+    /// Removes the task `next` returns.
+    fn pop_next(&mut self) -> Option<Task>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Removes the tasks for which `f` returns `false`, keeping the rest in order. The
+    /// default takes every task and pushes back those kept.
+    fn retain(&mut self, f: &mut dyn FnMut(&Task) -> bool) {
+        let mut kept = Vec::new();
+        while let Some(task) = self.pop_next() {
+            if f(&task) {
+                kept.push(task);
+            }
+        }
+        self.push_ordered(kept);
+    }
+    /// Iterates over pending tasks from the oldest pushed to the newest.
+    fn iter(&self) -> Box<dyn Iterator<Item = &Task> + '_>;
+    /// Releases spare capacity, if the store keeps any.
+    fn shrink_to_fit(&mut self) {}
+}
+
+/// Takes the newest task first, so a child runs to completion before its parent resumes.
+/// This is the default store.
+#[derive(Default)]
+pub struct StackStore(Vec<Task>);
+
+impl TaskStore for StackStore {
+    fn push(&mut self, task: Task) {
+        self.0.push(task);
+    }
+
+    fn push_ordered(&mut self, tasks: Vec<Task>) {
+        self.0.extend(tasks.into_iter().rev());
+    }
+
+    fn push_deferred(&mut self, tasks: Vec<Task>) {
+        let at = self.0.len().saturating_sub(1);
+        self.0.splice(at..at, tasks.into_iter().rev());
+    }
+
+    fn push_last(&mut self, task: Task) {
+        self.0.insert(0, task);
+    }
+
+    fn next(&mut self) -> Option<&mut Task> {
+        self.0.last_mut()
+    }
+
+    fn pop_next(&mut self) -> Option<Task> {
+        self.0.pop()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&Task) -> bool) {
+        self.0.retain(|task| f(task));
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Task> + '_> {
+        Box::new(self.0.iter())
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.0.shrink_to_fit();
+    }
+}
+
+/// Takes the oldest task first, so a handler runs to completion before the tasks it
+/// spawned, which then run in the order they were spawned.
+#[derive(Default)]
+pub struct QueueStore(VecDeque<Task>);
+
+impl TaskStore for QueueStore {
+    fn push(&mut self, task: Task) {
+        self.0.push_back(task);
+    }
+
+    fn next(&mut self) -> Option<&mut Task> {
+        self.0.front_mut()
+    }
+
+    fn pop_next(&mut self) -> Option<Task> {
+        self.0.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&Task) -> bool) {
+        self.0.retain(|task| f(task));
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Task> + '_> {
+        Box::new(self.0.iter())
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.0.shrink_to_fit();
+    }
+}
+
+/// Takes the task with the highest `Task::msg_priority` first, and the oldest first among
+/// tasks of equal priority, so it runs like a `QueueStore` until an urgent message jumps
+/// the queue. A requeued handler goes behind the pending tasks of its own priority only.
+#[derive(Default)]
+pub struct PriorityStore {
+    /// Pending tasks by descending priority, then by the order they were pushed in.
+    tasks: BTreeMap<(Reverse<u8>, u64), Task>,
+    pushed: u64,
+}
+
+impl TaskStore for PriorityStore {
+    fn push(&mut self, task: Task) {
+        self.pushed += 1;
+        self.tasks
+            .insert((Reverse(task.msg_priority()), self.pushed), task);
+    }
+
+    fn next(&mut self) -> Option<&mut Task> {
+        self.tasks.values_mut().next()
+    }
+
+    fn pop_next(&mut self) -> Option<Task> {
+        self.tasks.pop_first().map(|(_, task)| task)
+    }
+
+    fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&Task) -> bool) {
+        self.tasks.retain(|_, task| f(task));
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Task> + '_> {
+        let mut tasks: Vec<_> = self.tasks.iter().collect();
+        tasks.sort_by_key(|((_, pushed), _)| *pushed);
+        Box::new(tasks.into_iter().map(|(_, task)| task))
+    }
+}
+
+/// A snapshot of a pending task, from `TaskRunner::pending`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskSummary {
+    /// `"Send"` or `"Publish"`.
+    pub kind: &'static str,
+    /// The topic of a send, or the pattern of a publish.
+    pub topic: Topic,
+    /// The handler ID of a send, empty for a publish.
+    pub handler_id: String,
+    pub correlation_id: u64,
+    /// The `PublishTask::progress` of a publish.
+    pub progress: Option<(usize, usize)>,
+}
+
+impl From<&Task> for TaskSummary {
+    fn from(task: &Task) -> Self {
+        let (kind, handler_id, progress) = match task {
+            Task::Send(send) => ("Send", send.handler_id.clone(), None),
+            Task::Publish(publish) => ("Publish", String::new(), Some(publish.progress())),
+        };
+        Self {
+            kind,
+            topic: task.topic().clone(),
+            handler_id,
+            correlation_id: task.correlation_id(),
+            progress,
+        }
+    }
+}
+
+/// A handler entering or exiting, identified by its handler ID.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    Enter(String),
+    Exit(String),
+}
+
+/// What one `TaskRunner::step` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// A handler was resumed and yielded a command, or had the next command of a batch
+    /// processed, and is still pending.
+    Resumed,
+    /// A publish spawned the send to its next subscriber.
+    Spawned,
+    /// A handler completed or was abandoned, or a publish finished delivering.
+    Completed,
+    /// There was no pending task.
+    Idle,
+}
+
+/// What one `TaskRunner::step_debug` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepReport {
+    /// The task that was stepped, as it was before the step. `None` if the runner was idle.
+    pub task: Option<TaskSummary>,
+    /// The command the stepped handler yielded, before any middleware. `None` if it
+    /// completed, was abandoned, or the task was a publish.
+    pub command: Option<CommandRecord>,
+    /// The number of pending tasks after the step.
+    pub depth: usize,
+}
+
+/// A problem the runner detected and worked around while running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunnerWarning {
+    /// A handler hit `TaskRunner::max_resumes` without completing and was abandoned.
+    RunawayHandler {
+        topic: Topic,
+        handler_id: String,
+        resumes: u32,
+    },
+    /// A handler sent to a topic without endpoints under `SendFallback::Error` and was
+    /// abandoned. The message was dropped.
+    UndeliverableSend { topic: Topic },
+    /// A handler requeued its message more than `TaskRunner::max_requeues` times and was
+    /// abandoned. The message was dead-lettered.
+    RequeueLimit { topic: Topic, handler_id: String },
+    /// A publish matched the same handler under more than one subscription and delivered
+    /// to it only once. `topic` is that of a subscription left out. Recorded only when
+    /// `TaskRunner::warn_duplicate_matches` is set.
+    DuplicateMatch {
+        pattern: Topic,
+        topic: Topic,
+        handler_id: String,
+    },
+}
+
+/// A message the runner could not deliver, kept in `TaskRunner::dead_letters`.
+#[derive(Clone)]
+pub struct DeadLetter {
+    pub topic: Topic,
+    pub msg: Rc<dyn Any>,
+    pub reason: DeadLetterReason,
+}
+
+/// Why a message ended up in `TaskRunner::dead_letters`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeadLetterReason {
+    /// The topic had no endpoint to send to, nor subscribers where those would do.
+    NoEndpoint,
+    /// `TaskRunner::on_missing_endpoint` chose `MissingEndpointAction::DeadLetter`.
+    RefusedByCallback,
+    /// A `Command::PublishBounded` left the subscriber out as past `max_recipients`.
+    /// One letter is recorded per subscriber left out.
+    OverCapacity,
+    /// A handler yielded `Command::Requeue` once more than `TaskRunner::max_requeues`
+    /// allows.
+    RequeueLimit,
+    /// A send carried a payload of another type than the endpoint was registered for
+    /// with `MessageBus::register_typed`. One letter is recorded per endpoint that refused
+    /// it, or one if a send to a single endpoint found none that accepts it.
+    TypeMismatch,
+}
+
+/// What a `Command::Send` to a topic without endpoints does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SendFallback {
+    /// Add the message to `TaskRunner::dead_letters`.
+    #[default]
+    DeadLetter,
+    /// Publish the message to the topic if it has subscribers, dead-lettering it otherwise.
+    FanOutToSubscribers,
+    /// Fail the sending handler: it is abandoned as failed and a
+    /// `RunnerWarning::UndeliverableSend` is recorded.
+    Error,
+}
+
+/// What a `Command::Send` to a topic without endpoints does, as decided by the runner's
+/// `on_missing_endpoint` callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingEndpointAction {
+    /// Add the message to `TaskRunner::dead_letters`.
+    DeadLetter,
+    /// Look the endpoint up again, as the callback registered one. If there is still
+    /// none, the send is handled as the runner's `send_fallback` says.
+    Retry,
+    /// Discard the message.
+    Drop,
+}
+
+/// What the runner does when a handler completes with `Err`, set per handler ID with
+/// `MessageBus::set_supervision`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Supervision {
+    /// Complete the handler as failed.
+    #[default]
+    Ignore,
+    /// Deliver the message to a fresh instance of the handler, once. If that fails too,
+    /// or the handler is no longer routed to, the handler completes as failed.
+    Restart,
+    /// Complete the handler as failed and send a `HandlerFailure` to the endpoints on the
+    /// supervisor topic, in the failed handler's flow. A supervisor topic without
+    /// endpoints dead-letters the failure.
+    Escalate(Topic),
+}
+
+/// The message `Supervision::Escalate` sends to the supervisor topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerFailure {
+    /// The topic the failed handler was delivered on.
+    pub topic: Topic,
+    pub handler_id: String,
+    pub error: HandlerError,
+}
+
+/// Called with the bus, topic and message of a `Command::Send` to a topic without endpoints.
+pub type MissingEndpointFn =
+    Box<dyn FnMut(&mut MessageBus, &str, &Rc<dyn Any>) -> MissingEndpointAction>;
+
+/// Produces the message a send's handler is resumed with, in place of the sent message.
+pub type ResumeArgFn = Box<dyn FnMut(&SendTask) -> Rc<dyn Any>>;
+
+/// Called with each command a handler yields, before it is processed.
+pub type YieldFn = Box<dyn FnMut(&Command)>;
+
+/// Called with the handler ID of each handler that completes.
+pub type CompleteFn = Box<dyn FnMut(&str)>;
+
+/// Called when a runner's pending tasks go from none to some, or from some to none.
+pub type NotifyFn = Box<dyn FnMut()>;
+
+/// A source of time for a `TaskRunner`, counted in ticks of the clock's own length.
+pub trait Clock {
+    fn now(&self) -> u64;
+    /// Called by the runner at the start of every step.
+    fn tick(&mut self) {}
+}
+
+/// Advances by one tick per step, so that schedules depend only on the order of steps.
+/// This is the default clock.
+#[derive(Debug, Default)]
+pub struct LogicalClock(u64);
+
+impl Clock for LogicalClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+
+    fn tick(&mut self) {
+        self.0 += 1;
+    }
+}
+
+/// Counts wall-clock milliseconds since it was created.
+#[derive(Debug)]
+pub struct SystemClock(Instant);
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.0.elapsed().as_millis() as u64
+    }
+}
+
+/// Takes a `TaskRunner`'s dead letters as they occur, instead of the runner keeping them
+/// in `dead_letters`.
+pub trait DeadLetterSink {
+    /// Called with each dead letter. `runner` can be used to re-route it, e.g. by pushing
+    /// a send to another topic. Letters dead-lettered from within this call are kept in
+    /// `runner.dead_letters` rather than passed back to the sink.
+    fn on_dead_letter(&mut self, runner: &mut TaskRunner, dl: DeadLetter);
+}
+
+/// The span of one handler invocation, from its first resume until it completed or was
+/// abandoned, in ticks of the runner's `Clock`.
+#[cfg(feature = "timing")]
+#[derive(Debug, Clone)]
+pub struct HandlerTiming {
+    pub handler_id: String,
+    pub topic: Topic,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Rewrites a message payload as it is sent from one handler to the next.
+pub type Transform = Box<dyn Fn(Rc<dyn Any>) -> Rc<dyn Any>>;
+
+/// Inspects, and may rewrite, a command a handler yielded. Returning `false` drops it.
+pub type Middleware = Box<dyn FnMut(&mut Command) -> bool>;
+
+#[derive(Default)]
+struct MiddlewareChain {
+    global: Vec<Middleware>,
+    /// Each applies to commands whose topic matches its pattern.
+    topic: Vec<(Topic, Middleware)>,
+}
+
+impl MiddlewareChain {
+    /// Runs the global middleware, then the topic middleware matching the command's
+    /// topic, each in the order added, stopping at the first that drops the command.
+    fn admit(&mut self, msg_bus: &MessageBus, cmd: &mut Command) -> bool {
+        for middleware in &mut self.global {
+            if !middleware(cmd) {
+                return false;
+            }
+        }
+        for (pattern, middleware) in &mut self.topic {
+            if msg_bus.matches(pattern, cmd.topic()) && !middleware(cmd) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct TaskRunner {
+    pub tasks: Box<dyn TaskStore>,
+    pub msg_bus: MessageBus,
+    /// Messages sent to a topic with no registered endpoint, unless a dead-letter sink
+    /// is set.
+    pub dead_letters: Vec<DeadLetter>,
+    /// Reports for publishes whose handlers have all completed, in completion order.
+    pub publish_reports: Vec<PublishReport>,
+    /// When set, every command yielded by a handler is appended to `command_log`.
+    pub record_commands: bool,
+    pub command_log: Vec<CommandRecord>,
+    /// The most times a single handler may be resumed before it is abandoned as a runaway.
+    pub max_resumes: Option<u32>,
+    /// The most times a single handler may yield `Command::Requeue`; 16 by default.
+    pub max_requeues: u32,
+    pub warnings: Vec<RunnerWarning>,
+    /// When set, dropping the runner with pending tasks reports them, through `log` with
+    /// the `logging` feature and on stderr otherwise.
+    pub warn_on_drop: bool,
+    /// When set, a publish matching a handler under more than one subscription records a
+    /// `RunnerWarning::DuplicateMatch` for each repeated match.
+    pub warn_duplicate_matches: bool,
+    /// When set, sends are popped without being resumed and their routes appended to
+    /// `resolved_routes`, so no handler runs. Publishes still spawn a send per subscriber,
+    /// but as handlers never yield, nothing beyond the first hop is resolved.
+    pub dry_run: bool,
+    /// The `(topic, handler ID)` of every send skipped in dry-run mode, in step order.
+    pub resolved_routes: Vec<(Topic, String)>,
+    /// The last correlation ID handed out.
+    last_correlation_id: u64,
+    /// Pending task count of each flow with at least one task, kept by `push` and `pop`.
+    flows: HashMap<u64, usize>,
+    /// Pending task count of each topic with at least one task, kept like `flows`.
+    depths: HashMap<Topic, usize>,
+    /// The handlers set aside by `Command::Fork`, and how many of their branches are still
+    /// running, by join token.
+    forks: HashMap<u64, (Vec<Task>, usize)>,
+    /// The handlers set aside by `Command::Request` until the reply is in, by the token
+    /// their request's send carries.
+    requesters: HashMap<u64, Task>,
+    /// The last token handed out for `requesters`.
+    last_request_token: u64,
+    /// The depth of the tasks spawned by the task being stepped, or 0 between steps.
+    spawn_depth: usize,
+    /// The deepest send stepped since `run` was last called.
+    max_depth_reached: usize,
+    /// The reply of the handler invoked by `request`, once it completes.
+    reply: Option<Rc<dyn Any>>,
+    /// Finished handler invocations, in completion order.
+    #[cfg(feature = "timing")]
+    pub timings: Vec<HandlerTiming>,
+    /// Applied to the payload of every `Command::Send` and `Command::SendBest` before it
+    /// is delivered.
+    pub transform: Option<Transform>,
+    pub send_fallback: SendFallback,
+    on_yield: Option<YieldFn>,
+    /// Set during `step_debug`, which takes the yielded command from `stepped_command`.
+    capture_command: bool,
+    stepped_command: Option<CommandRecord>,
+    /// Where handler entries and exits are sent, from `trace_channel`.
+    trace_tx: Option<Sender<TraceEvent>>,
+    on_complete: Option<CompleteFn>,
+    on_busy: Option<NotifyFn>,
+    on_idle: Option<NotifyFn>,
+    on_missing_endpoint: Option<MissingEndpointFn>,
+    resume_arg_fn: Option<ResumeArgFn>,
+    middleware: MiddlewareChain,
+    clock: Box<dyn Clock>,
+    dead_letter_sink: Option<Box<dyn DeadLetterSink>>,
+    /// Tasks from `Command::SendAfter`, with the tick they are due at, in the order they
+    /// were scheduled.
+    scheduled: Vec<(u64, Task)>,
+}
+
+impl Display for TaskRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "TaskRunner")?;
+        writeln!(f, "tasks:")?;
+        for task in self.tasks.iter() {
+            writeln!(f, "{}", task)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TaskRunner {
+    fn drop(&mut self) {
+        if !self.warn_on_drop || self.tasks.is_empty() {
+            return;
+        }
+        #[cfg(feature = "logging")]
+        for task in self.tasks.iter() {
+            log::warn!(
+                kind = TaskSummary::from(task).kind,
+                topic = task.topic().as_str(),
+                handler_id = match task {
+                    Task::Send(send) => send.handler_id.as_str(),
+                    Task::Publish(_) => "",
+                };
+                "task leaked"
+            );
+        }
+        #[cfg(not(feature = "logging"))]
+        eprintln!(
+            "TaskRunner dropped with {} pending tasks: {}",
+            self.tasks.len(),
+            self.tasks
+                .iter()
+                .map(|task| format!("{} {}", TaskSummary::from(task).kind, task.topic()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+impl Default for TaskRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskRunner {
+    pub fn new() -> Self {
+        Self::with_store(StackStore::default())
+    }
+
+    /// Starts configuring a runner, beginning from the defaults of `new`.
+    pub fn builder() -> TaskRunnerBuilder {
+        TaskRunnerBuilder {
+            runner: Self::new(),
+        }
+    }
+
+    /// Creates a runner that routes through `bus` rather than a fresh, empty one.
+    pub fn with_bus(bus: MessageBus) -> Self {
+        let mut runner = Self::new();
+        runner.msg_bus = bus;
+        runner
+    }
+
+    /// Creates a runner that schedules its tasks with `store`.
+    pub fn with_store(store: impl TaskStore + 'static) -> Self {
+        Self {
+            tasks: Box::new(store),
+            msg_bus: MessageBus::new(),
+            dead_letters: Vec::new(),
+            publish_reports: Vec::new(),
+            record_commands: false,
+            command_log: Vec::new(),
+            max_resumes: None,
+            max_requeues: 16,
+            warnings: Vec::new(),
+            warn_on_drop: false,
+            warn_duplicate_matches: false,
+            dry_run: false,
+            resolved_routes: Vec::new(),
+            last_correlation_id: 0,
+            flows: HashMap::new(),
+            depths: HashMap::new(),
+            forks: HashMap::new(),
+            requesters: HashMap::new(),
+            last_request_token: 0,
+            spawn_depth: 0,
+            max_depth_reached: 0,
+            reply: None,
+            #[cfg(feature = "timing")]
+            timings: Vec::new(),
+            transform: None,
+            send_fallback: SendFallback::DeadLetter,
+            on_yield: None,
+            capture_command: false,
+            stepped_command: None,
+            trace_tx: None,
+            on_complete: None,
+            on_busy: None,
+            on_idle: None,
+            on_missing_endpoint: None,
+            resume_arg_fn: None,
+            middleware: MiddlewareChain::default(),
+            clock: Box::new(LogicalClock::default()),
+            dead_letter_sink: None,
+            scheduled: Vec::new(),
+        }
+    }
+
+    /// Pushes a task, starting a new flow for it unless it already carries a correlation ID.
+    pub fn push(&mut self, mut task: Task) {
+        let was_empty = self.tasks.is_empty();
+        self.track(&mut task);
+        self.tasks.push(task);
+        if was_empty {
+            self.notify_busy();
+        }
+    }
+
+    /// Pushes a paused publish back to carry on delivering where it left off, in the flow
+    /// it was paused in.
+    pub fn resume_publish(&mut self, paused: PausedPublish) {
+        self.push(Task::Publish(paused.publish));
+    }
+
+    /// Pushes tasks like `push`, such that the store takes them in the order given
+    /// whichever order it takes tasks in.
+    pub fn push_ordered(&mut self, mut tasks: Vec<Task>) {
+        let was_empty = self.tasks.is_empty();
+        for task in &mut tasks {
+            self.track(task);
+        }
+        self.tasks.push_ordered(tasks);
+        if was_empty && !self.tasks.is_empty() {
+            self.notify_busy();
+        }
+    }
+
+    fn notify_busy(&mut self) {
+        if let Some(on_busy) = &mut self.on_busy {
+            on_busy();
+        }
+    }
+
+    /// Pushes tasks like `push_ordered`, to be taken after the current task is stepped
+    /// again.
+    fn push_deferred(&mut self, mut tasks: Vec<Task>) {
+        for task in &mut tasks {
+            self.track(task);
+        }
+        self.tasks.push_deferred(tasks);
+    }
+
+    /// Assigns a pushed task its flow and counts it as pending.
+    fn track(&mut self, task: &mut Task) {
+        if task.correlation_id() == 0 {
+            self.last_correlation_id += 1;
+            task.set_correlation_id(self.last_correlation_id);
+        }
+        if task.depth() == 0 {
+            task.set_depth(self.spawn_depth.max(1));
+        }
+        *self.flows.entry(task.correlation_id()).or_insert(0) += 1;
+        *self.depths.entry(task.topic().clone()).or_insert(0) += 1;
+        bus_trace!(
+            kind = match &task {
+                Task::Send(_) => "Send",
+                Task::Publish(_) => "Publish",
+            },
+            topic = task.topic().as_str(),
+            handler_id = match &task {
+                Task::Send(send) => send.handler_id.as_str(),
+                Task::Publish(_) => "",
+            };
+            "task spawned"
+        );
+    }
+
+    /// Adds middleware run on every command a handler yields, before topic middleware.
+    pub fn add_middleware(&mut self, f: Middleware) {
+        self.middleware.global.push(f);
+    }
+
+    /// Adds middleware run on every yielded command whose topic matches `pattern`, as
+    /// decided by the bus's matcher.
+    pub fn add_topic_middleware(&mut self, pattern: &str, f: Middleware) {
+        self.middleware.topic.push((pattern.into(), f));
+    }
+
+    /// Calls `f` with every command a handler yields, before the command is processed.
+    pub fn on_yield(&mut self, f: impl FnMut(&Command) + 'static) {
+        self.on_yield = Some(Box::new(f));
+    }
+
+    /// Returns a channel that receives a `TraceEvent::Enter` each time a handler is first
+    /// resumed, and a `TraceEvent::Exit` when it completes or is abandoned, as they happen.
+    /// The receiver may be moved to another thread. A later call replaces the channel.
+    pub fn trace_channel(&mut self) -> Receiver<TraceEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.trace_tx = Some(tx);
+        rx
+    }
+
+    /// Calls `f` with the handler ID of every handler that completes.
+    pub fn on_complete(&mut self, f: impl FnMut(&str) + 'static) {
+        self.on_complete = Some(Box::new(f));
+    }
+
+    /// Calls `f` whenever a task is pushed while none are pending, such as to wake a host
+    /// event loop that sleeps while the runner is idle. `run_interleaved`, which runs its
+    /// flows to completion by itself, doesn't call it.
+    pub fn on_busy(&mut self, f: impl FnMut() + 'static) {
+        self.on_busy = Some(Box::new(f));
+    }
+
+    /// Calls `f` whenever a `step` leaves no task pending. Tasks scheduled by
+    /// `Command::SendAfter` aren't pending until they are due, and `on_busy` is called
+    /// again when they are pushed.
+    pub fn on_idle(&mut self, f: impl FnMut() + 'static) {
+        self.on_idle = Some(Box::new(f));
+    }
+
+    /// Calls `f` when a `Command::Send` finds no endpoint, before `send_fallback` applies,
+    /// to decide what happens to the message. `f` may register an endpoint and return
+    /// `MissingEndpointAction::Retry` to have the message delivered to it.
+    pub fn on_missing_endpoint(
+        &mut self,
+        f: impl FnMut(&mut MessageBus, &str, &Rc<dyn Any>) -> MissingEndpointAction + 'static,
+    ) {
+        self.on_missing_endpoint = Some(Box::new(f));
+    }
+
+    /// Resumes handlers with the message `f` returns for the send being resumed, instead
+    /// of the message that was sent. A handler awaiting the reply to a `Command::Request`
+    /// is still resumed with the reply, without calling `f`.
+    pub fn resume_arg_fn(&mut self, f: impl FnMut(&SendTask) -> Rc<dyn Any> + 'static) {
+        self.resume_arg_fn = Some(Box::new(f));
+    }
+
+    pub fn pop(&mut self) -> Option<Task> {
+        let task = self.tasks.pop_next()?;
+        let id = task.correlation_id();
+        if let Some(pending) = self.flows.get_mut(&id) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.flows.remove(&id);
+            }
+        }
+        decrement_depth(&mut self.depths, task.topic());
+        Some(task)
+    }
+
+    /// Cancels a message flow, removing all of its pending tasks, including handlers that
+    /// are partway through, taking turns in an interleaved publish, or awaiting the
+    /// branches of a `Command::Fork` or the reply to a `Command::Request`, and sends
+    /// scheduled by `Command::SendAfter` that are not yet due, without resuming or
+    /// delivering them. Other flows are unaffected. As no task of the flow is left, none
+    /// can spawn further tasks for it. Returns the number of tasks removed.
+    pub fn cancel_flow(&mut self, correlation_id: u64) -> usize {
+        let mut set_aside = 0;
+        self.forks.retain(|_, (forkers, _)| {
+            let before = forkers.len();
+            forkers.retain(|task| task.correlation_id() != correlation_id);
+            set_aside += before - forkers.len();
+            !forkers.is_empty()
+        });
+        let before = self.requesters.len();
+        self.requesters
+            .retain(|_, task| task.correlation_id() != correlation_id);
+        set_aside += before - self.requesters.len();
+        // Scheduled sends are counted as pending only once they are due and pushed.
+        let before = self.scheduled.len();
+        self.scheduled
+            .retain(|(_, task)| task.correlation_id() != correlation_id);
+        set_aside += before - self.scheduled.len();
+        let Some(pending) = self.flows.remove(&correlation_id) else {
+            return set_aside;
+        };
+        let depths = &mut self.depths;
+        self.tasks.retain(&mut |task| {
+            let keep = task.correlation_id() != correlation_id;
+            if !keep {
+                // Everything in the bands of an interleaved publish was spawned in its
+                // flow, so goes with it.
+                uncount_task(depths, task);
+            }
+            keep
+        });
+        pending + set_aside
+    }
+
+    /// Forgets the flows with no task pending or scheduled, keeping the state of active
+    /// flows. The runner forgets a flow as soon as its last task is popped through it, so
+    /// this only finds flows whose tasks were removed from `tasks` directly: their count
+    /// in `active_flows` is dropped, along with any of their handlers awaiting the
+    /// branches of a `Command::Fork` or the reply to a `Command::Request`, which can no
+    /// longer be resumed.
+    pub fn gc(&mut self) {
+        let live: HashSet<u64> = self
+            .tasks
+            .iter()
+            .chain(self.scheduled.iter().map(|(_, task)| task))
+            .map(Task::correlation_id)
+            .collect();
+        self.flows.retain(|id, _| live.contains(id));
+        self.forks.retain(|_, (forkers, _)| {
+            forkers.retain(|task| live.contains(&task.correlation_id()));
+            !forkers.is_empty()
+        });
+        self.requesters
+            .retain(|_, task| live.contains(&task.correlation_id()));
+    }
+
+    /// The longest chain of handlers, each sent to by the one before, that ran since
+    /// `run` was last called. A send pushed from outside a handler is 1 deep; siblings
+    /// spawned by the same handler share a depth, and a publish passes its own depth on
+    /// to its subscribers.
+    pub fn max_depth_reached(&self) -> usize {
+        self.max_depth_reached
+    }
+
+    /// Replaces the clock, which is a `LogicalClock` by default. Sends already scheduled
+    /// keep the ticks they are due at.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Passes dead letters to `sink` from now on, instead of adding them to
+    /// `dead_letters`. Letters already there are left in place.
+    pub fn set_dead_letter_sink(&mut self, sink: impl DeadLetterSink + 'static) {
+        self.dead_letter_sink = Some(Box::new(sink));
+    }
+
+    /// The current tick of the runner's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Pushes the scheduled tasks that are due, earliest first.
+    fn release_due(&mut self) {
+        let now = self.clock.now();
+        if self.scheduled.iter().all(|(due, _)| *due > now) {
+            return;
+        }
+        let (mut due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|(due, _)| *due <= now);
+        self.scheduled = later;
+        due.sort_by_key(|(due, _)| *due);
+        self.push_ordered(due.into_iter().map(|(_, task)| task).collect());
+    }
+
+    /// Replaces the bus with `new_bus` between steps and returns the old one. Pending tasks
+    /// are kept: handlers already instantiated from the old bus run to completion, and
+    /// only the commands processed after the swap are routed by `new_bus`. A publish that
+    /// has started delivering skips subscribers that `new_bus` doesn't have.
+    pub fn swap_bus(&mut self, new_bus: MessageBus) -> MessageBus {
+        std::mem::replace(&mut self.msg_bus, new_bus)
+    }
+
+    /// Releases the spare capacity of the task store, the bus and the runner's own
+    /// collections, such as after a burst of tasks or registrations.
+    pub fn shrink_to_fit(&mut self) {
+        self.tasks.shrink_to_fit();
+        self.msg_bus.shrink_to_fit();
+        self.dead_letters.shrink_to_fit();
+        self.publish_reports.shrink_to_fit();
+        self.command_log.shrink_to_fit();
+        self.warnings.shrink_to_fit();
+        self.resolved_routes.shrink_to_fit();
+        self.flows.shrink_to_fit();
+        self.depths.shrink_to_fit();
+        self.forks.shrink_to_fit();
+        self.requesters.shrink_to_fit();
+        self.scheduled.shrink_to_fit();
+    }
+
+    /// The number of message flows with at least one pending task. Only tasks pushed and
+    /// popped through the runner are counted.
+    pub fn active_flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// The number of pending tasks addressed to `topic`: sends to it and publishes of it,
+    /// not counting the deliveries a publish has yet to make. Like `active_flows`, only
+    /// tasks pushed and popped through the runner are counted.
+    pub fn queue_depth(&self, topic: &str) -> usize {
+        self.depths.get(topic).copied().unwrap_or(0)
+    }
+
+    /// Removes and yields the dead letters, oldest first. `dead_letters` is left empty
+    /// even if the iterator is dropped before it is exhausted.
+    pub fn drain_dead_letters(&mut self) -> impl Iterator<Item = DeadLetter> + '_ {
+        self.dead_letters.drain(..)
+    }
+
+    /// Summarizes the pending tasks, from the oldest pushed to the newest.
+    pub fn pending(&self) -> Vec<TaskSummary> {
+        self.tasks.iter().map(TaskSummary::from).collect()
+    }
+
+    /// Advances the next pending task by one step and reports what happened.
+    pub fn step(&mut self) -> StepOutcome {
+        let outcome = self.step_task();
+        if outcome != StepOutcome::Idle && self.tasks.is_empty() {
+            if let Some(on_idle) = &mut self.on_idle {
+                on_idle();
+            }
+        }
+        outcome
+    }
+
+    fn step_task(&mut self) -> StepOutcome {
+        self.clock.tick();
+        self.release_due();
+        let outcome = self.step_band().unwrap_or_else(|| self.step_next());
+        self.spawn_depth = 0;
+        outcome
+    }
+
+    /// Steps the subscriber whose turn it is in the band of an interleaved publish, if
+    /// the next task is one with subscribers left. The subscriber's tasks are swapped in
+    /// as the runner's, so what it spawns stays in the band. Its turn passes once it is
+    /// its only task left, as it then yielded or its spawned tasks completed.
+    fn step_band(&mut self) -> Option<StepOutcome> {
+        let Some(Task::Publish(publish)) = self.tasks.next() else {
+            return None;
+        };
+        if publish.band.is_empty() {
+            if self.msg_bus.publish_order != PublishOrder::PriorityInterleaved {
+                return None;
+            }
+            let sends = publish.next_band(&self.msg_bus);
+            if sends.is_empty() {
+                return None;
+            }
+            let mut band = Vec::new();
+            for send in sends {
+                let mut task = Task::Send(send);
+                self.track(&mut task);
+                let mut store: Box<dyn TaskStore> = Box::new(StackStore::default());
+                store.push(task);
+                band.push(store);
+            }
+            let publish = self.band_publish();
+            publish.band = band;
+            publish.turn = 0;
+        }
+
+        let publish = self.band_publish();
+        let turn = publish.turn;
+        let mut store = std::mem::replace(&mut publish.band[turn], Box::new(StackStore::default()));
+        std::mem::swap(&mut self.tasks, &mut store);
+        let outcome = self.step_next();
+        std::mem::swap(&mut self.tasks, &mut store);
+
+        let publish = self.band_publish();
+        if store.is_empty() {
+            publish.band.remove(turn);
+        } else {
+            let yielded = store.len() == 1;
+            publish.band[turn] = store;
+            if yielded {
+                publish.turn += 1;
+            }
+        }
+        if publish.turn >= publish.band.len() {
+            publish.turn = 0;
+        }
+        Some(outcome)
+    }
+
+    /// The interleaved publish `step_band` is stepping, which stays the next task.
+    fn band_publish(&mut self) -> &mut PublishTask {
+        match self.tasks.next() {
+            Some(Task::Publish(publish)) => publish,
+            _ => unreachable!("the interleaved publish is the next task"),
+        }
+    }
+
+    fn step_next(&mut self) -> StepOutcome {
+        match self.tasks.next() {
+            Some(Task::Send(send)) => {
+                self.spawn_depth = send.depth + 1;
+                self.max_depth_reached = self.max_depth_reached.max(send.depth);
+                if self.dry_run {
+                    self.resolved_routes
+                        .push((send.pattern.clone(), send.handler_id.clone()));
+                    self.pop();
+                    return StepOutcome::Completed;
+                }
+                if self.max_resumes.is_some_and(|max| send.resumes >= max) {
+                    self.abandon_current(|send| RunnerWarning::RunawayHandler {
+                        topic: send.pattern.clone(),
+                        handler_id: send.handler_id.clone(),
+                        resumes: send.resumes,
+                    });
+                    return StepOutcome::Completed;
+                }
+                let correlation_id = send.correlation_id;
+                let mut headers = send.headers.clone();
+                if send.resumes == 0 {
+                    if let Some(tx) = &self.trace_tx {
+                        // The receiver may have been dropped; events are then discarded.
+                        let _ = tx.send(TraceEvent::Enter(send.handler_id.clone()));
+                    }
+                }
+                let state = match send.batch.pop_front() {
+                    Some(cmd) => CoroutineState::Yielded(cmd),
+                    None => {
+                        let input = match (send.awaiting.take(), &mut self.resume_arg_fn) {
+                            (Some(slot), _) => slot.get_any().unwrap_or_else(|| Rc::new(())),
+                            (None, Some(resume_arg_fn)) => resume_arg_fn(send),
+                            (None, None) => send.msg.clone(),
+                        };
+                        #[cfg(feature = "timing")]
+                        send.started.get_or_insert(self.clock.now());
+                        send.resume(input)
+                    }
+                };
+                match state {
+                    CoroutineState::Yielded(mut cmd) => {
+                        if self.capture_command {
+                            self.stepped_command = Some(CommandRecord::new(&send.handler_id, &cmd));
+                        }
+                        if !self.middleware.admit(&self.msg_bus, &mut cmd) {
+                            bus_debug!(
+                                kind = cmd.name(),
+                                topic = cmd.topic(),
+                                handler_id = send.handler_id.as_str();
+                                "command rejected"
+                            );
+                            return StepOutcome::Resumed;
+                        }
+                        bus_trace!(
+                            kind = cmd.name(),
+                            topic = cmd.topic(),
+                            handler_id = send.handler_id.as_str();
+                            "command processed"
+                        );
+                        if self.record_commands {
+                            self.command_log
+                                .push(CommandRecord::new(&send.handler_id, &cmd));
+                        }
+                        if let Some(on_yield) = &mut self.on_yield {
+                            on_yield(&cmd);
+                        }
+                        if let Command::SendWithHeaders { headers: added, .. } = &mut cmd {
+                            Rc::make_mut(&mut headers).extend(std::mem::take(added));
+                        }
+                        let reply_into = match &cmd {
+                            Command::RequestInto { reply, .. } => Some(reply.clone()),
+                            _ => None,
+                        };
+                        let msg_priority = match &cmd {
+                            Command::SendPrioritized { msg_priority, .. }
+                            | Command::PublishPrioritized { msg_priority, .. } => *msg_priority,
+                            _ => 0,
+                        };
+                        // Process the yielded command.
+                        match cmd {
+                            // Endpoints are looked up when the send is processed. A handler
+                            // that is already running keeps its coroutine even if its endpoint
+                            // is deregistered mid-flight; only later sends see the change.
+                            Command::Send { topic, msg }
+                            | Command::SendWithHeaders { topic, msg, .. }
+                            | Command::SendPrioritized { topic, msg, .. } => {
+                                let msg = self.apply_transform(msg);
+                                let action = match &mut self.on_missing_endpoint {
+                                    Some(f) if self.msg_bus.resolve_endpoints(&topic).is_none() => {
+                                        Some(f(&mut self.msg_bus, &topic, &msg))
+                                    }
+                                    _ => None,
+                                };
+                                match action {
+                                    Some(MissingEndpointAction::DeadLetter) => {
+                                        let reason = DeadLetterReason::RefusedByCallback;
+                                        self.dead_letter("Send", topic, msg, reason);
+                                        return StepOutcome::Resumed;
+                                    }
+                                    Some(MissingEndpointAction::Drop) => {
+                                        return StepOutcome::Resumed
+                                    }
+                                    Some(MissingEndpointAction::Retry) | None => {}
+                                }
+                                let Some((subs, refused)) =
+                                    self.msg_bus.endpoints_for(&topic, msg.as_ref())
+                                else {
+                                    if let Some(sub) = &self.msg_bus.default_endpoint {
+                                        let mut send = SendTask::for_subscription(sub, topic, msg);
+                                        send.correlation_id = correlation_id;
+                                        send.headers = headers.clone();
+                                        send.msg_priority = msg_priority;
+                                        self.push(Task::Send(send));
+                                        return StepOutcome::Resumed;
+                                    }
+                                    match self.send_fallback {
+                                        SendFallback::FanOutToSubscribers
+                                            if self.msg_bus.subscriber_count(&topic) > 0 =>
+                                        {
+                                            let mut publish = PublishTask::new(topic, msg);
+                                            publish.correlation_id = correlation_id;
+                                            publish.headers = headers.clone();
+                                            publish.msg_priority = msg_priority;
+                                            self.push(Task::Publish(publish));
+                                        }
+                                        SendFallback::Error => {
+                                            self.abandon_current(|_| {
+                                                RunnerWarning::UndeliverableSend { topic }
+                                            });
+                                            return StepOutcome::Completed;
+                                        }
+                                        _ => self.dead_letter(
+                                            "Send",
+                                            topic,
+                                            msg,
+                                            DeadLetterReason::NoEndpoint,
+                                        ),
+                                    }
+                                    return StepOutcome::Resumed;
+                                };
+                                // Ordered so the highest-priority endpoint is taken first,
+                                // whether the store takes the newest or oldest task first.
+                                let sends = subs
+                                    .iter()
+                                    .map(|sub| {
+                                        let mut send = SendTask::for_subscription(
+                                            sub,
+                                            topic.clone(),
+                                            msg.clone(),
+                                        );
+                                        send.correlation_id = correlation_id;
+                                        send.headers = headers.clone();
+                                        send.msg_priority = msg_priority;
+                                        Task::Send(send)
+                                    })
+                                    .collect();
+                                self.refuse("Send", &topic, &msg, refused);
+                                self.push_ordered(sends);
+                            }
+                            Command::SendOwned { topic, msg } => {
+                                let sub = match self.msg_bus.endpoint_for(&topic, msg.as_ref()) {
+                                    Ok(sub) => sub,
+                                    Err(reason) => {
+                                        self.dead_letter("SendOwned", topic, Rc::from(msg), reason);
+                                        return StepOutcome::Resumed;
+                                    }
+                                };
+                                let mut send = SendTask::for_subscription(sub, topic, Rc::new(()));
+                                send.owned = Some(msg);
+                                send.correlation_id = correlation_id;
+                                send.headers = headers.clone();
+                                self.push(Task::Send(send));
+                            }
+                            Command::SendBest { topic, msg } => {
+                                let msg = self.apply_transform(msg);
+                                let sub = match self.msg_bus.endpoint_for(&topic, msg.as_ref()) {
+                                    Ok(sub) => sub,
+                                    Err(reason) => {
+                                        self.dead_letter("SendBest", topic, msg, reason);
+                                        return StepOutcome::Resumed;
+                                    }
+                                };
+                                let mut send = SendTask::for_subscription(sub, topic, msg);
+                                send.correlation_id = correlation_id;
+                                send.headers = headers.clone();
+                                self.push(Task::Send(send));
+                            }
+                            Command::Tee { topic, msg } => {
+                                let msg = self.apply_transform(msg);
+                                let Some((subs, refused)) =
+                                    self.msg_bus.endpoints_for(&topic, msg.as_ref())
+                                else {
+                                    self.dead_letter(
+                                        "Tee",
+                                        topic,
+                                        msg,
+                                        DeadLetterReason::NoEndpoint,
+                                    );
+                                    return StepOutcome::Resumed;
+                                };
+                                let sends = subs
+                                    .iter()
+                                    .map(|sub| {
+                                        let mut send = SendTask::for_subscription(
+                                            sub,
+                                            topic.clone(),
+                                            msg.clone(),
+                                        );
+                                        send.correlation_id = correlation_id;
+                                        send.headers = headers.clone();
+                                        Task::Send(send)
+                                    })
+                                    .collect();
+                                self.refuse("Tee", &topic, &msg, refused);
+                                self.push_deferred(sends);
+                            }
+                            Command::SendAndNotify { topic, msg } => {
+                                let msg = self.apply_transform(msg);
+                                let (subs, refused) = self
+                                    .msg_bus
+                                    .endpoints_for(&topic, msg.as_ref())
+                                    .unwrap_or_default();
+                                if subs.is_empty()
+                                    && refused == 0
+                                    && self.msg_bus.subscriber_count(&topic) == 0
+                                {
+                                    self.dead_letter(
+                                        "SendAndNotify",
+                                        topic,
+                                        msg,
+                                        DeadLetterReason::NoEndpoint,
+                                    );
+                                    return StepOutcome::Resumed;
+                                }
+                                let mut tasks: Vec<Task> = subs
+                                    .iter()
+                                    .map(|sub| {
+                                        let mut send = SendTask::for_subscription(
+                                            sub,
+                                            topic.clone(),
+                                            msg.clone(),
+                                        );
+                                        send.correlation_id = correlation_id;
+                                        send.headers = headers.clone();
+                                        Task::Send(send)
+                                    })
+                                    .collect();
+                                self.refuse("SendAndNotify", &topic, &msg, refused);
+                                let mut publish = PublishTask::new(topic, msg);
+                                publish.correlation_id = correlation_id;
+                                publish.headers = headers.clone();
+                                tasks.push(Task::Publish(publish));
+                                self.push_ordered(tasks);
+                            }
+                            Command::Request { topic, msg }
+                            | Command::RequestInto { topic, msg, .. } => {
+                                let slot = reply_into.unwrap_or_default();
+                                send.awaiting = Some(slot.clone());
+                                let sub = match self.msg_bus.endpoint_for(&topic, msg.as_ref()) {
+                                    Ok(sub) => sub,
+                                    Err(reason) => {
+                                        self.dead_letter("Request", topic, msg, reason);
+                                        return StepOutcome::Resumed;
+                                    }
+                                };
+                                let mut request = SendTask::for_subscription(sub, topic, msg);
+                                request.reply_to = Some(slot);
+                                request.correlation_id = correlation_id;
+                                request.headers = headers.clone();
+                                // Set aside, so that it isn't resumed before the reply is
+                                // in whichever order the store takes tasks in.
+                                if let Some(requester) = self.pop() {
+                                    self.last_request_token += 1;
+                                    let token = self.last_request_token;
+                                    self.requesters.insert(token, requester);
+                                    request.requester = Some(token);
+                                }
+                                self.push(Task::Send(request));
+                            }
+                            Command::Register(subscription) => {
+                                self.msg_bus.register(subscription);
+                            }
+                            Command::Deregister(topic) => {
+                                self.msg_bus.deregister(&topic);
+                            }
+                            Command::Subscribe(subscription) => {
+                                self.msg_bus.subscribe(subscription);
+                            }
+                            Command::Unsubscribe((topic, handler_id)) => {
+                                self.msg_bus.remove_subscription(&topic, &handler_id);
+                            }
+                            Command::Query { query, reply } => {
+                                *reply.borrow_mut() = Some(self.msg_bus.answer(&query));
+                            }
+                            Command::SendAfter { topic, msg, delay } => {
+                                let msg = self.apply_transform(msg);
+                                let Some((subs, refused)) =
+                                    self.msg_bus.endpoints_for(&topic, msg.as_ref())
+                                else {
+                                    self.dead_letter(
+                                        "SendAfter",
+                                        topic,
+                                        msg,
+                                        DeadLetterReason::NoEndpoint,
+                                    );
+                                    return StepOutcome::Resumed;
+                                };
+                                let due = self.clock.now().saturating_add(delay);
+                                for sub in subs {
+                                    let mut send =
+                                        SendTask::for_subscription(sub, topic.clone(), msg.clone());
+                                    send.correlation_id = correlation_id;
+                                    send.headers = headers.clone();
+                                    send.depth = self.spawn_depth;
+                                    self.scheduled.push((due, Task::Send(send)));
+                                }
+                                self.refuse("SendAfter", &topic, &msg, refused);
+                            }
+                            Command::Requeue { msg } => {
+                                if send.requeues >= self.max_requeues {
+                                    let topic = send.pattern.clone();
+                                    self.abandon_current(|send| RunnerWarning::RequeueLimit {
+                                        topic: send.pattern.clone(),
+                                        handler_id: send.handler_id.clone(),
+                                    });
+                                    let reason = DeadLetterReason::RequeueLimit;
+                                    self.dead_letter("Requeue", topic, msg, reason);
+                                    return StepOutcome::Completed;
+                                }
+                                send.requeues += 1;
+                                send.msg = msg;
+                                // Still pending, so the flow's task count is unchanged.
+                                if let Some(task) = self.tasks.pop_next() {
+                                    self.tasks.push_last(task);
+                                }
+                            }
+                            Command::Fork {
+                                branches,
+                                join_token,
+                            } => {
+                                let mut sends = Vec::new();
+                                for (topic, msg) in branches {
+                                    let Some((subs, refused)) =
+                                        self.msg_bus.endpoints_for(&topic, msg.as_ref())
+                                    else {
+                                        let reason = DeadLetterReason::NoEndpoint;
+                                        self.dead_letter("Fork", topic, msg, reason);
+                                        continue;
+                                    };
+                                    sends.extend(subs.iter().map(|sub| {
+                                        let mut send = SendTask::for_subscription(
+                                            sub,
+                                            topic.clone(),
+                                            msg.clone(),
+                                        );
+                                        send.correlation_id = correlation_id;
+                                        send.headers = headers.clone();
+                                        send.join = Some(join_token);
+                                        Task::Send(send)
+                                    }));
+                                    self.refuse("Fork", &topic, &msg, refused);
+                                }
+                                if sends.is_empty() {
+                                    return StepOutcome::Resumed;
+                                }
+                                if let Some(forker) = self.pop() {
+                                    let (forkers, running) =
+                                        self.forks.entry(join_token).or_default();
+                                    forkers.push(forker);
+                                    *running += sends.len();
+                                }
+                                self.push_ordered(sends);
+                            }
+                            Command::Many(cmds) => {
+                                // Flattened without recursion, so no depth of nesting can
+                                // overflow the stack.
+                                let mut pending: Vec<Command> = cmds.into_iter().rev().collect();
+                                while let Some(cmd) = pending.pop() {
+                                    match cmd {
+                                        Command::Many(cmds) => {
+                                            pending.extend(cmds.into_iter().rev())
+                                        }
+                                        cmd => send.batch.push_back(cmd),
+                                    }
+                                }
+                            }
+                            Command::Publish { pattern, msg }
+                            | Command::PublishPrioritized { pattern, msg, .. } => {
+                                let mut publish = PublishTask::new(pattern, msg);
+                                publish.correlation_id = correlation_id;
+                                publish.headers = headers.clone();
+                                publish.msg_priority = msg_priority;
+                                self.push(Task::Publish(publish));
+                            }
+                            Command::PublishTopN { pattern, msg, n } => {
+                                let mut publish = PublishTask::top_n(pattern, msg, n);
+                                publish.correlation_id = correlation_id;
+                                publish.headers = headers.clone();
+                                self.push(Task::Publish(publish));
+                            }
+                            Command::PublishBounded {
+                                pattern,
+                                msg,
+                                max_recipients,
+                            } => {
+                                let mut publish =
+                                    PublishTask::bounded(pattern, msg, max_recipients);
+                                publish.correlation_id = correlation_id;
+                                publish.headers = headers.clone();
+                                self.push(Task::Publish(publish));
+                            }
+                        }
+                        StepOutcome::Resumed
+                    }
+                    CoroutineState::Complete(result) => {
+                        if let Some(Task::Send(send)) = self.pop() {
+                            #[cfg(feature = "timing")]
+                            self.record_timing(&send);
+                            self.trace_exit(&send);
+                            if let Some(on_complete) = &mut self.on_complete {
+                                on_complete(&send.handler_id);
+                            }
+                            if let Err(error) = &result {
+                                match self.msg_bus.supervision(&send.handler_id) {
+                                    Supervision::Ignore => {}
+                                    Supervision::Restart if send.restarted => {}
+                                    Supervision::Restart => {
+                                        let sub =
+                                            self.msg_bus.handler(&send.pattern, &send.handler_id);
+                                        if let Some(sub) = sub {
+                                            let restarted = send.restart(sub);
+                                            self.push(Task::Send(restarted));
+                                            return StepOutcome::Completed;
+                                        }
+                                    }
+                                    Supervision::Escalate(supervisor) => {
+                                        self.escalate(supervisor, &send, error.clone());
+                                    }
+                                }
+                            }
+                            // After supervision, as a restarted branch is still running.
+                            if let Some(join_token) = send.join {
+                                self.join_branch(join_token);
+                            }
+                            if let (Some(topic), Ok(_)) = (&send.once, &result) {
+                                self.msg_bus.remove_once(topic, &send.handler_id);
+                            }
+                            if send.is_request {
+                                self.reply = result.clone().ok().flatten();
+                            }
+                            if let Some(slot) = &send.reply_to {
+                                slot.set_any(result.clone().ok().flatten());
+                            }
+                            if let Some(token) = send.requester {
+                                self.resume_requester(token);
+                            }
+                            if let Some(report) = send.report {
+                                {
+                                    let mut report = report.borrow_mut();
+                                    match result {
+                                        Ok(_) => report.delivered += 1,
+                                        Err(_) => report.failed += 1,
+                                    }
+                                }
+                                self.finish_report(report);
+                            }
+                        }
+                        StepOutcome::Completed
+                    }
+                }
+            }
+            Some(Task::Publish(publish)) => {
+                if self.warn_duplicate_matches && publish.matched.is_none() {
+                    let (_, repeated) = self.msg_bus.match_subscribers(&publish.pattern);
+                    for sub in repeated {
+                        self.warnings.push(RunnerWarning::DuplicateMatch {
+                            pattern: publish.pattern.clone(),
+                            topic: sub.topic.clone(),
+                            handler_id: sub.handler_id.clone(),
+                        });
+                    }
+                }
+                match publish.next_task(&self.msg_bus) {
+                    Some(send) => {
+                        self.push(Task::Send(send));
+                        StepOutcome::Spawned
+                    }
+                    None => {
+                        if let Some(Task::Publish(publish)) = self.pop() {
+                            let skipped = publish.report.borrow().skipped.len();
+                            for _ in 0..skipped {
+                                self.dead_letter(
+                                    "PublishBounded",
+                                    publish.pattern.clone(),
+                                    publish.msg.clone(),
+                                    DeadLetterReason::OverCapacity,
+                                );
+                            }
+                            self.finish_report(publish.report);
+                        }
+                        StepOutcome::Completed
+                    }
+                }
+            }
+            None => StepOutcome::Idle,
+        }
+    }
+
+    /// Steps like `step` and reports what the step did.
+    pub fn step_debug(&mut self) -> StepReport {
+        let task = self.tasks.next().map(|task| TaskSummary::from(&*task));
+        self.capture_command = true;
+        self.step();
+        self.capture_command = false;
+        StepReport {
+            task,
+            command: self.stepped_command.take(),
+            depth: self.tasks.len(),
+        }
+    }
+
+    /// Reports the command the next task's handler yields when it is next stepped, without
+    /// stepping it. The rest of a `Command::Many` batch is known already; otherwise a
+    /// fresh instance of the handler is resumed with the delivered message as many times
+    /// as the pending one will have been, which is only done for handlers marked with
+    /// `MessageBus::mark_pure`. Returns `None` for anything else, including handlers about
+    /// to complete, awaiting a reply, or yet to receive an owned payload.
+    pub fn try_peek_command(&mut self) -> Option<CommandRecord> {
+        let Some(Task::Send(send)) = self.tasks.next() else {
+            return None;
+        };
+        if let Some(cmd) = send.batch.front() {
+            return Some(CommandRecord::new(&send.handler_id, cmd));
+        }
+        if !self.msg_bus.is_pure(&send.handler_id)
+            || send.awaiting.is_some()
+            || send.owned.is_some()
+        {
+            return None;
+        }
+        let sub = self.msg_bus.handler(&send.pattern, &send.handler_id)?;
+        let mut replay = (sub.actor_fn)();
+        let mut yielded = None;
+        for _ in 0..=send.resumes {
+            match replay.as_mut().resume(send.envelope()) {
+                CoroutineState::Yielded(cmd) => yielded = Some(cmd),
+                CoroutineState::Complete(_) => return None,
+            }
+        }
+        yielded.map(|cmd| CommandRecord::new(&send.handler_id, &cmd))
+    }
+
+    pub fn run(&mut self) {
+        self.max_depth_reached = 0;
+        while !self.tasks.is_empty() || !self.scheduled.is_empty() {
+            self.step();
+        }
+    }
+
+    /// Runs like `run`, but calls `between` after every `every` steps, such as to embed
+    /// the runner in a host loop that has other work to do. `between` may inspect the
+    /// runner or push further tasks, and stops the run by returning `false`, leaving the
+    /// remaining tasks pending.
+    ///
+    /// # Panics
+    ///
+    /// If `every` is zero.
+    pub fn run_cooperative(
+        &mut self,
+        every: usize,
+        mut between: impl FnMut(&mut TaskRunner) -> bool,
+    ) {
+        assert!(every > 0, "run_cooperative needs a nonzero step interval");
+        self.max_depth_reached = 0;
+        let mut steps = 0;
+        while !self.tasks.is_empty() || !self.scheduled.is_empty() {
+            self.step();
+            steps += 1;
+            if steps % every == 0 && !between(self) {
+                return;
+            }
+        }
+    }
+
+    /// Runs each root as an independent flow on its own stack, stepping the flows in turn
+    /// until all of them are idle. Each flow keeps the nesting `run` would give it, but
+    /// no root waits for the one pushed after it to complete. Tasks already pending are
+    /// left for a later `run`, and only the stepped flow's tasks are visible to `tasks`
+    /// and `pending` while a step runs.
+    pub fn run_interleaved(&mut self, roots: Vec<Task>) {
+        let mut flows: Vec<Box<dyn TaskStore>> = roots
+            .into_iter()
+            .map(|mut root| {
+                let mut store: Box<dyn TaskStore> = Box::new(StackStore::default());
+                self.track(&mut root);
+                store.push(root);
+                store
+            })
+            .collect();
+        while !flows.is_empty() {
+            flows.retain_mut(|store| {
+                std::mem::swap(&mut self.tasks, store);
+                self.step_task();
+                std::mem::swap(&mut self.tasks, store);
+                !store.is_empty()
+            });
+        }
+    }
+
+    /// Sends `msg` to the endpoint on `topic`, runs until every task has completed and
+    /// returns the endpoint handler's reply. Returns `None` if the handler replied with
+    /// nothing, failed or was abandoned, or if `topic` has no endpoint, in which case the
+    /// message is dead-lettered. Only the highest-priority endpoint on `topic` is invoked.
+    pub fn request(&mut self, topic: impl Into<Topic>, msg: Rc<dyn Any>) -> Option<Rc<dyn Any>> {
+        let topic = topic.into();
+        let sub = match self.msg_bus.endpoint_for(&topic, msg.as_ref()) {
+            Ok(sub) => sub,
+            Err(reason) => {
+                self.dead_letter("Send", topic, msg, reason);
+                return None;
+            }
+        };
+        let mut send = SendTask::for_subscription(sub, topic, msg);
+        send.is_request = true;
+        self.reply = None;
+        self.push(Task::Send(send));
+        self.run();
+        self.reply.take()
+    }
+
+    /// Sends a payload that arrived as bytes, such as across the FFI boundary, to the
+    /// highest-priority endpoint on `topic`, rebuilding it with the type registered under
+    /// `type_tag`. The send is pushed but not run. Returns `false`, pushing nothing, if
+    /// the payload can't be rebuilt; a payload for a topic without endpoints is
+    /// dead-lettered.
+    pub fn send_tagged(&mut self, topic: impl Into<Topic>, type_tag: u32, bytes: &[u8]) -> bool {
+        let Some(msg) = self.msg_bus.decode(type_tag, bytes) else {
+            return false;
+        };
+        let topic = topic.into();
+        let sub = match self.msg_bus.endpoint_for(&topic, msg.as_ref()) {
+            Ok(sub) => sub,
+            Err(reason) => {
+                self.dead_letter("Send", topic, msg, reason);
+                return true;
+            }
+        };
+        let send = SendTask::for_subscription(sub, topic, msg);
+        self.push(Task::Send(send));
+        true
+    }
+
+    /// Writes `command_log` as newline-delimited JSON, one record per line.
+    #[cfg(feature = "serde")]
+    pub fn write_log<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for record in &self.command_log {
+            serde_json::to_writer(&mut w, record)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Exports `timings` in the Chrome trace event format, one complete (`"X"`) event per
+    /// handler invocation, for loading into `chrome://tracing` or Perfetto. Timestamps are
+    /// in ticks of the runner's `Clock` from the earliest recorded start, which viewers
+    /// show as microseconds.
+    #[cfg(feature = "timing")]
+    pub fn trace_to_chrome_json(&self) -> String {
+        let Some(origin) = self.timings.iter().map(|timing| timing.start).min() else {
+            return r#"{"traceEvents":[]}"#.to_string();
+        };
+        let mut json = String::from(r#"{"traceEvents":["#);
+        for (i, timing) in self.timings.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(r#"{"name":"#);
+            push_json_str(&mut json, &timing.handler_id);
+            json.push_str(r#","cat":"#);
+            push_json_str(&mut json, &timing.topic);
+            json.push_str(&format!(
+                r#","ph":"X","ts":{},"dur":{},"pid":0,"tid":0}}"#,
+                timing.start - origin,
+                timing.end - timing.start,
+            ));
+        }
+        json.push_str("]}");
+        json
+    }
+
+    #[cfg(feature = "timing")]
+    fn record_timing(&mut self, send: &SendTask) {
+        if let Some(start) = send.started {
+            self.timings.push(HandlerTiming {
+                handler_id: send.handler_id.clone(),
+                topic: send.pattern.clone(),
+                start,
+                end: self.clock.now(),
+            });
+        }
+    }
+
+    fn trace_exit(&self, send: &SendTask) {
+        if let Some(tx) = &self.trace_tx {
+            let _ = tx.send(TraceEvent::Exit(send.handler_id.clone()));
+        }
+    }
+
+    fn apply_transform(&self, msg: Rc<dyn Any>) -> Rc<dyn Any> {
+        match &self.transform {
+            Some(transform) => transform(msg),
+            None => msg,
+        }
+    }
+
+    fn dead_letter(
+        &mut self,
+        kind: &'static str,
+        topic: Topic,
+        msg: Rc<dyn Any>,
+        reason: DeadLetterReason,
+    ) {
+        bus_debug!(
+            kind = kind,
+            topic = topic.as_str(),
+            handler_id = "";
+            "dead letter"
+        );
+        let dl = DeadLetter { topic, msg, reason };
+        match self.dead_letter_sink.take() {
+            Some(mut sink) => {
+                sink.on_dead_letter(self, dl);
+                self.dead_letter_sink = Some(sink);
+            }
+            None => self.dead_letters.push(dl),
+        }
+    }
+
+    /// Pops the current send without resuming it again, counting it as failed, and
+    /// records why.
+    fn abandon_current(&mut self, warning: impl FnOnce(&SendTask) -> RunnerWarning) {
+        if let Some(Task::Send(send)) = self.pop() {
+            bus_debug!(
+                kind = "Send",
+                topic = send.pattern.as_str(),
+                handler_id = send.handler_id.as_str();
+                "handler abandoned"
+            );
+            #[cfg(feature = "timing")]
+            self.record_timing(&send);
+            self.trace_exit(&send);
+            if let Some(join_token) = send.join {
+                self.join_branch(join_token);
+            }
+            if let Some(token) = send.requester {
+                self.resume_requester(token);
+            }
+            self.warnings.push(warning(&send));
+            if let Some(report) = send.report {
+                report.borrow_mut().failed += 1;
+                self.finish_report(report);
+            }
+        }
+    }
+
+    /// Dead-letters `msg` as a `TypeMismatch` once for each of the `refused` typed
+    /// endpoints `MessageBus::endpoints_for` left out.
+    fn refuse(&mut self, kind: &'static str, topic: &Topic, msg: &Rc<dyn Any>, refused: usize) {
+        for _ in 0..refused {
+            let reason = DeadLetterReason::TypeMismatch;
+            self.dead_letter(kind, topic.clone(), msg.clone(), reason);
+        }
+    }
+
+    /// Counts a branch of the fork `join_token` as done, resuming the handlers awaiting the
+    /// fork once none is left running.
+    fn join_branch(&mut self, join_token: u64) {
+        let Some((_, running)) = self.forks.get_mut(&join_token) else {
+            return;
+        };
+        *running -= 1;
+        if *running == 0 {
+            if let Some((forkers, _)) = self.forks.remove(&join_token) {
+                self.push_ordered(forkers);
+            }
+        }
+    }
+
+    /// Pushes back the handler set aside under `token` by a `Command::Request`, to be
+    /// resumed with the reply, or `()` if the request's handler gave none.
+    fn resume_requester(&mut self, token: u64) {
+        if let Some(requester) = self.requesters.remove(&token) {
+            self.push(requester);
+        }
+    }
+
+    /// Sends the failure of `send`'s handler to the endpoints on `supervisor`.
+    fn escalate(&mut self, supervisor: Topic, send: &SendTask, error: HandlerError) {
+        let failure: Rc<dyn Any> = Rc::new(HandlerFailure {
+            topic: send.pattern.clone(),
+            handler_id: send.handler_id.clone(),
+            error,
+        });
+        let Some(subs) = self.msg_bus.resolve_endpoints(&supervisor) else {
+            let reason = DeadLetterReason::NoEndpoint;
+            self.dead_letter("Escalate", supervisor, failure, reason);
+            return;
+        };
+        let sends = subs
+            .iter()
+            .map(|sub| {
+                let mut escalation =
+                    SendTask::for_subscription(sub, supervisor.clone(), failure.clone());
+                escalation.correlation_id = send.correlation_id;
+                escalation.headers = send.headers.clone();
+                Task::Send(escalation)
+            })
+            .collect();
+        self.push_ordered(sends);
+    }
+
+    /// Surfaces a publish report once its last holder (the publish or one of its
+    /// handlers) has finished.
+    fn finish_report(&mut self, report: Rc<RefCell<PublishReport>>) {
+        if let Ok(report) = Rc::try_unwrap(report) {
+            self.publish_reports.push(report.into_inner());
+        }
+    }
+}
+
+/// Configures a `TaskRunner` in one expression, from `TaskRunner::builder`. Options not
+/// set keep the defaults of `TaskRunner::new`.
+pub struct TaskRunnerBuilder {
+    runner: TaskRunner,
+}
+
+impl TaskRunnerBuilder {
+    /// Schedules tasks with `store`, e.g. a `QueueStore` for breadth-first order.
+    pub fn store(mut self, store: impl TaskStore + 'static) -> Self {
+        self.runner.tasks = Box::new(store);
+        self
+    }
+
+    pub fn bus(mut self, bus: MessageBus) -> Self {
+        self.runner.msg_bus = bus;
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.runner.set_clock(clock);
+        self
+    }
+
+    pub fn dead_letter_sink(mut self, sink: impl DeadLetterSink + 'static) -> Self {
+        self.runner.set_dead_letter_sink(sink);
+        self
+    }
+
+    pub fn max_resumes(mut self, max_resumes: u32) -> Self {
+        self.runner.max_resumes = Some(max_resumes);
+        self
+    }
+
+    pub fn max_requeues(mut self, max_requeues: u32) -> Self {
+        self.runner.max_requeues = max_requeues;
+        self
+    }
+
+    pub fn send_fallback(mut self, send_fallback: SendFallback) -> Self {
+        self.runner.send_fallback = send_fallback;
+        self
+    }
+
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.runner.transform = Some(transform);
+        self
+    }
+
+    pub fn record_commands(mut self, record_commands: bool) -> Self {
+        self.runner.record_commands = record_commands;
+        self
+    }
+
+    pub fn warn_on_drop(mut self, warn_on_drop: bool) -> Self {
+        self.runner.warn_on_drop = warn_on_drop;
+        self
+    }
+
+    pub fn warn_duplicate_matches(mut self, warn_duplicate_matches: bool) -> Self {
+        self.runner.warn_duplicate_matches = warn_duplicate_matches;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.runner.dry_run = dry_run;
+        self
+    }
+
+    pub fn build(self) -> TaskRunner {
+        self.runner
+    }
+}
+
+/// Appends `s` to `json` as a quoted JSON string.
+#[cfg(feature = "timing")]
+fn push_json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Counts one task addressed to `topic` as no longer pending.
+fn decrement_depth(depths: &mut HashMap<Topic, usize>, topic: &Topic) {
+    if let Some(pending) = depths.get_mut(topic) {
+        *pending -= 1;
+        if *pending == 0 {
+            depths.remove(topic);
+        }
+    }
+}
+
+/// Counts a dropped task as no longer pending, along with the tasks held in its bands if
+/// it is an interleaved publish.
+fn uncount_task(depths: &mut HashMap<Topic, usize>, task: &Task) {
+    decrement_depth(depths, task.topic());
+    if let Task::Publish(publish) = task {
+        for task in publish.band.iter().flat_map(|store| store.iter()) {
+            uncount_task(depths, task);
+        }
+    }
+}
+
+pub struct Subscription {
+    /// The shareable message handler for the subscription.
+    pub actor_fn: ActorFn,
+    /// Store a copy of the handler ID for faster equality checks.
+    pub handler_id: String,
+    /// The topic for the subscription.
+    pub topic: Topic,
+    /// The priority for the subscription determines the ordering of handlers receiving
+    /// messages being processed, higher priority handlers will receive messages before
+    /// lower priority handlers.
+    pub priority: u8,
+    /// The group the subscription belongs to, which `MessageBus::set_group_enabled` toggles.
+    pub group: Option<String>,
+}
+
+// Subscriptions are keyed by topic and handler ID alone, so subscribing again with another
+// priority replaces the subscription; `MessageBus::update_priority` changes it explicitly.
+impl Hash for Subscription {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.topic.hash(state);
+        self.handler_id.hash(state);
+    }
+}
+
+impl PartialEq for Subscription {
+    fn eq(&self, other: &Self) -> bool {
+        self.topic == other.topic && self.handler_id == other.handler_id
+    }
+}
+
+impl Eq for Subscription {}
+
+impl Subscription {
+    /// A subscription of `handler_id` on `topic` at priority 0, in no group.
+    pub fn new(
+        topic: impl Into<Topic>,
+        handler_id: impl Into<String>,
+        actor_fn: impl Fn() -> ActorCoroutine + 'static,
+    ) -> Self {
+        Self {
+            actor_fn: Box::new(actor_fn),
+            handler_id: handler_id.into(),
+            topic: topic.into(),
+            priority: 0,
+            group: None,
+        }
+    }
+
+    /// Sets the priority the subscription is delivered to in.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Puts the subscription in `group`, for `MessageBus::set_group_enabled` to toggle.
+    pub fn with_group(mut self, group: impl Into<String>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+}
+
+impl Display for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sub::{}:{}", self.topic, self.handler_id)
+    }
+}
+
+/// A subscription whose handler keeps state across deliveries. The bus holds one `S` per
+/// handler ID, shared by every stateful endpoint and subscription with that ID, and
+/// passes it to `actor_fn` each time the handler is instantiated for a delivery.
+///
+/// The state is a `RefCell` borrowed only for the duration of the `actor_fn` call, so a
+/// coroutine can't hold on to it; copy what it needs out instead. Holding a borrow of
+/// the state from `MessageBus::state` while the runner instantiates the handler panics.
+pub struct StatefulSubscription<S> {
+    pub actor_fn: Box<dyn Fn(&mut S) -> ActorCoroutine>,
+    pub handler_id: String,
+    pub topic: Topic,
+    pub priority: u8,
+}
+
+impl<S: 'static> StatefulSubscription<S> {
+    fn into_subscription(self, state: Rc<RefCell<S>>) -> Subscription {
+        let actor_fn = self.actor_fn;
+        Subscription {
+            actor_fn: Box::new(move || actor_fn(&mut state.borrow_mut())),
+            handler_id: self.handler_id,
+            topic: self.topic,
+            priority: self.priority,
+            group: None,
+        }
+    }
+}
+
+/// A handler written as a named type instead of an `ActorFn` closure. Each delivery calls
+/// `handle` once, and the handler then yields the returned commands in order.
+///
+/// `handle` borrows the actor only while it runs, so the commands it returns may cause
+/// further deliveries to the same actor.
+pub trait Actor {
+    fn handle(&mut self, msg: &Envelope) -> Vec<Command>;
+}
+
+/// The coroutine of one delivery to an `Actor`.
+struct ActorDelivery<A> {
+    actor: Rc<RefCell<A>>,
+    commands: Option<std::vec::IntoIter<Command>>,
+}
+
+impl<A: Actor> Coroutine<Envelope> for ActorDelivery<A> {
+    type Yield = Command;
+    type Return = HandlerResult;
+
+    fn resume(mut self: Pin<&mut Self>, msg: Envelope) -> CoroutineState<Command, HandlerResult> {
+        let actor = self.actor.clone();
+        let commands = self
+            .commands
+            .get_or_insert_with(|| actor.borrow_mut().handle(&msg).into_iter());
+        match commands.next() {
+            Some(cmd) => CoroutineState::Yielded(cmd),
+            None => CoroutineState::Complete(Ok(None)),
+        }
+    }
+}
+
+/// Chains handlers so that each stage's reply is sent on to the next stage's topic.
+///
+/// Every stage is registered as the endpoint of its topic, with the topic as its handler
+/// ID; a message sent to the first stage's topic enters the pipeline. A stage that
+/// completes with `Ok(Some(output))` sends `output` to the next stage, then completes
+/// with it as its own reply. A stage that replies with nothing or fails ends the flow.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<(Topic, ActorFn)>,
+    output: Option<Topic>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            output: None,
+        }
+    }
+
+    /// Appends a stage handled by `actor_fn` on `topic`.
+    pub fn stage(mut self, topic: impl Into<Topic>, actor_fn: ActorFn) -> Self {
+        self.stages.push((topic.into(), actor_fn));
+        self
+    }
+
+    /// Sends the last stage's reply on to `topic`, which the pipeline doesn't register.
+    pub fn output(mut self, topic: impl Into<Topic>) -> Self {
+        self.output = Some(topic.into());
+        self
+    }
+
+    /// Registers every stage on `msg_bus`.
+    pub fn register(self, msg_bus: &mut MessageBus) {
+        let nexts: Vec<Option<Topic>> = self
+            .stages
+            .iter()
+            .skip(1)
+            .map(|(topic, _)| Some(topic.clone()))
+            .chain([self.output])
+            .collect();
+        for ((topic, actor_fn), next) in self.stages.into_iter().zip(nexts) {
+            msg_bus.register(Subscription {
+                handler_id: topic.to_string(),
+                topic,
+                actor_fn: Box::new(move || {
+                    Box::pin(PipelineStage {
+                        handler: actor_fn(),
+                        next: next.clone(),
+                        reply: None,
+                    })
+                }),
+                priority: 0,
+                group: None,
+            });
+        }
+    }
+}
+
+/// The coroutine of one delivery to a `Pipeline` stage.
+struct PipelineStage {
+    handler: ActorCoroutine,
+    next: Option<Topic>,
+    /// The stage's reply, once it has been sent to the next stage.
+    reply: Option<Rc<dyn Any>>,
+}
+
+impl Coroutine<Envelope> for PipelineStage {
+    type Yield = Command;
+    type Return = HandlerResult;
+
+    fn resume(mut self: Pin<&mut Self>, msg: Envelope) -> CoroutineState<Command, HandlerResult> {
+        if let Some(reply) = self.reply.take() {
+            return CoroutineState::Complete(Ok(Some(reply)));
+        }
+        match self.handler.as_mut().resume(msg) {
+            CoroutineState::Complete(Ok(Some(output))) => match self.next.clone() {
+                Some(topic) => {
+                    self.reply = Some(output.clone());
+                    CoroutineState::Yielded(Command::Send { topic, msg: output })
+                }
+                None => CoroutineState::Complete(Ok(Some(output))),
+            },
+            state => state,
+        }
+    }
+}
+
+/// Returns whether a publish `pattern` reaches a subscription on `topic`. This is the
+/// default matcher of a `MessageBus`.
+///
+/// Topics are compared segment by segment, splitting on `.`. On either side, `*` matches
+/// exactly one segment and `#` matches any number of segments, including none, wherever
+/// it appears, so `sensors.temp` reaches `sensors.*`, `sensors.#` and `#.temp`, while
+/// `a.#.c` reaches `a.c` and `a.b.c` but not `a.b.d`. Without wildcards this is an exact
+/// comparison. An empty pattern is a broadcast: it reaches every subscription.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+    segments_match(pattern.split('.'), topic.split('.'))
+}
+
+/// Matches the segments left of a pattern against those left of a topic for
+/// `topic_matches`, one pair at a time. Only a `#` backtracks: it first ends where it
+/// is, then takes one more segment facing it.
+fn segments_match(mut pattern: Split<'_, char>, mut topic: Split<'_, char>) -> bool {
+    loop {
+        match (pattern.clone().next(), topic.clone().next()) {
+            (Some("#"), _) => {
+                let mut rest = pattern.clone();
+                rest.next();
+                if segments_match(rest, topic.clone()) {
+                    return true;
+                }
+                if topic.next().is_none() {
+                    return false;
+                }
+            }
+            (_, Some("#")) => {
+                let mut rest = topic.clone();
+                rest.next();
+                if segments_match(pattern.clone(), rest) {
+                    return true;
+                }
+                if pattern.next().is_none() {
+                    return false;
+                }
+            }
+            (Some(p), Some(t)) if p == t || p == "*" || t == "*" => {
+                pattern.next();
+                topic.next();
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// How specific a topic with wildcards is as a pattern, lower being more specific: its
+/// number of wildcard segments, then the negated number of literal segments before the
+/// first wildcard, then its number of `#` segments. `None` if it has no wildcards.
+fn wildcard_specificity(pattern: &str) -> Option<(usize, Reverse<usize>, usize)> {
+    let is_wildcard = |segment: &&str| *segment == "*" || *segment == "#";
+    let wildcards = pattern.split('.').filter(is_wildcard).count();
+    let prefix = pattern.split('.').take_while(|s| !is_wildcard(s)).count();
+    let hashes = pattern.split('.').filter(|s| *s == "#").count();
+    (wildcards > 0).then_some((wildcards, Reverse(prefix), hashes))
+}
+
+/// A `(pattern, topic)` predicate deciding which subscriptions a publish reaches.
+pub type Matcher = Box<dyn Fn(&str, &str) -> bool>;
+
+/// Rebuilds a payload from the bytes it was sent across the FFI boundary as.
+pub type FromBytes = fn(&[u8]) -> Rc<dyn Any>;
+
+/// What a publish to a pattern would cost, from `MessageBus::publish_cost`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublishCost {
+    /// The number of subscribers that would be delivered to.
+    pub recipients: usize,
+    /// The highest and lowest priority among the recipients, both zero if there are none.
+    pub max_priority: u8,
+    pub min_priority: u8,
+}
+
+/// The order a publish delivers to the subscriptions it matches, set per bus with
+/// `MessageBus::set_publish_order`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PublishOrder {
+    /// Highest priority first, ties broken by handler ID.
+    #[default]
+    Priority,
+    /// The order the subscriptions were subscribed in. Subscribing again under the same
+    /// topic and handler ID moves a subscription to the end; `update_priority` doesn't.
+    Registration,
+    /// By handler ID, ignoring priority.
+    HandlerIdAsc,
+    /// Highest priority first like `Priority`, but subscribers of equal priority run
+    /// cooperatively: each is resumed in turn, running whatever it spawns to completion
+    /// before the next one's turn, until all of them have completed. Handlers of equal
+    /// priority are therefore not strictly nested within each other, so their entries
+    /// and exits interleave, and their pending tasks are hidden from `TaskRunner::pending`
+    /// until the publish moves on to the next priority.
+    PriorityInterleaved,
+}
+
+/// How a topic is routed on a `MessageBus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteKind {
+    /// Nothing is routed on the topic.
+    None,
+    /// Only a point-to-point endpoint is registered.
+    Endpoint,
+    /// Only pub/sub subscriptions match the topic.
+    Subscription,
+    /// Both an endpoint and matching subscriptions exist.
+    Both,
+}
+
+/// The routing-relevant parts of a `Subscription`, without its handler.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteInfo {
+    pub topic: String,
+    pub handler_id: String,
+    pub priority: u8,
+}
+
+impl From<&Subscription> for RouteInfo {
+    fn from(sub: &Subscription) -> Self {
+        Self {
+            topic: sub.topic.to_string(),
+            handler_id: sub.handler_id.clone(),
+            priority: sub.priority,
+        }
+    }
+}
+
+/// The routing structure of a `MessageBus`, sorted by topic then handler ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoutingSnapshot {
+    pub endpoints: Vec<RouteInfo>,
+    pub subscriptions: Vec<RouteInfo>,
+}
+
+impl RoutingSnapshot {
+    /// What changed from `old` to `new`, matching routes by topic and handler ID.
+    pub fn diff(old: &RoutingSnapshot, new: &RoutingSnapshot) -> RoutingDiff {
+        RoutingDiff {
+            endpoints: RouteDiff::new(&old.endpoints, &new.endpoints),
+            subscriptions: RouteDiff::new(&old.subscriptions, &new.subscriptions),
+        }
+    }
+}
+
+/// The changes between two `RoutingSnapshot`s, from `RoutingSnapshot::diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoutingDiff {
+    pub endpoints: RouteDiff,
+    pub subscriptions: RouteDiff,
+}
+
+impl RoutingDiff {
+    /// Whether the snapshots route identically.
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty() && self.subscriptions.is_empty()
+    }
+}
+
+/// The changes to one kind of route, each list in snapshot order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteDiff {
+    pub added: Vec<RouteInfo>,
+    pub removed: Vec<RouteInfo>,
+    /// Each route whose priority changed, before and after.
+    pub priority_changed: Vec<(RouteInfo, RouteInfo)>,
+}
+
+impl RouteDiff {
+    fn new(old: &[RouteInfo], new: &[RouteInfo]) -> Self {
+        let key = |route: &RouteInfo| (route.topic.clone(), route.handler_id.clone());
+        let old_routes: HashMap<_, _> = old.iter().map(|route| (key(route), route)).collect();
+        let new_routes: HashMap<_, _> = new.iter().map(|route| (key(route), route)).collect();
+        let mut diff = Self::default();
+        for route in new {
+            match old_routes.get(&key(route)) {
+                None => diff.added.push(route.clone()),
+                Some(old) if old.priority != route.priority => {
+                    diff.priority_changed.push(((*old).clone(), route.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .iter()
+            .filter(|route| !new_routes.contains_key(&key(route)))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.priority_changed.is_empty()
+    }
+}
+
+/// A view into a single endpoint slot of a `MessageBus`, from `MessageBus::endpoint_entry`.
+pub enum EndpointEntry<'a> {
+    Occupied(OccupiedEndpoint<'a>),
+    Vacant(VacantEndpoint<'a>),
+}
+
+/// An endpoint slot with at least one subscription registered.
+pub struct OccupiedEndpoint<'a>(hash_map::OccupiedEntry<'a, Topic, Vec<Subscription>>);
+
+impl<'a> OccupiedEndpoint<'a> {
+    /// The highest-priority endpoint.
+    pub fn get(&self) -> &Subscription {
+        &self.0.get()[0]
+    }
+
+    pub fn get_mut(&mut self) -> &mut Subscription {
+        &mut self.0.get_mut()[0]
+    }
+
+    /// Every endpoint on the topic, highest priority first.
+    pub fn all(&self) -> &[Subscription] {
+        self.0.get()
+    }
+
+    /// Deregisters the topic, returning its endpoints.
+    pub fn remove(self) -> Vec<Subscription> {
+        self.0.remove()
+    }
+}
+
+/// An endpoint slot with nothing registered.
+pub struct VacantEndpoint<'a>(hash_map::VacantEntry<'a, Topic, Vec<Subscription>>);
+
+impl<'a> VacantEndpoint<'a> {
+    pub fn topic(&self) -> &str {
+        self.0.key()
+    }
+
+    /// Registers `subscription` in this slot. Its topic must match the entry's topic.
+    pub fn register(self, subscription: Subscription) -> &'a mut Subscription {
+        debug_assert_eq!(subscription.topic, *self.0.key());
+        &mut self.0.insert(vec![subscription])[0]
+    }
+}
+
+/// The topic and handler ID identifying a pub/sub subscription on a `MessageBus`.
+type SubscriptionKey = (Topic, String);
+
+#[derive(Default)]
+pub struct MessageBus {
+    /// The endpoints of each topic, highest priority first then in registration order.
+    /// Topics without endpoints have no entry.
+    endpoints: HashMap<Topic, Vec<Subscription>>,
+    /// Each subscription, and how many subscriptions were made before it. Ordered by topic
+    /// then handler ID, so that it iterates the same way on every run; lookups cost a few
+    /// string comparisons more than hashing would, which routing by topic pattern, a scan
+    /// of every subscription, doesn't notice.
+    subscriptions: BTreeMap<SubscriptionKey, (Subscription, u64)>,
+    /// The number of subscriptions made, including replaced and removed ones.
+    subscribed: u64,
+    publish_order: PublishOrder,
+    /// Overrides `topic_matches` when set.
+    matcher: Option<Matcher>,
+    /// The `Rc<RefCell<S>>` state of each stateful handler, by handler ID.
+    states: HashMap<String, Rc<dyn Any>>,
+    /// The supervision of each handler set with `set_supervision`, by handler ID.
+    supervision: HashMap<String, Supervision>,
+    /// The handler IDs marked with `mark_pure`.
+    pure: HashSet<String>,
+    /// The payload type registered under each type tag, and how to rebuild it.
+    types: HashMap<u32, (TypeId, FromBytes)>,
+    /// Groups whose subscriptions publishes skip.
+    disabled_groups: HashSet<String>,
+    /// The topic and handler ID of each `subscribe_once` subscription.
+    once: HashSet<(Topic, String)>,
+    /// The payload type each `register_typed` endpoint accepts, by topic and handler ID.
+    endpoint_types: HashMap<(Topic, String), TypeId>,
+    /// The number of handler IDs `subscribe_anon` has generated.
+    anon_ids: u64,
+    /// Receives sends to topics without endpoints; see `set_default_endpoint`.
+    default_endpoint: Option<Subscription>,
+    /// Receive every publish; see `subscribe_all_publishes`.
+    publish_observers: Vec<Subscription>,
+}
+
+impl Display for MessageBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Messagebus")?;
+        // Sorted, as the maps iterate in a different order on every run.
+        writeln!(f, "endpoints:")?;
+        let mut endpoints: Vec<_> = self.endpoints.values().flatten().collect();
+        endpoints.sort_by(|a, b| (&a.topic, &a.handler_id).cmp(&(&b.topic, &b.handler_id)));
+        for sub in endpoints {
+            writeln!(f, "{}: {}", sub.topic, sub)?;
+        }
+        writeln!(f, "subscriptions:")?;
+        for (sub, _) in self.subscriptions.values() {
+            writeln!(f, "{}: {}", sub, sub.topic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects subscriptions as pub/sub subscriptions, each subscribed in turn like
+/// `MessageBus::subscribe`. Use `MessageBus::from_endpoints` to collect endpoints.
+impl FromIterator<Subscription> for MessageBus {
+    fn from_iter<I: IntoIterator<Item = Subscription>>(subs: I) -> Self {
+        let mut bus = Self::new();
+        bus.subscribe_all(subs);
+        bus
+    }
+}
+
+impl MessageBus {
+    pub fn new() -> Self {
+        Self {
+            endpoints: HashMap::new(),
+            subscriptions: BTreeMap::new(),
+            subscribed: 0,
+            publish_order: PublishOrder::Priority,
+            matcher: None,
+            states: HashMap::new(),
+            supervision: HashMap::new(),
+            pure: HashSet::new(),
+            types: HashMap::new(),
+            disabled_groups: HashSet::new(),
+            once: HashSet::new(),
+            endpoint_types: HashMap::new(),
+            anon_ids: 0,
+            default_endpoint: None,
+            publish_observers: Vec::new(),
+        }
+    }
+
+    /// Registers a coroutine handler for message type M on the given topic. A topic can
+    /// have several endpoints; registering a handler ID that is already an endpoint on
+    /// the topic replaces it.
+    pub fn register(&mut self, subscription: Subscription) {
+        self.endpoint_types
+            .remove(&(subscription.topic.clone(), subscription.handler_id.clone()));
+        let subs = self.endpoints.entry(subscription.topic.clone()).or_default();
+        subs.retain(|sub| sub.handler_id != subscription.handler_id);
+        let idx = subs.partition_point(|sub| sub.priority >= subscription.priority);
+        subs.insert(idx, subscription);
+    }
+
+    /// Registers an endpoint, like `register`, that accepts only payloads of type `M`.
+    /// A send of any other payload to the topic, by any command or `TaskRunner` method,
+    /// is dead-lettered with `DeadLetterReason::TypeMismatch` instead of being delivered
+    /// to it. Single-endpoint sends go to the highest-priority endpoint that accepts the
+    /// payload.
+    pub fn register_typed<M: Any>(&mut self, subscription: Subscription) {
+        let key = (subscription.topic.clone(), subscription.handler_id.clone());
+        self.register(subscription);
+        self.endpoint_types.insert(key, TypeId::of::<M>());
+    }
+
+    /// The payload type the endpoint `handler_id` on `topic` was registered for with
+    /// `register_typed`, if any.
+    pub fn endpoint_type(&self, topic: &str, handler_id: &str) -> Option<TypeId> {
+        self.endpoint_types
+            .get(&(topic.into(), handler_id.to_string()))
+            .copied()
+    }
+
+    /// Whether the endpoint `sub` accepts `msg`, as it wasn't registered for another type.
+    fn accepts(&self, sub: &Subscription, msg: &dyn Any) -> bool {
+        match self.endpoint_type(&sub.topic, &sub.handler_id) {
+            Some(type_id) => type_id == msg.type_id(),
+            None => true,
+        }
+    }
+
+    /// The endpoints `resolve_endpoints` gives for `topic` that accept `msg`, highest
+    /// priority first, and the number left out as registered with `register_typed` for
+    /// another payload type. `None` if `topic` has no endpoints. Every send to endpoints
+    /// resolves them through this or `endpoint_for`.
+    pub fn endpoints_for(&self, topic: &str, msg: &dyn Any) -> Option<(Vec<&Subscription>, usize)> {
+        let subs = self.resolve_endpoints(topic)?;
+        let accepted: Vec<_> = subs.iter().filter(|sub| self.accepts(sub, msg)).collect();
+        let refused = subs.len() - accepted.len();
+        Some((accepted, refused))
+    }
+
+    /// The highest-priority endpoint on `topic` that accepts `msg`, for sends to a single
+    /// endpoint. Fails with the reason to dead-letter `msg` for: `NoEndpoint`, or
+    /// `TypeMismatch` if every endpoint was registered for another payload type.
+    pub fn endpoint_for(
+        &self,
+        topic: &str,
+        msg: &dyn Any,
+    ) -> Result<&Subscription, DeadLetterReason> {
+        match self.endpoints_for(topic, msg) {
+            None => Err(DeadLetterReason::NoEndpoint),
+            Some((subs, _)) => subs.first().copied().ok_or(DeadLetterReason::TypeMismatch),
+        }
+    }
+
+    /// Deregisters every endpoint on `topic`.
+    pub fn deregister(&mut self, topic: &str) {
+        self.endpoints.remove(topic);
+        self.endpoint_types
+            .retain(|(typed, _), _| typed.as_str() != topic);
+    }
+
+    /// Answers `query` from the bus's current routing, as a `Command::Query` is answered.
+    pub fn answer(&self, query: &BusQuery) -> BusAnswer {
+        match query {
+            BusQuery::SubscriberCount(pattern) => {
+                BusAnswer::SubscriberCount(self.subscriber_count(pattern))
+            }
+            BusQuery::TopicExists(topic) => {
+                BusAnswer::TopicExists(self.route_kind(topic) != RouteKind::None)
+            }
+        }
+    }
+
+    /// The highest-priority endpoint a send to `topic` is delivered to; see
+    /// `resolve_endpoints`.
+    pub fn endpoint(&self, topic: &str) -> Option<&Subscription> {
+        self.resolve_endpoints(topic).and_then(|subs| subs.first())
+    }
+
+    /// The endpoints a send to `topic` is delivered to, highest priority first. These are
+    /// the endpoints registered on `topic` itself if there are any. Otherwise they are
+    /// those of the most specific endpoint topic with wildcards that matches `topic`: the
+    /// one with the fewest wildcard segments, then the most literal segments before its
+    /// first wildcard, then the fewest `#`s. Finding a wildcard endpoint scans every
+    /// endpoint topic.
+    pub fn resolve_endpoints(&self, topic: &str) -> Option<&[Subscription]> {
+        if let Some(subs) = self.endpoints.get(topic) {
+            return Some(subs);
+        }
+        self.endpoints
+            .iter()
+            .filter_map(|(pattern, subs)| {
+                let specificity = wildcard_specificity(pattern)?;
+                self.matches(pattern, topic)
+                    .then_some((specificity, pattern, subs))
+            })
+            .min_by(|(a, a_pattern, _), (b, b_pattern, _)| a.cmp(b).then(a_pattern.cmp(b_pattern)))
+            .map(|(_, _, subs)| subs.as_slice())
+    }
+
+    /// Sets the endpoint that receives a `Command::Send` to a topic without endpoints,
+    /// replacing any previous one. It is used after the runner's `on_missing_endpoint`
+    /// callback and instead of its `send_fallback`. The handler finds the topic the message
+    /// was sent to in `Envelope::matched_topic`.
+    pub fn set_default_endpoint(&mut self, subscription: Subscription) {
+        self.default_endpoint = Some(subscription);
+    }
+
+    /// Delivers every publish to `actor_fn` under `handler_id`, whatever its pattern, such
+    /// as for audit logging. Observers are delivered to after the subscribers the publish
+    /// matched, in the order they were added, and don't count towards a publish's limit
+    /// or report. Adding a handler ID that is already an observer replaces it.
+    pub fn subscribe_all_publishes(&mut self, handler_id: impl Into<String>, actor_fn: ActorFn) {
+        let handler_id = handler_id.into();
+        self.publish_observers
+            .retain(|sub| sub.handler_id != handler_id);
+        self.publish_observers.push(Subscription {
+            topic: "#".into(),
+            handler_id,
+            actor_fn,
+            priority: 0,
+            group: None,
+        });
+    }
+
+    /// Gets the endpoint slot for `topic` for in-place inspection or registration.
+    pub fn endpoint_entry(&mut self, topic: &str) -> EndpointEntry<'_> {
+        match self.endpoints.entry(Topic::from(topic)) {
+            hash_map::Entry::Occupied(entry) => EndpointEntry::Occupied(OccupiedEndpoint(entry)),
+            hash_map::Entry::Vacant(entry) => EndpointEntry::Vacant(VacantEndpoint(entry)),
+        }
+    }
+
+    /// Classifies how a message addressed to `topic` would be routed.
+    pub fn route_kind(&self, topic: &str) -> RouteKind {
+        let endpoint = self.resolve_endpoints(topic).is_some();
+        let subscription = self
+            .subscriptions
+            .values()
+            .any(|(sub, _)| self.matches(topic, &sub.topic));
+        match (endpoint, subscription) {
+            (false, false) => RouteKind::None,
+            (true, false) => RouteKind::Endpoint,
+            (false, true) => RouteKind::Subscription,
+            (true, true) => RouteKind::Both,
+        }
+    }
+
+    /// Subscribes a handler to publishes matching `subscription.topic`. Subscribing a
+    /// handler ID that is already subscribed to the topic replaces its subscription.
+    pub fn subscribe(&mut self, subscription: Subscription) {
+        let key = (subscription.topic.clone(), subscription.handler_id.clone());
+        self.once.remove(&key);
+        self.subscriptions
+            .insert(key, (subscription, self.subscribed));
+        self.subscribed += 1;
+    }
+
+    /// Subscribes like `subscribe`, but removes the subscription once a delivery to it
+    /// completes with `Ok`. A publish delivers to a handler at most once, so the handler
+    /// runs once even if the subscription matches it twice; a delivery that fails leaves
+    /// the subscription in place for the next publish.
+    pub fn subscribe_once(&mut self, subscription: Subscription) {
+        let key = (subscription.topic.clone(), subscription.handler_id.clone());
+        self.subscribe(subscription);
+        self.once.insert(key);
+    }
+
+    /// Whether the subscription of `handler_id` on `topic` was made by `subscribe_once`.
+    pub fn is_once(&self, topic: &str, handler_id: &str) -> bool {
+        self.once.contains(&(topic.into(), handler_id.to_string()))
+    }
+
+    /// Removes the `subscribe_once` subscription of `handler_id` on `topic`, if it still is
+    /// one.
+    fn remove_once(&mut self, topic: &Topic, handler_id: &str) {
+        if self.once.remove(&(topic.clone(), handler_id.to_string())) {
+            self.remove_subscription(topic, handler_id);
+        }
+    }
+
+    /// Subscribes `actor_fn` to `topic` under a generated handler ID, which is returned
+    /// for unsubscribing later. The ID is `topic` followed by `#` and a counter, skipping
+    /// any already subscribed to `topic`, so it never replaces an existing subscription.
+    pub fn subscribe_anon(
+        &mut self,
+        topic: impl Into<Topic>,
+        priority: u8,
+        actor_fn: ActorFn,
+    ) -> String {
+        let topic = topic.into();
+        let handler_id = loop {
+            self.anon_ids += 1;
+            let handler_id = format!("{}#{}", topic, self.anon_ids);
+            if !self.is_subscribed(&topic, &handler_id) {
+                break handler_id;
+            }
+        };
+        self.subscribe(Subscription {
+            topic,
+            actor_fn,
+            handler_id: handler_id.clone(),
+            priority,
+            group: None,
+        });
+        handler_id
+    }
+
+    /// Registers an endpoint whose handler keeps state, starting from `S::default()` if
+    /// the handler ID has none yet.
+    ///
+    /// # Panics
+    ///
+    /// If the handler ID already has state of a type other than `S`.
+    pub fn register_stateful<S: Default + 'static>(
+        &mut self,
+        subscription: StatefulSubscription<S>,
+    ) {
+        let state = self.state_or_default(&subscription.handler_id);
+        self.register(subscription.into_subscription(state));
+    }
+
+    /// Subscribes a handler that keeps state, like `register_stateful`.
+    ///
+    /// # Panics
+    ///
+    /// If the handler ID already has state of a type other than `S`.
+    pub fn subscribe_stateful<S: Default + 'static>(
+        &mut self,
+        subscription: StatefulSubscription<S>,
+    ) {
+        let state = self.state_or_default(&subscription.handler_id);
+        self.subscribe(subscription.into_subscription(state));
+    }
+
+    /// Registers `actor` as an endpoint for `topic`. The bus keeps the actor as the state
+    /// of `handler_id`, so `state::<A>(handler_id)` returns it.
+    pub fn register_actor<A: Actor + 'static>(
+        &mut self,
+        topic: impl Into<Topic>,
+        handler_id: impl Into<String>,
+        actor: A,
+    ) {
+        let handler_id = handler_id.into();
+        let actor = Rc::new(RefCell::new(actor));
+        self.states.insert(handler_id.clone(), actor.clone());
+        self.register(Subscription {
+            topic: topic.into(),
+            actor_fn: Box::new(move || {
+                Box::pin(ActorDelivery {
+                    actor: actor.clone(),
+                    commands: None,
+                })
+            }),
+            handler_id,
+            priority: 0,
+            group: None,
+        });
+    }
+
+    /// Sets what the runner does when the handler `handler_id` fails, under every topic it
+    /// is registered or subscribed to. Handlers default to `Supervision::Ignore`.
+    pub fn set_supervision(&mut self, handler_id: impl Into<String>, supervision: Supervision) {
+        self.supervision.insert(handler_id.into(), supervision);
+    }
+
+    /// What the runner does when the handler `handler_id` fails.
+    pub fn supervision(&self, handler_id: &str) -> Supervision {
+        self.supervision
+            .get(handler_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Marks the handler `handler_id` as pure: instantiating and resuming it has no side
+    /// effects, and it yields the same commands for the same message whatever else it is
+    /// resumed with. `TaskRunner::try_peek_command` replays pure handlers only.
+    pub fn mark_pure(&mut self, handler_id: impl Into<String>) {
+        self.pure.insert(handler_id.into());
+    }
+
+    /// Whether `handler_id` was marked with `mark_pure`.
+    pub fn is_pure(&self, handler_id: &str) -> bool {
+        self.pure.contains(handler_id)
+    }
+
+    /// The endpoint or subscription through which a send or publish to `topic` reaches
+    /// `handler_id`, for instantiating the handler again.
+    fn handler(&self, topic: &str, handler_id: &str) -> Option<&Subscription> {
+        self.resolve_endpoints(topic)
+            .into_iter()
+            .flatten()
+            .chain(&self.default_endpoint)
+            .chain(self.matching_subscribers(topic))
+            .find(|sub| sub.handler_id == handler_id)
+    }
+
+    /// The state of a stateful handler, if it has state of type `S`.
+    pub fn state<S: 'static>(&self, handler_id: &str) -> Option<Rc<RefCell<S>>> {
+        self.states.get(handler_id)?.clone().downcast().ok()
+    }
+
+    fn state_or_default<S: Default + 'static>(&mut self, handler_id: &str) -> Rc<RefCell<S>> {
+        let state = self
+            .states
+            .entry(handler_id.to_string())
+            .or_insert_with(|| Rc::new(RefCell::new(S::default())));
+        state
+            .clone()
+            .downcast()
+            .unwrap_or_else(|_| panic!("handler {handler_id} already has state of another type"))
+    }
+
+    /// Registers `T` as the payload type of messages sent with `tag`, rebuilt from their
+    /// bytes with `from_bytes`. Re-registering a tag for the same type replaces its
+    /// `from_bytes`.
+    ///
+    /// # Panics
+    ///
+    /// If `tag` is already registered for a type other than `T`.
+    pub fn register_type<T: Any>(&mut self, tag: u32, from_bytes: FromBytes) {
+        let type_id = TypeId::of::<T>();
+        if let Some((registered, _)) = self.types.get(&tag) {
+            assert!(
+                *registered == type_id,
+                "type tag {tag} is already registered for another type"
+            );
+        }
+        self.types.insert(tag, (type_id, from_bytes));
+    }
+
+    /// Rebuilds a payload sent with `tag`. Returns `None` if the tag is unregistered or
+    /// its `from_bytes` built a value of another type than the one registered.
+    pub fn decode(&self, tag: u32, bytes: &[u8]) -> Option<Rc<dyn Any>> {
+        let (type_id, from_bytes) = self.types.get(&tag)?;
+        let msg = from_bytes(bytes);
+        ((*msg).type_id() == *type_id).then_some(msg)
+    }
+
+    /// Sets the order publishes deliver to their subscribers in. Publishes already
+    /// delivering keep the order they matched in.
+    pub fn set_publish_order(&mut self, order: PublishOrder) {
+        self.publish_order = order;
+    }
+
+    /// Replaces the publish matching rule for this bus.
+    pub fn set_matcher(&mut self, f: Matcher) {
+        self.matcher = Some(f);
+    }
+
+    /// Returns whether a publish to `pattern` reaches a subscription on `topic`.
+    pub fn matches(&self, pattern: &str, topic: &str) -> bool {
+        match &self.matcher {
+            Some(matcher) => matcher(pattern, topic),
+            None => topic_matches(pattern, topic),
+        }
+    }
+
+    /// The number of handlers a publish to `pattern` would reach: those
+    /// `matching_subscribers` returns, so a handler matched under several subscriptions
+    /// counts once and disabled groups aren't counted.
+    pub fn subscriber_count(&self, pattern: &str) -> usize {
+        self.matching_subscribers(pattern).len()
+    }
+
+    /// Estimates a publish to `pattern` without delivering anything. The recipients are
+    /// those `matching_subscribers` returns.
+    pub fn publish_cost(&self, pattern: &str) -> PublishCost {
+        let subs = self.matching_subscribers(pattern);
+        let priorities = subs.iter().map(|sub| sub.priority);
+        PublishCost {
+            recipients: subs.len(),
+            max_priority: priorities.clone().max().unwrap_or(0),
+            min_priority: priorities.min().unwrap_or(0),
+        }
+    }
+
+    /// Enables or disables every subscription in `group`. Publishes skip the subscriptions
+    /// of a disabled group, including ones subscribed after it was disabled; endpoints are
+    /// unaffected. Groups are enabled until disabled.
+    pub fn set_group_enabled(&mut self, group: &str, enabled: bool) {
+        if enabled {
+            self.disabled_groups.remove(group);
+        } else {
+            self.disabled_groups.insert(group.to_string());
+        }
+    }
+
+    /// Whether `sub` is not in a disabled group.
+    fn is_enabled(&self, sub: &Subscription) -> bool {
+        match &sub.group {
+            Some(group) => !self.disabled_groups.contains(group),
+            None => true,
+        }
+    }
+
+    /// Returns the subscriptions matching `pattern` in delivery order, which is the bus's
+    /// `PublishOrder`; ties are broken by handler ID, then topic. Subscriptions in disabled
+    /// groups are left out, as are later matches of a handler ID already matched under
+    /// another topic, so that no handler is delivered to twice.
+    pub fn matching_subscribers(&self, pattern: &str) -> Vec<&Subscription> {
+        self.match_subscribers(pattern).0
+    }
+
+    /// Splits the subscriptions matching `pattern` into those `matching_subscribers`
+    /// returns and the repeated matches of a handler ID it leaves out.
+    fn match_subscribers(&self, pattern: &str) -> (Vec<&Subscription>, Vec<&Subscription>) {
+        let mut subs: Vec<(&Subscription, u64)> = self
+            .subscriptions
+            .values()
+            .filter(|(sub, _)| self.is_enabled(sub) && self.matches(pattern, &sub.topic))
+            .map(|(sub, seq)| (sub, *seq))
+            .collect();
+        subs.sort_by(|(a, a_seq), (b, b_seq)| {
+            let order = match self.publish_order {
+                PublishOrder::Priority | PublishOrder::PriorityInterleaved => {
+                    b.priority.cmp(&a.priority)
+                }
+                PublishOrder::Registration => a_seq.cmp(b_seq),
+                PublishOrder::HandlerIdAsc => std::cmp::Ordering::Equal,
+            };
+            order
+                .then_with(|| a.handler_id.cmp(&b.handler_id))
+                .then_with(|| a.topic.cmp(&b.topic))
+        });
+        let mut seen = HashSet::new();
+        subs.into_iter()
+            .map(|(sub, _)| sub)
+            .partition(|sub| seen.insert(sub.handler_id.as_str()))
+    }
+
+    /// Registers every endpoint yielded by the iterator.
+    pub fn register_all<I: IntoIterator<Item = Subscription>>(&mut self, subs: I) {
+        for subscription in subs {
+            self.register(subscription);
+        }
+    }
+
+    /// Creates a bus with every endpoint yielded by the iterator registered.
+    pub fn from_endpoints<I: IntoIterator<Item = Subscription>>(subs: I) -> Self {
+        let mut bus = Self::new();
+        bus.register_all(subs);
+        bus
+    }
+
+    /// Subscribes every subscription yielded by the iterator.
+    pub fn subscribe_all<I: IntoIterator<Item = Subscription>>(&mut self, subs: I) {
+        for subscription in subs {
+            self.subscribe(subscription);
+        }
+    }
+
+    /// Instantiates the handler of every endpoint, subscription, default endpoint and
+    /// publish observer once and drops it unresumed, as a check before any message is
+    /// delivered. Returns the sorted handler IDs whose `actor_fn` panicked. The panic hook
+    /// still reports each panic.
+    pub fn validate_handlers(&self) -> Result<(), Vec<String>> {
+        let mut failed: Vec<String> = self
+            .endpoints
+            .values()
+            .flatten()
+            .chain(self.subscriptions.values().map(|(sub, _)| sub))
+            .chain(&self.default_endpoint)
+            .chain(&self.publish_observers)
+            .filter(|sub| panic::catch_unwind(AssertUnwindSafe(|| (sub.actor_fn)())).is_err())
+            .map(|sub| sub.handler_id.clone())
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        failed.sort();
+        failed.dedup();
+        Err(failed)
+    }
+
+    /// Captures the topics, handler IDs and priorities of every route on the bus.
+    pub fn clone_routing(&self) -> RoutingSnapshot {
+        let mut endpoints: Vec<RouteInfo> =
+            self.endpoints.values().flatten().map(RouteInfo::from).collect();
+        let subscriptions = self
+            .subscriptions
+            .values()
+            .map(|(sub, _)| RouteInfo::from(sub))
+            .collect();
+        endpoints.sort();
+        RoutingSnapshot {
+            endpoints,
+            subscriptions,
+        }
+    }
+
+    /// Rebuilds the routes in `snapshot` on this bus, asking `factory` for each handler.
+    pub fn apply_routing(
+        &mut self,
+        snapshot: &RoutingSnapshot,
+        mut factory: impl FnMut(&RouteInfo) -> ActorFn,
+    ) {
+        for route in &snapshot.endpoints {
+            self.register(Subscription {
+                actor_fn: factory(route),
+                handler_id: route.handler_id.clone(),
+                topic: route.topic.as_str().into(),
+                priority: route.priority,
+                group: None,
+            });
+        }
+        for route in &snapshot.subscriptions {
+            self.subscribe(Subscription {
+                actor_fn: factory(route),
+                handler_id: route.handler_id.clone(),
+                topic: route.topic.as_str().into(),
+                priority: route.priority,
+                group: None,
+            });
+        }
+    }
+
+    /// Removes every endpoint and subscription of `handler_id`, whatever its topic,
+    /// including the default endpoint and publish observer, returning how many were
+    /// removed. Everything else the bus keeps for the handler ID is forgotten too (its
+    /// state, supervision, purity, `subscribe_once` markers and endpoint types), so a
+    /// handler added again under it starts clean.
+    pub fn remove_handler(&mut self, handler_id: &str) -> usize {
+        let mut removed = 0;
+        self.endpoints.retain(|_, subs| {
+            let before = subs.len();
+            subs.retain(|sub| sub.handler_id != handler_id);
+            removed += before - subs.len();
+            !subs.is_empty()
+        });
+        self.endpoint_types
+            .retain(|(_, typed), _| typed != handler_id);
+        let before = self.subscriptions.len();
+        self.subscriptions
+            .retain(|(_, subscribed), _| subscribed != handler_id);
+        removed += before - self.subscriptions.len();
+        self.once.retain(|(_, once)| once != handler_id);
+        let default = self.default_endpoint.as_ref();
+        if default.is_some_and(|sub| sub.handler_id == handler_id) {
+            self.default_endpoint = None;
+            removed += 1;
+        }
+        let before = self.publish_observers.len();
+        self.publish_observers
+            .retain(|sub| sub.handler_id != handler_id);
+        removed += before - self.publish_observers.len();
+        self.states.remove(handler_id);
+        self.supervision.remove(handler_id);
+        self.pure.remove(handler_id);
+        removed
+    }
+
+    pub fn remove_subscription(&mut self, topic: &str, handler_id: &str) {
+        let key = (topic.into(), handler_id.to_string());
+        self.subscriptions.remove(&key);
+        self.once.remove(&key);
+    }
+
+    /// Releases the spare capacity of the bus's maps, such as after many handlers were
+    /// removed.
+    pub fn shrink_to_fit(&mut self) {
+        self.endpoints.shrink_to_fit();
+        for subs in self.endpoints.values_mut() {
+            subs.shrink_to_fit();
+        }
+        self.states.shrink_to_fit();
+        self.supervision.shrink_to_fit();
+        self.pure.shrink_to_fit();
+        self.types.shrink_to_fit();
+        self.disabled_groups.shrink_to_fit();
+        self.once.shrink_to_fit();
+        self.endpoint_types.shrink_to_fit();
+        self.publish_observers.shrink_to_fit();
+    }
+
+    /// Changes the priority of the subscription of `handler_id` on `topic`, keeping its
+    /// handler. Returns whether there was such a subscription.
+    pub fn update_priority(&mut self, topic: &str, handler_id: &str, priority: u8) -> bool {
+        let key = (topic.into(), handler_id.to_string());
+        let Some((sub, _)) = self.subscriptions.get_mut(&key) else {
+            return false;
+        };
+        sub.priority = priority;
+        true
+    }
+
+    /// The subscription of `handler_id` on `topic`.
+    pub fn subscription(&self, topic: &str, handler_id: &str) -> Option<&Subscription> {
+        self.subscriptions
+            .get(&(topic.into(), handler_id.to_string()))
+            .map(|(sub, _)| sub)
+    }
+
+    /// Whether `handler_id` is subscribed to exactly `topic`.
+    pub fn is_subscribed(&self, topic: &str, handler_id: &str) -> bool {
+        self.subscriptions
+            .contains_key(&(topic.into(), handler_id.to_string()))
+    }
+
+    /// The topic and handler ID of every subscription, sorted by topic then handler ID.
+    pub fn subscription_topics(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.subscriptions
+            .keys()
+            .map(|(topic, handler_id)| (topic.as_str(), handler_id.as_str()))
+    }
+
+    /// Whether `topic` has at least one endpoint.
+    pub fn is_registered(&self, topic: &str) -> bool {
+        self.endpoints.contains_key(topic)
+    }
+}
+
+// These tests write handlers as `#[coroutine]` closures, which the stable parser rejects even
+// in code that is configured out, so they live in their own file.
+#[cfg(all(test, not(feature = "stable")))]
+mod tests;
+
+#[cfg(test)]
+mod property_tests {
+    use super::testing::*;
+    use super::*;
+    use proptest::prelude::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    // Register the static chain A -> B -> C and push a send to A as the root task
+    fn push_static_chain(runner: &mut TaskRunner, trace: &Rc<RefCell<Vec<TraceEvent>>>) {
+        for (id, topic, next) in [
+            ("C", "topic_c", None),
+            ("B", "topic_b", Some("topic_c")),
+            ("A", "topic_a", Some("topic_b")),
+        ] {
+            let actions = next
+                .map(|next| vec![ActorAction::Send(next.to_string())])
+                .unwrap_or_default();
+            runner.msg_bus.register(create_actor_handler(
+                id.to_string(),
+                topic.to_string(),
+                actions,
+                trace.clone(),
+            ));
+        }
+        let root = SendTask::for_subscription(
+            &runner.msg_bus.endpoints["topic_a"][0],
+            "topic_a",
+            Rc::new(()),
+        );
+        runner.push(Task::Send(root));
+    }
+
+    // Test that probing the A handler of the static chain on its own finds its one send
+    #[test]
+    fn test_probe_handler() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let actions = vec![ActorAction::Send("topic_b".to_string())];
+        let sub = create_actor_handler("A".into(), "topic_a".into(), actions, trace.clone());
+
+        let commands = probe_handler(&sub.actor_fn, Rc::new(()));
+
+        assert_eq!(
+            commands,
+            vec![CommandRecord {
+                handler_id: String::new(),
+                kind: "Send".to_string(),
+                topic: "topic_b".to_string(),
+            }]
+        );
+        assert!(is_well_formed(&trace.borrow()));
+    }
+
+    // Test for static chain: A -> B -> C
+    #[test]
+    fn test_static_chain() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+
+        // Define actions for each actor
+        let c_actions: Vec<ActorAction> = vec![];
+        let b_actions = vec![ActorAction::Send("topic_c".to_string())];
+        let a_actions = vec![ActorAction::Send("topic_b".to_string())];
+
+        // Register all handlers
+        runner.msg_bus.register(create_actor_handler(
+            "C".to_string(),
+            "topic_c".to_string(),
+            c_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "B".to_string(),
+            "topic_b".to_string(),
+            b_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "A".to_string(),
+            "topic_a".to_string(),
+            a_actions,
+            trace.clone(),
+        ));
+
+        // Start with A
+        runner.push(Task::Send(SendTask::new(
+            "topic_a".to_string(),
+            (runner.msg_bus.endpoints["topic_a"][0].actor_fn)(),
+            Rc::new(()),
+        )));
+
+        // Run and verify
+        runner.run();
+
+        // Expected trace: A enters, B enters, C enters, C exits, B exits, A exits
+        let expected_trace = vec![
+            TraceEvent::Enter("A".to_string()),
+            TraceEvent::Enter("B".to_string()),
+            TraceEvent::Enter("C".to_string()),
+            TraceEvent::Exit("C".to_string()),
+            TraceEvent::Exit("B".to_string()),
+            TraceEvent::Exit("A".to_string()),
+        ];
+
+        assert!(
+            is_well_formed(&trace.borrow()),
+            "Trace is not well-formed: {:?}",
+            *trace.borrow()
+        );
+
+        assert_eq!(
+            *trace.borrow(),
+            expected_trace,
+            "Trace mismatch: {:?}",
+            *trace.borrow()
+        );
+    }
+
+    // Test for tree structure: A -> (B, C), B -> (D, E)
+    #[test]
+    fn test_tree_structure() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+
+        // Define actions for each actor
+        let d_actions: Vec<ActorAction> = vec![];
+        let e_actions: Vec<ActorAction> = vec![];
+        let c_actions: Vec<ActorAction> = vec![];
+        let b_actions = vec![
+            ActorAction::Send("topic_d".to_string()),
+            ActorAction::Send("topic_e".to_string()),
+        ];
+        let a_actions = vec![
+            ActorAction::Send("topic_b".to_string()),
+            ActorAction::Send("topic_c".to_string()),
+        ];
+
+        // Register all handlers
+        runner.msg_bus.register(create_actor_handler(
+            "D".to_string(),
+            "topic_d".to_string(),
+            d_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "E".to_string(),
+            "topic_e".to_string(),
+            e_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "C".to_string(),
+            "topic_c".to_string(),
+            c_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "B".to_string(),
+            "topic_b".to_string(),
+            b_actions,
+            trace.clone(),
+        ));
+
+        runner.msg_bus.register(create_actor_handler(
+            "A".to_string(),
+            "topic_a".to_string(),
+            a_actions,
+            trace.clone(),
+        ));
+
+        // Start with A
+        runner.push(Task::Send(SendTask::new(
+            "topic_a".to_string(),
+            (runner.msg_bus.endpoints["topic_a"][0].actor_fn)(),
+            Rc::new(()),
+        )));
+
+        // Run and verify
+        runner.run();
+
+        // Expected trace: A enters, B enters, D enters, D exits, E enters, E exits, B exits, C enters, C exits, A exits
+        let expected_trace = vec![
+            TraceEvent::Enter("A".to_string()),
+            TraceEvent::Enter("B".to_string()),
+            TraceEvent::Enter("D".to_string()),
+            TraceEvent::Exit("D".to_string()),
+            TraceEvent::Enter("E".to_string()),
+            TraceEvent::Exit("E".to_string()),
+            TraceEvent::Exit("B".to_string()),
+            TraceEvent::Enter("C".to_string()),
+            TraceEvent::Exit("C".to_string()),
+            TraceEvent::Exit("A".to_string()),
+        ];
+
+        assert!(
+            is_well_formed(&trace.borrow()),
+            "Trace is not well-formed: {:?}",
+            *trace.borrow()
+        );
+
+        assert_eq!(
+            *trace.borrow(),
+            expected_trace,
+            "Trace mismatch: {:?}",
+            *trace.borrow()
+        );
+
+        // The longest path, A -> B -> D, is three handlers deep
+        assert_eq!(runner.max_depth_reached(), 3);
+    }
+
+    // Test that the trace channel receives the same events the static chain's handlers
+    // record, even when drained from another thread
+    #[test]
+    fn test_static_chain_trace_channel() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        let events = runner.trace_channel();
+        push_static_chain(&mut runner, &trace);
+        runner.run();
+        drop(runner);
+
+        let received = std::thread::spawn(move || events.iter().collect::<Vec<_>>())
+            .join()
+            .unwrap();
+        assert_eq!(received, *trace.borrow());
+    }
+
+    // Test the step-by-step reports of the static chain: A -> B -> C
+    #[test]
+    fn test_static_chain_step_debug() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        push_static_chain(&mut runner, &trace);
+
+        let report = |handler_id: &str, sent_to: Option<&str>, depth: usize| StepReport {
+            task: Some(TaskSummary {
+                kind: "Send",
+                topic: format!("topic_{}", handler_id.to_lowercase()).into(),
+                handler_id: handler_id.to_string(),
+                correlation_id: 1,
+                progress: None,
+            }),
+            command: sent_to.map(|topic| CommandRecord {
+                handler_id: handler_id.to_string(),
+                kind: "Send".to_string(),
+                topic: topic.to_string(),
+            }),
+            depth,
+        };
+        let reports: Vec<StepReport> = (0..6).map(|_| runner.step_debug()).collect();
+
+        assert_eq!(
+            reports,
+            vec![
+                report("A", Some("topic_b"), 2),
+                report("B", Some("topic_c"), 3),
+                report("C", None, 2),
+                report("B", None, 1),
+                report("A", None, 0),
+                StepReport {
+                    task: None,
+                    command: None,
+                    depth: 0,
+                },
+            ]
+        );
+    }
+
+    // Test that two independent chains, A -> B -> C and X -> Y -> Z, run interleaved
+    // rather than one after the other
+    #[test]
+    fn test_run_interleaved() {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        for (id, topic, next) in [
+            ("C", "topic_c", None),
+            ("B", "topic_b", Some("topic_c")),
+            ("A", "topic_a", Some("topic_b")),
+            ("Z", "topic_z", None),
+            ("Y", "topic_y", Some("topic_z")),
+            ("X", "topic_x", Some("topic_y")),
+        ] {
+            let actions = next
+                .map(|next| vec![ActorAction::Send(next.to_string())])
+                .unwrap_or_default();
+            runner.msg_bus.register(create_actor_handler(
+                id.to_string(),
+                topic.to_string(),
+                actions,
+                trace.clone(),
+            ));
+        }
+        let roots = ["topic_a", "topic_x"]
+            .map(|topic| {
+                let sub = runner.msg_bus.endpoint(topic).unwrap();
+                Task::Send(SendTask::for_subscription(sub, topic, Rc::new(())))
+            })
+            .into();
+
+        runner.run_interleaved(roots);
+
+        let enter = |id: &str| TraceEvent::Enter(id.to_string());
+        let exit = |id: &str| TraceEvent::Exit(id.to_string());
+        assert_eq!(
+            *trace.borrow(),
+            vec![
+                enter("A"),
+                enter("X"),
+                enter("B"),
+                enter("Y"),
+                enter("C"),
+                exit("C"),
+                enter("Z"),
+                exit("Z"),
+                exit("B"),
+                exit("Y"),
+                exit("A"),
+                exit("X"),
+            ]
+        );
+        assert!(runner.tasks.is_empty());
+        assert_eq!(runner.active_flows(), 0);
+    }
+
+    // Test that a delivery recorder subscribed on two topics records every message
+    #[test]
+    fn test_delivery_recorder() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .subscribe(recorder.subscription("orders", "orders"));
+        runner
+            .msg_bus
+            .subscribe(recorder.subscription("refunds", "refunds"));
+
+        runner.push(Task::Publish(PublishTask::new("orders", Rc::new(1u32))));
+        runner.push(Task::Publish(PublishTask::new("orders", Rc::new(2u32))));
+        runner.push(Task::Publish(PublishTask::new(
+            "refunds",
+            Rc::new("late".to_string()),
+        )));
+        runner.run();
+
+        assert_eq!(recorder.times_called(), 3);
+        let mut orders: Vec<u32> = recorder.received::<u32>().iter().map(|n| **n).collect();
+        orders.sort();
+        assert_eq!(orders, vec![1, 2]);
+        assert_eq!(
+            recorder.received::<String>(),
+            vec![Rc::new("late".to_string())]
+        );
+    }
+
+    // Test that a command's topic borrows a static name passed as a `Cow` instead of
+    // copying it
+    #[test]
+    fn test_cow_topic() {
+        const NAME: &str = "prices";
+        let cmd = Command::Send {
+            topic: Cow::Borrowed(NAME).into(),
+            msg: Rc::new(()),
+        };
+        assert_eq!(cmd.topic().as_ptr(), NAME.as_ptr());
+
+        let owned = Command::Send {
+            topic: Cow::<str>::Owned(NAME.to_string()).into(),
+            msg: Rc::new(()),
+        };
+        assert_eq!(owned.topic(), NAME);
+        assert_ne!(owned.topic().as_ptr(), NAME.as_ptr());
+    }
+
+    // Test that options set through the builder take effect: A sends to B and C, which run
+    // only once A has completed as the tasks are queued
+    #[test]
+    fn test_runner_builder() {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut bus = MessageBus::new();
+        for (id, topic, actions) in [
+            ("A", "topic_a", vec!["topic_b", "topic_c"]),
+            ("B", "topic_b", vec![]),
+            ("C", "topic_c", vec![]),
+        ] {
+            let actions = actions
+                .into_iter()
+                .map(|next| ActorAction::Send(next.to_string()))
+                .collect();
+            bus.register(create_actor_handler(
+                id.to_string(),
+                topic.to_string(),
+                actions,
+                trace.clone(),
+            ));
+        }
+        let mut runner = TaskRunner::builder()
+            .bus(bus)
+            .store(QueueStore::default())
+            .max_resumes(3)
+            .record_commands(true)
+            .build();
+
+        runner.request("topic_a", Rc::new(()));
+
+        let enter = |id: &str| TraceEvent::Enter(id.to_string());
+        let exit = |id: &str| TraceEvent::Exit(id.to_string());
+        assert_eq!(
+            *trace.borrow(),
+            vec![
+                enter("A"),
+                exit("A"),
+                enter("B"),
+                exit("B"),
+                enter("C"),
+                exit("C"),
+            ]
+        );
+        assert_eq!(runner.command_log.len(), 2);
+        // A is resumed once per send and once more to complete, within the limit
+        assert!(runner.warnings.is_empty());
+    }
+
+    // Test that a send without an exact endpoint goes to the most specific wildcard endpoint
+    // matching it, and that an exact endpoint takes precedence over any wildcard one
+    #[test]
+    fn test_wildcard_endpoints() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        for topic in ["*.*", "*.echo", "rpc.*", "rpc.#"] {
+            runner.msg_bus.register(recorder.subscription(topic, topic));
+        }
+
+        let handler = |runner: &TaskRunner| {
+            let sub = runner.msg_bus.endpoint("rpc.echo").unwrap();
+            sub.handler_id.clone()
+        };
+        assert_eq!(handler(&runner), "rpc.*");
+        assert_eq!(runner.msg_bus.route_kind("rpc.echo"), RouteKind::Endpoint);
+        runner.request("rpc.echo", Rc::new(()));
+        assert_eq!(recorder.times_called(), 1);
+        assert!(runner.dead_letters.is_empty());
+
+        runner
+            .msg_bus
+            .register(recorder.subscription("rpc.echo", "exact"));
+        assert_eq!(handler(&runner), "exact");
+        assert!(runner.msg_bus.endpoint("other.thing").is_some());
+        assert!(runner.msg_bus.endpoint("single").is_none());
+    }
+
+    // Test that a routing diff sorts each change into added, removed or re-prioritised
+    #[test]
+    fn test_routing_diff() {
+        let route = |topic: &str, handler_id: &str, priority| RouteInfo {
+            topic: topic.to_string(),
+            handler_id: handler_id.to_string(),
+            priority,
+        };
+        let old = RoutingSnapshot {
+            endpoints: vec![route("orders", "router", 0)],
+            subscriptions: vec![route("prices", "audit", 0), route("prices", "ticker", 1)],
+        };
+        let new = RoutingSnapshot {
+            endpoints: vec![route("orders", "router", 0)],
+            subscriptions: vec![
+                route("prices", "audit", 0),
+                route("prices", "ticker", 5),
+                route("trades", "audit", 0),
+            ],
+        };
+
+        let diff = RoutingSnapshot::diff(&old, &new);
+        assert!(diff.endpoints.is_empty());
+        assert_eq!(
+            diff.subscriptions,
+            RouteDiff {
+                added: vec![route("trades", "audit", 0)],
+                removed: Vec::new(),
+                priority_changed: vec![(
+                    route("prices", "ticker", 1),
+                    route("prices", "ticker", 5)
+                )],
+            }
+        );
+
+        let reverse = RoutingSnapshot::diff(&new, &old);
+        assert_eq!(
+            reverse.subscriptions.removed,
+            vec![route("trades", "audit", 0)]
+        );
+        assert!(RoutingSnapshot::diff(&old, &old).is_empty());
+    }
+
+    // Test that a dead-letter sink can re-route dead letters to a topic of its choosing
+    #[test]
+    fn test_dead_letter_sink() {
+        struct Requeue;
+
+        impl DeadLetterSink for Requeue {
+            fn on_dead_letter(&mut self, runner: &mut TaskRunner, dl: DeadLetter) {
+                if let Some(sub) = runner.msg_bus.endpoint("dlq") {
+                    let send = SendTask::for_subscription(sub, "dlq", dl.msg);
+                    runner.push(Task::Send(send));
+                }
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner.msg_bus.register(recorder.subscription("dlq", "dlq"));
+        runner.set_dead_letter_sink(Requeue);
+
+        assert!(runner.request("nowhere", Rc::new(7u32)).is_none());
+        runner.run();
+
+        assert_eq!(recorder.received::<u32>(), vec![Rc::new(7)]);
+        assert!(runner.dead_letters.is_empty());
+    }
+
+    // Test that shrinking releases the capacity left behind by removed handlers
+    #[test]
+    fn test_shrink_to_fit() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        let bus = &mut runner.msg_bus;
+        for i in 0..1000 {
+            let topic = format!("topic_{i}");
+            bus.register(recorder.subscription(&topic, "endpoint"));
+            bus.subscribe(recorder.subscription(&topic, "subscriber"));
+        }
+        for i in 10..1000 {
+            let topic = format!("topic_{i}");
+            bus.deregister(&topic);
+            bus.remove_subscription(&topic, "subscriber");
+        }
+        let endpoints = runner.msg_bus.endpoints.capacity();
+
+        runner.shrink_to_fit();
+
+        assert!(runner.msg_bus.endpoints.capacity() < endpoints);
+        assert!(runner.msg_bus.is_registered("topic_0"));
+        assert!(runner.msg_bus.is_subscribed("topic_9", "subscriber"));
+    }
+
+    // Test that a once subscription runs for the first publish only, while an ordinary
+    // subscription keeps running, and that a handler matched twice by one publish runs once
+    #[test]
+    fn test_subscribe_once() {
+        let once = DeliveryRecorder::new();
+        let always = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .subscribe_once(once.subscription("ticks.*", "once"));
+        runner
+            .msg_bus
+            .subscribe(always.subscription("ticks.*", "always"));
+        assert!(runner.msg_bus.is_once("ticks.*", "once"));
+
+        for _ in 0..2 {
+            runner.push(Task::Publish(PublishTask::new("ticks.a", Rc::new(()))));
+            runner.run();
+        }
+        assert_eq!(once.times_called(), 1);
+        assert_eq!(always.times_called(), 2);
+        assert!(!runner.msg_bus.is_subscribed("ticks.*", "once"));
+        assert!(!runner.msg_bus.is_once("ticks.*", "once"));
+
+        runner
+            .msg_bus
+            .subscribe_once(once.subscription("ticks.*", "twice"));
+        runner
+            .msg_bus
+            .subscribe_once(once.subscription("ticks.#", "twice"));
+        runner.push(Task::Publish(PublishTask::new("ticks.a", Rc::new(()))));
+        runner.run();
+        assert_eq!(once.times_called(), 2);
+    }
+
+    // Test that the runner reports becoming busy when a task is pushed onto an empty store,
+    // and idle only once the last pending task has completed
+    #[test]
+    fn test_busy_idle_callbacks() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        for handler_id in ["a", "b"] {
+            let sub = recorder.subscription("ticks", handler_id);
+            runner.msg_bus.subscribe(sub);
+        }
+        let busy = Rc::new(Cell::new(0));
+        let idle = Rc::new(Cell::new(0));
+        runner.on_busy({
+            let busy = busy.clone();
+            move || busy.set(busy.get() + 1)
+        });
+        runner.on_idle({
+            let idle = idle.clone();
+            move || idle.set(idle.get() + 1)
+        });
+
+        runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
+        assert_eq!(busy.get(), 1);
+        runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
+        assert_eq!(busy.get(), 1);
+
+        runner.run();
+        assert_eq!(recorder.times_called(), 4);
+        assert_eq!((busy.get(), idle.get()), (1, 1));
+        assert_eq!(runner.step(), StepOutcome::Idle);
+        assert_eq!(idle.get(), 1);
+    }
+
+    // Test that a priority store takes a high-priority send queued after a low-priority one
+    // to the same topic first, and sends of equal priority in the order they were pushed
+    #[test]
+    fn test_priority_store() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::with_store(PriorityStore::default());
+        runner
+            .msg_bus
+            .register(recorder.subscription("jobs", "worker"));
+        for (job, msg_priority) in [(1u32, 0), (2, 0), (3, 7)] {
+            let sub = runner.msg_bus.endpoint("jobs").unwrap();
+            let send = SendTask::for_subscription(sub, "jobs", Rc::new(job));
+            runner.push(Task::Send(send.with_msg_priority(msg_priority)));
+        }
+        let pending: Vec<_> = runner.tasks.iter().map(Task::msg_priority).collect();
+        assert_eq!(pending, vec![0, 0, 7]);
+
+        runner.run();
+
+        let received: Vec<u32> = recorder.received::<u32>().iter().map(|job| **job).collect();
+        assert_eq!(received, vec![3, 1, 2]);
+    }
+
+    // Test that a cooperative run calls back after every given number of steps, and that
+    // the callback can stop it with tasks still pending
+    #[test]
+    fn test_run_cooperative() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        for link in 0..20 {
+            let actions = vec![ActorAction::Send(format!("link_{}", link + 1))];
+            runner.msg_bus.register(create_actor_handler(
+                format!("L{link}"),
+                format!("link_{link}"),
+                actions,
+                trace.clone(),
+            ));
+        }
+        let sub = runner.msg_bus.endpoint("link_0").unwrap();
+        let send = SendTask::for_subscription(sub, "link_0", Rc::new(()));
+        runner.push(Task::Send(send));
+
+        let mut calls = 0;
+        runner.run_cooperative(5, |_| {
+            calls += 1;
+            calls < 2
+        });
+
+        assert_eq!(calls, 2);
+        assert!(!runner.pending().is_empty());
+        assert!(trace.borrow().len() < 40);
+
+        runner.run();
+        assert_eq!(trace.borrow().len(), 40);
+        assert!(is_well_formed(&trace.borrow()));
+    }
+
+    // Test that a send of the wrong payload type to a typed endpoint is dead-lettered as a
+    // type mismatch instead of being delivered, while the right type still is
+    #[test]
+    fn test_register_typed() {
+        struct Sender;
+        impl Actor for Sender {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![
+                    Command::Send {
+                        topic: "numbers".into(),
+                        msg: Rc::new("seven".to_string()),
+                    },
+                    Command::Send {
+                        topic: "numbers".into(),
+                        msg: Rc::new(7u32),
+                    },
+                ]
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .register_typed::<u32>(recorder.subscription("numbers", "adder"));
+        runner.msg_bus.register_actor("start", "sender", Sender);
+        assert_eq!(
+            runner.msg_bus.endpoint_type("numbers", "adder"),
+            Some(TypeId::of::<u32>())
+        );
+        let sub = runner.msg_bus.endpoint("start").unwrap();
+        let send = SendTask::for_subscription(sub, "start", Rc::new(()));
+        runner.push(Task::Send(send));
+
+        runner.run();
+
+        assert_eq!(recorder.times_called(), 1);
+        assert_eq!(*recorder.received::<u32>()[0], 7);
+        assert_eq!(runner.dead_letters.len(), 1);
+        let letter = &runner.dead_letters[0];
+        assert_eq!(letter.reason, DeadLetterReason::TypeMismatch);
+        assert_eq!(letter.topic, "numbers");
+        assert_eq!(letter.msg.downcast_ref::<String>().unwrap(), "seven");
+    }
+
+    // Test that every send path, not only `Send`, dead-letters a payload of the wrong type
+    // for a typed endpoint instead of delivering it
+    #[test]
+    fn test_register_typed_every_send_path() {
+        struct Sender;
+        impl Actor for Sender {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                let msg: Rc<dyn Any> = Rc::new("seven".to_string());
+                vec![
+                    Command::SendBest {
+                        topic: "numbers".into(),
+                        msg: msg.clone(),
+                    },
+                    Command::Tee {
+                        topic: "numbers".into(),
+                        msg: msg.clone(),
+                    },
+                    Command::SendAndNotify {
+                        topic: "numbers".into(),
+                        msg: msg.clone(),
+                    },
+                    Command::SendAfter {
+                        topic: "numbers".into(),
+                        msg: msg.clone(),
+                        delay: 0,
+                    },
+                    Command::Fork {
+                        branches: vec![("numbers".into(), msg)],
+                        join_token: 1,
+                    },
+                ]
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .register_typed::<u32>(recorder.subscription("numbers", "adder"));
+        runner.msg_bus.register_actor("start", "sender", Sender);
+        let sub = runner.msg_bus.endpoint("start").unwrap();
+        let send = SendTask::for_subscription(sub, "start", Rc::new(()));
+        runner.push(Task::Send(send));
+
+        runner.run();
+        assert!(runner.request("numbers", Rc::new(7i64)).is_none());
+
+        assert_eq!(recorder.times_called(), 0);
+        // One each for `SendBest`, `Tee`, `SendAndNotify`, `SendAfter`, `Fork` and the
+        // request.
+        let mismatches = runner
+            .dead_letters
+            .iter()
+            .filter(|letter| letter.reason == DeadLetterReason::TypeMismatch)
+            .inspect(|letter| assert_eq!(letter.topic, "numbers"))
+            .count();
+        assert_eq!(mismatches, 6);
+    }
+
+    // Test that subscriptions are listed by topic then handler ID whatever order they were
+    // subscribed in, and that unsubscribing and resubscribing keeps that order
+    #[test]
+    fn test_subscription_topics_sorted() {
+        let recorder = DeliveryRecorder::new();
+        let mut bus = MessageBus::new();
+        for (topic, handler_id) in [("b", "y"), ("a", "z"), ("b", "x"), ("c", "w"), ("a", "v")] {
+            bus.subscribe(recorder.subscription(topic, handler_id));
+        }
+        bus.remove_subscription("c", "w");
+        bus.subscribe(recorder.subscription("b", "y"));
+
+        let topics: Vec<_> = bus.subscription_topics().collect();
+        assert_eq!(topics, vec![("a", "v"), ("a", "z"), ("b", "x"), ("b", "y")]);
+        let routes = bus.clone_routing().subscriptions;
+        assert!(routes.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    // Test that peeking at a pure handler reports each command the following step
+    // processes, and that a handler not marked pure isn't peeked at
+    #[test]
+    fn test_try_peek_command() {
+        struct Splitter;
+        impl Actor for Splitter {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![
+                    Command::Send {
+                        topic: "left".into(),
+                        msg: Rc::new(()),
+                    },
+                    Command::Publish {
+                        pattern: "right".into(),
+                        msg: Rc::new(()),
+                    },
+                ]
+            }
+        }
+
+        let mut runner = TaskRunner::new();
+        runner.msg_bus.register_actor("split", "splitter", Splitter);
+        let sub = runner.msg_bus.endpoint("split").unwrap();
+        let send = SendTask::for_subscription(sub, "split", Rc::new(()));
+        runner.push(Task::Send(send));
+        assert_eq!(runner.try_peek_command(), None);
+
+        runner.msg_bus.mark_pure("splitter");
+        for kind in ["Send", "Publish"] {
+            let peeked = runner.try_peek_command().unwrap();
+            assert_eq!(peeked.kind, kind);
+            assert_eq!(runner.step_debug().command, Some(peeked));
+        }
+        // The publish, which has no subscribers, then the splitter, which is about to complete.
+        assert_eq!(runner.step(), StepOutcome::Completed);
+        assert_eq!(runner.try_peek_command(), None);
+        assert_eq!(runner.step(), StepOutcome::Completed);
+        assert_eq!(runner.step(), StepOutcome::Idle);
+    }
+
+    // Test that collecting garbage keeps a flow that is still active and forgets one whose
+    // tasks were removed from the store directly, including its handler awaiting a fork
+    #[test]
+    fn test_gc() {
+        struct Scatter;
+        impl Actor for Scatter {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![Command::Fork {
+                    branches: vec![("branch".into(), Rc::new(()))],
+                    join_token: 1,
+                }]
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        for topic in ["branch", "other"] {
+            runner.msg_bus.register(recorder.subscription(topic, topic));
+        }
+        runner.msg_bus.register_actor("scatter", "scatter", Scatter);
+        let push = |runner: &mut TaskRunner, topic: &str| {
+            let sub = runner.msg_bus.endpoint(topic).unwrap();
+            let send = SendTask::for_subscription(sub, topic, Rc::new(()));
+            runner.push(Task::Send(send));
+        };
+
+        // A flow that runs to completion leaves nothing to collect.
+        push(&mut runner, "other");
+        runner.run();
+        assert_eq!(runner.active_flows(), 0);
+
+        push(&mut runner, "other");
+        push(&mut runner, "scatter");
+        runner.step();
+        assert_eq!(runner.forks.len(), 1);
+        runner
+            .tasks
+            .retain(&mut |task| task.topic().as_str() != "branch");
+        assert_eq!(runner.active_flows(), 2);
+
+        runner.gc();
+
+        assert_eq!(runner.active_flows(), 1);
+        assert!(runner.forks.is_empty());
+        runner.run();
+        assert_eq!(recorder.times_called(), 2);
+        assert_eq!(runner.active_flows(), 0);
+    }
+
+    // Test that a publish reset after delivering to every subscriber delivers to each of
+    // them again
+    #[test]
+    fn test_publish_reset() {
+        let first = DeliveryRecorder::new();
+        let second = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .subscribe(first.subscription("news", "first"));
+        runner
+            .msg_bus
+            .subscribe(second.subscription("news", "second"));
+        let mut publish = PublishTask::new("news", Rc::new(7u32));
+        let deliver = |runner: &mut TaskRunner, publish: &mut PublishTask| {
+            while let Some(send) = publish.next_task(&runner.msg_bus) {
+                runner.push(Task::Send(send));
+            }
+            runner.run();
+        };
+
+        deliver(&mut runner, &mut publish);
+        assert_eq!(publish.progress(), (2, 2));
+        publish.reset();
+        assert_eq!(publish.progress(), (0, 0));
+        deliver(&mut runner, &mut publish);
+
+        for recorder in [&first, &second] {
+            let received: Vec<u32> = recorder.received::<u32>().iter().map(|msg| **msg).collect();
+            assert_eq!(received, vec![7, 7]);
+        }
+    }
+
+    // Test that the subscriber count leaves out subscriptions in a disabled group, as a
+    // publish does
+    #[test]
+    fn test_subscriber_count_disabled_group() {
+        let recorder = DeliveryRecorder::new();
+        let mut bus = MessageBus::new();
+        bus.subscribe(recorder.subscription("alerts", "pager"));
+        let mut muted = recorder.subscription("alerts", "email");
+        muted.group = Some("mail".to_string());
+        bus.subscribe(muted);
+        assert_eq!(bus.subscriber_count("alerts"), 2);
+
+        bus.set_group_enabled("mail", false);
+
+        assert_eq!(bus.subscriber_count("alerts"), 1);
+        assert_eq!(
+            bus.answer(&BusQuery::SubscriberCount("alerts".into())),
+            BusAnswer::SubscriberCount(1)
+        );
+    }
+
+    // Test that the subscriber count counts a handler matched by two of its subscriptions
+    // once, as a publish delivers to it once
+    #[test]
+    fn test_subscriber_count_overlapping_patterns() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .subscribe(recorder.subscription("orders.*", "audit"));
+        runner
+            .msg_bus
+            .subscribe(recorder.subscription("orders.#", "audit"));
+
+        assert_eq!(runner.msg_bus.subscriber_count("orders.new"), 1);
+        runner.push(Task::Publish(PublishTask::new("orders.new", Rc::new(()))));
+        runner.run();
+        assert_eq!(recorder.times_called(), 1);
+    }
+
+    // Test that a send-and-notify reaches the endpoint registered under a matching
+    // wildcard topic, as well as the topic's subscribers
+    #[test]
+    fn test_send_and_notify_wildcard_endpoint() {
+        struct Notifier;
+        impl Actor for Notifier {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![Command::SendAndNotify {
+                    topic: "rpc.echo".into(),
+                    msg: Rc::new(()),
+                }]
+            }
+        }
+
+        let endpoint = DeliveryRecorder::new();
+        let subscriber = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .register(endpoint.subscription("rpc.*", "server"));
+        runner
+            .msg_bus
+            .subscribe(subscriber.subscription("rpc.echo", "monitor"));
+        runner.msg_bus.register_actor("start", "notifier", Notifier);
+        let sub = runner.msg_bus.endpoint("start").unwrap();
+        let send = SendTask::for_subscription(sub, "start", Rc::new(()));
+        runner.push(Task::Send(send));
+
+        runner.run();
+
+        assert_eq!(endpoint.times_called(), 1);
+        assert_eq!(subscriber.times_called(), 1);
+        assert!(runner.dead_letters.is_empty());
+    }
+
+    // Test that the deepest send chain of a run counts each handler a send nests, not the
+    // siblings a `Tee` fans out to, and starts over with each run
+    #[test]
+    fn test_max_depth_reached() {
+        struct Relay(fn() -> Command);
+        impl Actor for Relay {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![(self.0)()]
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        let to_b = || Command::Send {
+            topic: "b".into(),
+            msg: Rc::new(()),
+        };
+        let to_c = || Command::Send {
+            topic: "c".into(),
+            msg: Rc::new(()),
+        };
+        let tee = || Command::Tee {
+            topic: "leaves".into(),
+            msg: Rc::new(()),
+        };
+        runner.msg_bus.register_actor("a", "a", Relay(to_b));
+        runner.msg_bus.register_actor("b", "b", Relay(to_c));
+        runner.msg_bus.register(recorder.subscription("c", "c"));
+        runner.msg_bus.register_actor("root", "root", Relay(tee));
+        for handler_id in ["x", "y", "z"] {
+            runner
+                .msg_bus
+                .register(recorder.subscription("leaves", handler_id));
+        }
+        let start = |runner: &mut TaskRunner, topic: &str| {
+            let sub = runner.msg_bus.endpoint(topic).unwrap();
+            let send = SendTask::for_subscription(sub, topic, Rc::new(()));
+            runner.push(Task::Send(send));
+        };
+
+        // a -> b -> c is three handlers deep.
+        start(&mut runner, "a");
+        runner.run();
+        assert_eq!(runner.max_depth_reached(), 3);
+
+        // The three leaves are siblings, one below the root.
+        start(&mut runner, "root");
+        runner.run();
+        assert_eq!(runner.max_depth_reached(), 2);
+        assert_eq!(recorder.times_called(), 4);
+    }
+
+    // Test that removing a handler forgets everything the bus keeps under its handler ID,
+    // so a handler added again under it starts clean
+    #[test]
+    fn test_remove_handler_forgets_state() {
+        struct Idle;
+        impl Actor for Idle {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                Vec::new()
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        let bus = &mut runner.msg_bus;
+        bus.register_actor("idle", "h", Idle);
+        bus.register_typed::<u32>(recorder.subscription("numbers", "h"));
+        bus.subscribe_once(recorder.subscription("ticks", "h"));
+        bus.set_default_endpoint(recorder.subscription("", "h"));
+        bus.subscribe_all_publishes("h", recorder.subscription("#", "h").actor_fn);
+        bus.set_supervision("h", Supervision::Restart);
+        bus.mark_pure("h");
+
+        assert_eq!(bus.remove_handler("h"), 5);
+
+        assert!(bus.state::<Idle>("h").is_none());
+        assert_eq!(bus.endpoint_type("numbers", "h"), None);
+        assert_eq!(bus.supervision("h"), Supervision::Ignore);
+        assert!(!bus.is_pure("h"));
+        assert_eq!(bus.remove_handler("h"), 0);
+
+        // A plain subscription under the same ID stays subscribed, and no leftover observer
+        // is delivered the publishes as well.
+        bus.subscribe(recorder.subscription("ticks", "h"));
+        for _ in 0..2 {
+            runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
+        }
+        runner.run();
+        assert_eq!(recorder.times_called(), 2);
+    }
+
+    // Test that cancelling a flow drops its sends scheduled by `SendAfter` that are not yet
+    // due, so they are never delivered
+    #[test]
+    fn test_cancel_flow_scheduled() {
+        struct Delayer;
+        impl Actor for Delayer {
+            fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+                vec![Command::SendAfter {
+                    topic: "later".into(),
+                    msg: Rc::new(()),
+                    delay: 5,
+                }]
+            }
+        }
+
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .register(recorder.subscription("later", "later"));
+        runner.msg_bus.register_actor("root", "root", Delayer);
+        let sub = runner.msg_bus.endpoint("root").unwrap();
+        let send = SendTask::for_subscription(sub, "root", Rc::new(()));
+        runner.push(Task::Send(send));
+        let cancelled = runner.pending()[0].correlation_id;
+
+        // The root yields, then completes, leaving only the scheduled send.
+        runner.step();
+        runner.step();
+        assert!(runner.tasks.is_empty());
+        assert_eq!(runner.cancel_flow(cancelled), 1);
+        runner.run();
+
+        assert_eq!(recorder.times_called(), 0);
+        assert_eq!(runner.queue_depth("later"), 0);
+        assert_eq!(runner.cancel_flow(cancelled), 0);
+    }
+
+    // Test that subscribing a handler ID again on the same topic replaces its handler and
+    // priority, rather than keeping the first subscription or adding a second
+    #[test]
+    fn test_resubscribe_replaces() {
+        let first = DeliveryRecorder::new();
+        let second = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        runner
+            .msg_bus
+            .subscribe(first.subscription("news", "reader").with_priority(1));
+        runner
+            .msg_bus
+            .subscribe(second.subscription("news", "reader").with_priority(5));
+
+        assert_eq!(runner.msg_bus.subscriber_count("news"), 1);
+        let sub = runner.msg_bus.subscription("news", "reader").unwrap();
+        assert_eq!(sub.priority, 5);
+        runner.push(Task::Publish(PublishTask::new("news", Rc::new(()))));
+        runner.run();
+
+        assert_eq!(first.times_called(), 0);
+        assert_eq!(second.times_called(), 1);
+    }
+
+    // Test that the queue depth of each topic counts its pending sends, falls as they are
+    // delivered, and drops to zero for the tasks of a cancelled flow
+    #[test]
+    fn test_queue_depth() {
+        let recorder = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        for topic in ["busy", "idle"] {
+            runner.msg_bus.register(recorder.subscription(topic, topic));
+        }
+        for topic in ["busy", "busy", "busy", "idle"] {
+            let sub = runner.msg_bus.endpoint(topic).unwrap();
+            let send = SendTask::for_subscription(sub, topic, Rc::new(()));
+            runner.push(Task::Send(send));
+        }
+        assert_eq!(runner.queue_depth("busy"), 3);
+        assert_eq!(runner.queue_depth("idle"), 1);
+        assert_eq!(runner.queue_depth("unknown"), 0);
+
+        // The default store runs the most recently pushed task first.
+        runner.step();
+        assert_eq!(runner.queue_depth("idle"), 0);
+        assert_eq!(runner.queue_depth("busy"), 3);
+        runner.step();
+        assert_eq!(runner.queue_depth("busy"), 2);
+
+        let flow = runner.tasks.iter().next().unwrap().correlation_id();
+        assert_eq!(runner.cancel_flow(flow), 1);
+        assert_eq!(runner.queue_depth("busy"), 1);
+
+        runner.run();
+        assert_eq!(recorder.times_called(), 3);
+        assert_eq!(runner.queue_depth("busy"), 0);
+    }
+
+    // Test that a publish observer receives publishes to every pattern, while each pattern
+    // subscriber receives only those it matches
+    #[test]
+    fn test_publish_observer() {
+        let observer = DeliveryRecorder::new();
+        let orders = DeliveryRecorder::new();
+        let trades = DeliveryRecorder::new();
+        let mut runner = TaskRunner::new();
+        let audit = observer.subscription("", "audit");
+        runner
+            .msg_bus
+            .subscribe_all_publishes("audit", audit.actor_fn);
+        runner
+            .msg_bus
+            .subscribe(orders.subscription("orders.*", "orders"));
+        runner
+            .msg_bus
+            .subscribe(trades.subscription("trades.*", "trades"));
+
+        for (pattern, msg) in [("orders.new", 1u32), ("trades.fill", 2)] {
+            runner.push(Task::Publish(PublishTask::new(pattern, Rc::new(msg))));
+            runner.run();
+        }
+
+        let received = |recorder: &DeliveryRecorder| -> Vec<u32> {
+            recorder.received::<u32>().iter().map(|msg| **msg).collect()
+        };
+        assert_eq!(received(&observer), vec![1, 2]);
+        assert_eq!(received(&orders), vec![1]);
+        assert_eq!(received(&trades), vec![2]);
+    }
+
+    // Test that a short soak of route churn and traffic completes cleanly on either store
+    #[test]
+    fn test_soak() {
+        soak(&mut TaskRunner::new(), 2000, 7);
+        soak(&mut TaskRunner::with_store(QueueStore::default()), 2000, 11);
+    }
+
+    // Test that converting each command to its wire form keeps its routing fields, and
+    // indexes its payload in the side table
+    #[test]
+    fn test_wire_command() {
+        let recorder = DeliveryRecorder::new();
+        let msg = || -> Rc<dyn Any> { Rc::new(7u32) };
+        let headers = Headers::from([("trace".to_string(), "on".to_string())]);
+        let route = RouteInfo {
+            topic: "t".to_string(),
+            handler_id: "h".to_string(),
+            priority: 3,
+        };
+        let mut sub = recorder.subscription("t", "h");
+        sub.priority = 3;
+        let cases = vec![
+            (
+                Command::Send {
+                    topic: "t".into(),
+                    msg: msg(),
+                },
+                WireCommand::Send {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::SendOwned {
+                    topic: "t".into(),
+                    msg: Box::new(7u32),
+                },
+                WireCommand::SendOwned {
+                    topic: "t".to_string(),
+                },
+            ),
+            (
+                Command::SendWithHeaders {
+                    topic: "t".into(),
+                    msg: msg(),
+                    headers: headers.clone(),
+                },
+                WireCommand::SendWithHeaders {
+                    topic: "t".to_string(),
+                    msg: 0,
+                    headers,
+                },
+            ),
+            (
+                Command::SendPrioritized {
+                    topic: "t".into(),
+                    msg: msg(),
+                    msg_priority: 2,
+                },
+                WireCommand::SendPrioritized {
+                    topic: "t".to_string(),
+                    msg: 0,
+                    msg_priority: 2,
+                },
+            ),
+            (
+                Command::SendBest {
+                    topic: "t".into(),
+                    msg: msg(),
+                },
+                WireCommand::SendBest {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::Tee {
+                    topic: "t".into(),
+                    msg: msg(),
+                },
+                WireCommand::Tee {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::SendAndNotify {
+                    topic: "t".into(),
+                    msg: msg(),
+                },
+                WireCommand::SendAndNotify {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::Publish {
+                    pattern: "p.*".into(),
+                    msg: msg(),
+                },
+                WireCommand::Publish {
+                    pattern: "p.*".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::PublishPrioritized {
+                    pattern: "p.*".into(),
+                    msg: msg(),
+                    msg_priority: 2,
+                },
+                WireCommand::PublishPrioritized {
+                    pattern: "p.*".to_string(),
+                    msg: 0,
+                    msg_priority: 2,
+                },
+            ),
+            (
+                Command::PublishTopN {
+                    pattern: "p.*".into(),
+                    msg: msg(),
+                    n: 2,
+                },
+                WireCommand::PublishTopN {
+                    pattern: "p.*".to_string(),
+                    msg: 0,
+                    n: 2,
+                },
+            ),
+            (
+                Command::PublishBounded {
+                    pattern: "p.*".into(),
+                    msg: msg(),
+                    max_recipients: 2,
+                },
+                WireCommand::PublishBounded {
+                    pattern: "p.*".to_string(),
+                    msg: 0,
+                    max_recipients: 2,
+                },
+            ),
+            (Command::Register(sub), WireCommand::Register(route.clone())),
+            (
+                Command::Deregister("t".into()),
+                WireCommand::Deregister("t".to_string()),
+            ),
+            (
+                Command::Subscribe(recorder.subscription("t", "h")),
+                WireCommand::Subscribe(RouteInfo {
+                    priority: 0,
+                    ..route
+                }),
+            ),
+            (
+                Command::Unsubscribe(("t".into(), "h".to_string())),
+                WireCommand::Unsubscribe(("t".to_string(), "h".to_string())),
+            ),
+            (
+                Command::Request {
+                    topic: "t".into(),
+                    msg: msg(),
+                },
+                WireCommand::Request {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::RequestInto {
+                    topic: "t".into(),
+                    msg: msg(),
+                    reply: ReplyCell::default(),
+                },
+                WireCommand::RequestInto {
+                    topic: "t".to_string(),
+                    msg: 0,
+                },
+            ),
+            (
+                Command::Query {
+                    query: BusQuery::SubscriberCount("t".into()),
+                    reply: Rc::new(RefCell::new(None)),
+                },
+                WireCommand::Query {
+                    query: "SubscriberCount".to_string(),
+                    topic: "t".to_string(),
+                },
+            ),
+            (
+                Command::SendAfter {
+                    topic: "t".into(),
+                    msg: msg(),
+                    delay: 5,
+                },
+                WireCommand::SendAfter {
+                    topic: "t".to_string(),
+                    msg: 0,
+                    delay: 5,
+                },
+            ),
+            (
+                Command::Requeue { msg: msg() },
+                WireCommand::Requeue { msg: 0 },
+            ),
+        ];
+        for (cmd, expected) in cases {
+            let (wire, payloads): (WireCommand, Vec<Rc<dyn Any>>) = (&cmd).into();
+            assert_eq!(wire, expected);
+            for payload in payloads {
+                assert_eq!(payload.downcast_ref::<u32>(), Some(&7));
+            }
+        }
+
+        // A batch shares one table, so each nested payload has its own index.
+        let batch = Command::Many(vec![
+            Command::Send {
+                topic: "a".into(),
+                msg: msg(),
+            },
+            Command::Many(vec![Command::Publish {
+                pattern: "b".into(),
+                msg: msg(),
+            }]),
+        ]);
+        let (wire, payloads): (WireCommand, Vec<Rc<dyn Any>>) = (&batch).into();
+        assert_eq!(
+            wire,
+            WireCommand::Many(vec![
+                WireCommand::Send {
+                    topic: "a".to_string(),
+                    msg: 0
+                },
+                WireCommand::Many(vec![WireCommand::Publish {
+                    pattern: "b".to_string(),
+                    msg: 1,
+                }]),
+            ])
+        );
+        assert_eq!(payloads.len(), 2);
+    }
+
+    // Test that each publish order delivers to the same subscribers in its own order
+    #[test]
+    fn test_publish_order() {
+        for (order, expected) in [
+            (PublishOrder::Priority, ["b", "c", "a"]),
+            (PublishOrder::Registration, ["c", "a", "b"]),
+            (PublishOrder::HandlerIdAsc, ["a", "b", "c"]),
+        ] {
+            let recorder = DeliveryRecorder::new();
+            let mut runner = TaskRunner::new();
+            runner.msg_bus.set_publish_order(order);
+            for (handler_id, priority) in [("c", 1), ("a", 0), ("b", 2)] {
+                let mut sub = recorder.subscription("ticks", handler_id);
+                sub.priority = priority;
+                runner.msg_bus.subscribe(sub);
+            }
+            let delivered = Rc::new(RefCell::new(Vec::new()));
+            let delivered_clone = delivered.clone();
+            runner.on_complete(move |handler_id| {
+                delivered_clone.borrow_mut().push(handler_id.to_string())
+            });
+
+            runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
+            runner.run();
+
+            assert_eq!(*delivered.borrow(), expected, "{order:?}");
+        }
+    }
+
+    // Test that validating handlers reports only the handler whose factory panics
+    #[test]
+    fn test_validate_handlers() {
+        let recorder = DeliveryRecorder::new();
+        let mut bus = MessageBus::new();
+        bus.register(recorder.subscription("orders", "good"));
+        bus.subscribe(recorder.subscription("orders.*", "also_good"));
+        assert_eq!(bus.validate_handlers(), Ok(()));
+
+        bus.subscribe(Subscription::new("orders.*", "bad", || {
+            panic!("misconfigured handler")
+        }));
+        assert_eq!(bus.validate_handlers(), Err(vec!["bad".to_string()]));
+        assert_eq!(recorder.times_called(), 0);
+    }
+
+    // Test that interleaving takes equal-priority subscribers one yield at a time, each
+    // running what it spawned before the next one's turn, after higher-priority ones
+    #[test]
+    fn test_interleaved_publish() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        runner.record_commands = true;
+        runner
+            .msg_bus
+            .set_publish_order(PublishOrder::PriorityInterleaved);
+        runner.msg_bus.register(create_actor_handler(
+            "log".to_string(),
+            "log".to_string(),
+            Vec::new(),
+            trace.clone(),
         ));
-        let mut runner = TaskRunner::new();
-        runner.push(task);
+        for (id, priority) in [("first", 1), ("a", 0), ("b", 0)] {
+            let actions = vec![ActorAction::Send("log".to_string()); 2];
+            let mut sub =
+                create_actor_handler(id.to_string(), "ticks".to_string(), actions, trace.clone());
+            sub.priority = priority;
+            runner.msg_bus.subscribe(sub);
+        }
+
+        runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
         runner.run();
-        assert_eq!(*counter_clone.borrow(), 1);
 
-        // Now deregister the endpoint and try to send another message.
-        bus.deregister("endpoint_topic");
-        // Message won't be delivered since endpoint is gone
-        assert_eq!(*counter_clone.borrow(), 1);
+        let yielded: Vec<_> = runner
+            .command_log
+            .iter()
+            .map(|record| record.handler_id.as_str())
+            .collect();
+        assert_eq!(yielded, ["first", "first", "a", "b", "a", "b"]);
+        let enter = |id: &str| TraceEvent::Enter(id.to_string());
+        let exit = |id: &str| TraceEvent::Exit(id.to_string());
+        let expected = [
+            enter("first"),
+            enter("log"),
+            exit("log"),
+            enter("log"),
+            exit("log"),
+            exit("first"),
+            enter("a"),
+            enter("log"),
+            exit("log"),
+            enter("b"),
+            enter("log"),
+            exit("log"),
+            enter("log"),
+            exit("log"),
+            enter("log"),
+            exit("log"),
+            exit("a"),
+            exit("b"),
+        ];
+        assert_eq!(*trace.borrow(), expected);
+        assert!(!is_well_formed(&trace.borrow()));
+        assert_eq!(runner.publish_reports[0].delivered, 3);
+        assert_eq!(runner.active_flows(), 0);
     }
 
-    /// Test 2: Subscribe multiple handlers, send messages, unsubscribe one and test interleaving.
+    // Test that a publish paused partway delivers to the remaining subscribers once resumed
     #[test]
-    fn test_subscribe_multiple_and_unsubscribe() {
-        let counter1 = Rc::new(RefCell::new(0));
-        let counter2 = Rc::new(RefCell::new(0));
-        let sub_counter1 = counter1.clone();
-        let sub_counter2 = counter2.clone();
-
+    fn test_pause_publish() {
+        let recorder = DeliveryRecorder::new();
         let mut runner = TaskRunner::new();
+        for i in 1..=5 {
+            runner
+                .msg_bus
+                .subscribe(recorder.subscription("ticks", &format!("sub{i}")));
+        }
+        runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
 
-        // Register two subscriptions on the same topic.
-        runner.msg_bus.subscribe(Subscription {
-            topic: "pubsub_topic".to_string(),
-            actor_fn: Box::new(move || {
-                let value = sub_counter1.clone();
-                Box::pin(
-                    #[coroutine]
-                    move |_msg: Rc<dyn Any>| {
-                        *value.borrow_mut() += 1;
-                    },
-                )
-            }),
-            handler_id: "sub1".to_string(),
-            priority: 0,
-        });
-        runner.msg_bus.subscribe(Subscription {
-            topic: "pubsub_topic".to_string(),
-            actor_fn: Box::new(move || {
-                let value = sub_counter2.clone();
-                Box::pin(
-                    #[coroutine]
-                    move |_msg: Rc<dyn Any>| {
-                        *value.borrow_mut() += 1;
-                    },
-                )
-            }),
-            handler_id: "sub2".to_string(),
-            priority: 0,
-        });
+        // Each delivery is spawned by one step and completed by the next
+        for _ in 0..4 {
+            runner.step();
+        }
+        assert_eq!(recorder.times_called(), 2);
+        let Some(Task::Publish(publish)) = runner.pop() else {
+            panic!("the publish should be next");
+        };
+        let paused = publish.pause();
+        assert_eq!(paused.progress(), (2, 5));
+        assert!(runner.tasks.is_empty());
 
-        // Send a message; both subscriptions should process it.
-        runner.push(Task::Publish(PublishTask::new(
-            "pubsub_topic".to_string(),
-            Rc::new(()),
-        )));
+        runner.resume_publish(paused);
         runner.run();
-        assert_eq!(*counter1.borrow(), 1);
-        assert_eq!(*counter2.borrow(), 1);
 
-        // Unsubscribe the first subscription.
-        runner.msg_bus.remove_subscription("pubsub_topic", "sub1");
+        assert_eq!(recorder.times_called(), 5);
+        assert_eq!(runner.publish_reports.len(), 1);
+        assert_eq!(runner.publish_reports[0].delivered, 5);
+    }
+
+    // Test that swapping the bus mid-chain keeps the running handlers, but routes later
+    // sends through the new bus: A -> B -> C becomes A -> B -> D
+    #[test]
+    fn test_swap_bus() {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        push_static_chain(&mut runner, &trace);
 
-        // Send another message; only the second subscription should process it.
-        runner.push(Task::Publish(PublishTask::new(
-            "pubsub_topic".to_string(),
-            Rc::new(()),
-        )));
+        // A yields its send to B, which is spawned from the old bus
+        runner.step();
+        assert_eq!(runner.tasks.len(), 2);
+
+        let mut new_bus = MessageBus::new();
+        new_bus.register(create_actor_handler(
+            "D".to_string(),
+            "topic_c".to_string(),
+            vec![],
+            trace.clone(),
+        ));
+        let old_bus = runner.swap_bus(new_bus);
         runner.run();
-        assert_eq!(*counter1.borrow(), 1);
-        assert_eq!(*counter2.borrow(), 2);
+
+        let enter = |id: &str| TraceEvent::Enter(id.to_string());
+        let exit = |id: &str| TraceEvent::Exit(id.to_string());
+        assert_eq!(
+            *trace.borrow(),
+            vec![
+                enter("A"),
+                enter("B"),
+                enter("D"),
+                exit("D"),
+                exit("B"),
+                exit("A"),
+            ]
+        );
+        assert!(old_bus.is_registered("topic_b"));
+        assert!(!runner.msg_bus.is_registered("topic_b"));
     }
-}
 
-#[cfg(test)]
-mod property_tests {
-    use super::*;
-    use proptest::prelude::*;
-    use std::cell::RefCell;
-    use std::fmt;
-    use std::rc::Rc;
+    // Test that a runner built around a prepared bus routes through it from the first send
+    #[test]
+    fn test_with_bus() {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut bus = MessageBus::new();
+        for (id, topic, next) in [("A", "topic_a", Some("topic_b")), ("B", "topic_b", None)] {
+            let actions = next
+                .map(|next| vec![ActorAction::Send(next.to_string())])
+                .unwrap_or_default();
+            bus.register(create_actor_handler(
+                id.to_string(),
+                topic.to_string(),
+                actions,
+                trace.clone(),
+            ));
+        }
 
-    // Simplified trace events
-    #[derive(Debug, Clone, PartialEq)]
-    enum TraceEvent {
-        Enter(String), // Enter handler with ID
-        Exit(String),  // Exit handler with ID
-    }
+        let mut runner = TaskRunner::with_bus(bus);
+        runner.request("topic_a", Rc::new(()));
 
-    // Simple actions an actor can perform
-    #[derive(Debug, Clone, PartialEq)]
-    enum ActorAction {
-        Send(String),    // Send to topic
-        Publish(String), // Publish to pattern
+        let enter = |id: &str| TraceEvent::Enter(id.to_string());
+        let exit = |id: &str| TraceEvent::Exit(id.to_string());
+        assert_eq!(
+            *trace.borrow(),
+            vec![enter("A"), enter("B"), exit("B"), exit("A")]
+        );
+        assert!(runner.dead_letters.is_empty());
     }
 
-    // Function to verify a trace is well-formed (proper nesting)
-    fn is_well_formed(trace: &[TraceEvent]) -> bool {
-        let mut stack = Vec::new();
+    #[cfg(feature = "logging")]
+    mod logging {
+        use super::*;
+        use log::kv::{Error, Key, Value, VisitSource};
+        use log::{Log, Metadata, Record};
+        use std::sync::Once;
 
-        for event in trace {
-            match event {
-                TraceEvent::Enter(id) => {
-                    stack.push(id.clone());
-                }
-                TraceEvent::Exit(id) => {
-                    if stack.pop() != Some(id.clone()) {
-                        return false; // Mismatched exit
-                    }
-                }
+        thread_local! {
+            static RECORDS: RefCell<Vec<Vec<(String, String)>>> = const { RefCell::new(Vec::new()) };
+        }
+
+        /// Captures each record's message and key-values on the logging thread, so
+        /// concurrently running tests don't see each other's records.
+        struct CapturingLogger;
+
+        struct Fields(Vec<(String, String)>);
+
+        impl<'kvs> VisitSource<'kvs> for Fields {
+            fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+                self.0.push((key.to_string(), value.to_string()));
+                Ok(())
             }
         }
 
-        stack.is_empty() // Stack should be empty at the end
-    }
+        impl Log for CapturingLogger {
+            fn enabled(&self, _metadata: &Metadata) -> bool {
+                true
+            }
 
-    // Create a handler that executes a sequence of actions
-    fn create_actor_handler(
-        id: String,
-        topic: String,
-        actions: Vec<ActorAction>,
-        trace: Rc<RefCell<Vec<TraceEvent>>>,
-    ) -> Subscription {
-        let id_clone = id.clone();
-        Subscription {
-            topic: topic.clone(),
-            actor_fn: Box::new(move || {
-                let id = id.clone();
-                let trace = trace.clone();
-                let actions = actions.clone();
-
-                Box::pin(
-                    #[coroutine]
-                    static move |_msg: Rc<dyn Any>| {
-                        // Record entry
-                        trace.borrow_mut().push(TraceEvent::Enter(id.clone()));
-
-                        // Execute each action in sequence
-                        for action in &actions {
-                            match action {
-                                ActorAction::Send(to_topic) => {
-                                    yield Command::Send {
-                                        topic: to_topic.clone(),
-                                        msg: Rc::new(()),
-                                    };
-                                }
-                                ActorAction::Publish(pattern) => {
-                                    yield Command::Publish {
-                                        pattern: pattern.clone(),
-                                        msg: Rc::new(()),
-                                    };
-                                }
-                            }
-                        }
+            fn log(&self, record: &Record) {
+                let mut fields = Fields(vec![("msg".to_string(), record.args().to_string())]);
+                record.key_values().visit(&mut fields).unwrap();
+                RECORDS.with(|records| records.borrow_mut().push(fields.0));
+            }
 
-                        // Record exit
-                        trace.borrow_mut().push(TraceEvent::Exit(id.clone()));
-                    },
-                )
-            }),
-            handler_id: id_clone,
-            priority: 0,
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+        static INIT: Once = Once::new();
+
+        fn init_logger() {
+            INIT.call_once(|| {
+                log::set_logger(&LOGGER).unwrap();
+                log::set_max_level(log::LevelFilter::Trace);
+            });
+        }
+
+        fn record(msg: &str, kind: &str, topic: &str, handler_id: &str) -> Vec<(String, String)> {
+            [
+                ("msg", msg),
+                ("kind", kind),
+                ("topic", topic),
+                ("handler_id", handler_id),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+        }
+
+        // Test the log records emitted for the static chain: A -> B -> C
+        #[test]
+        fn test_static_chain_logging() {
+            init_logger();
+
+            let trace = Rc::new(RefCell::new(Vec::new()));
+            let mut runner = TaskRunner::new();
+            push_static_chain(&mut runner, &trace);
+            runner.run();
+
+            let records = RECORDS.with(|records| records.take());
+            assert_eq!(
+                records,
+                vec![
+                    record("task spawned", "Send", "topic_a", "A"),
+                    record("command processed", "Send", "topic_b", "A"),
+                    record("task spawned", "Send", "topic_b", "B"),
+                    record("command processed", "Send", "topic_c", "B"),
+                    record("task spawned", "Send", "topic_c", "C"),
+                ]
+            );
+        }
+
+        // Test that dropping a runner stopped mid-flow reports the tasks left on its stack
+        #[test]
+        fn test_warn_on_drop() {
+            init_logger();
+
+            let trace = Rc::new(RefCell::new(Vec::new()));
+            let mut runner = TaskRunner::new();
+            runner.warn_on_drop = true;
+            push_static_chain(&mut runner, &trace);
+            // A sends to B, and B to C, leaving all three pending.
+            runner.step();
+            runner.step();
+            RECORDS.with(|records| records.take());
+            drop(runner);
+
+            let records = RECORDS.with(|records| records.take());
+            assert_eq!(
+                records,
+                vec![
+                    record("task leaked", "Send", "topic_a", "A"),
+                    record("task leaked", "Send", "topic_b", "B"),
+                    record("task leaked", "Send", "topic_c", "C"),
+                ]
+            );
         }
     }
 
-    // Test for static chain: A -> B -> C
+    // Test round-tripping the command log of the static chain through NDJSON
+    #[cfg(feature = "serde")]
     #[test]
-    fn test_static_chain() {
+    fn test_command_log_round_trip() {
         let trace = Rc::new(RefCell::new(Vec::new()));
         let mut runner = TaskRunner::new();
+        runner.record_commands = true;
+        push_static_chain(&mut runner, &trace);
+        runner.run();
+        assert_eq!(runner.command_log.len(), 2);
 
-        // Define actions for each actor
-        let c_actions: Vec<ActorAction> = vec![];
-        let b_actions = vec![ActorAction::Send("topic_c".to_string())];
-        let a_actions = vec![ActorAction::Send("topic_b".to_string())];
-
-        // Register all handlers
-        runner.msg_bus.register(create_actor_handler(
-            "C".to_string(),
-            "topic_c".to_string(),
-            c_actions,
-            trace.clone(),
-        ));
-
-        runner.msg_bus.register(create_actor_handler(
-            "B".to_string(),
-            "topic_b".to_string(),
-            b_actions,
-            trace.clone(),
-        ));
+        let mut buffer = Vec::new();
+        runner.write_log(&mut buffer).unwrap();
+        let records = read_log(buffer.as_slice()).unwrap();
 
-        runner.msg_bus.register(create_actor_handler(
-            "A".to_string(),
-            "topic_a".to_string(),
-            a_actions,
-            trace.clone(),
-        ));
+        assert_eq!(records, runner.command_log);
+    }
 
-        // Start with A
-        runner.push(Task::Send(SendTask::new(
-            "topic_a".to_string(),
-            (runner.msg_bus.endpoints["topic_a"].actor_fn)(),
-            Rc::new(()),
-        )));
 
-        // Run and verify
-        runner.run();
+    proptest! {
+        // Test that publishes nested in publishes run depth first: every random acyclic graph
+        // of subscribers that publish to their targets produces a well-formed trace
+        #[test]
+        fn test_nested_publish_trace(mut graph in acyclic_graph(8, 3)) {
+            for targets in &mut graph.edges {
+                targets.sort();
+                targets.dedup();
+            }
+            let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+            let mut runner = TaskRunner::new();
+            graph.subscribe(&mut runner, &trace);
+            let events = run_and_collect_trace(&mut runner, graph.root_publish(), &trace);
 
-        // Expected trace: A enters, B enters, C enters, C exits, B exits, A exits
-        let expected_trace = vec![
-            TraceEvent::Enter("A".to_string()),
-            TraceEvent::Enter("B".to_string()),
-            TraceEvent::Enter("C".to_string()),
-            TraceEvent::Exit("C".to_string()),
-            TraceEvent::Exit("B".to_string()),
-            TraceEvent::Exit("A".to_string()),
-        ];
+            prop_assert!(is_well_formed(&events));
+            let entered = events
+                .iter()
+                .filter(|event| matches!(event, TraceEvent::Enter(_)))
+                .count();
+            prop_assert_eq!(entered, graph.invocations().iter().sum::<usize>());
+        }
 
-        assert!(
-            is_well_formed(&trace.borrow()),
-            "Trace is not well-formed: {:?}",
-            *trace.borrow()
-        );
+        // Test that every random acyclic actor graph produces a well-formed trace
+        #[test]
+        fn test_acyclic_graph_trace(graph in acyclic_graph(8, 3)) {
+            let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+            let mut runner = TaskRunner::new();
+            graph.register(&mut runner, &trace);
+            let root = graph.root(&runner);
+            let events = run_and_collect_trace(&mut runner, root, &trace);
 
-        assert_eq!(
-            *trace.borrow(),
-            expected_trace,
-            "Trace mismatch: {:?}",
-            *trace.borrow()
-        );
+            prop_assert!(is_well_formed(&events));
+            let entered = events
+                .iter()
+                .filter(|event| matches!(event, TraceEvent::Enter(_)))
+                .count();
+            prop_assert_eq!(entered, graph.invocations().iter().sum::<usize>());
+        }
     }
 
-    // Test for tree structure: A -> (B, C), B -> (D, E)
+    // Pin down inputs the matcher has got wrong. Publishes used to reach every subscription
+    // whose topic contained the pattern as a substring, so the first few matched.
     #[test]
-    fn test_tree_structure() {
-        let trace = Rc::new(RefCell::new(Vec::new()));
-        let mut runner = TaskRunner::new();
+    fn test_topic_matches_regressions() {
+        for (pattern, topic, expected) in [
+            ("sensors", "sensors_extra", false),
+            ("temp", "sensors.temp", false),
+            ("sensors.temp", "sensors.temperature", false),
+            ("a.b", "a.b.c", false),
+            ("s.t", "xs.ty", false),
+            ("", "anything", true),
+            ("#", "", true),
+            ("*", "", true),
+            (".", ".", true),
+            ("..", "a", false),
+            ("a.", "a", false),
+            ("a.#.c", "a.x.y.z", false),
+            ("a.x.y.z", "a.#.c", false),
+            ("a.#.c", "a.x.y.c", true),
+            ("a.#.c", "a.c", true),
+            ("#.c", "a.b", false),
+        ] {
+            assert_eq!(
+                topic_matches(pattern, topic),
+                expected,
+                "{pattern:?} against {topic:?}"
+            );
+        }
+    }
 
-        // Define actions for each actor
-        let d_actions: Vec<ActorAction> = vec![];
-        let e_actions: Vec<ActorAction> = vec![];
-        let c_actions: Vec<ActorAction> = vec![];
-        let b_actions = vec![
-            ActorAction::Send("topic_d".to_string()),
-            ActorAction::Send("topic_e".to_string()),
-        ];
-        let a_actions = vec![
-            ActorAction::Send("topic_b".to_string()),
-            ActorAction::Send("topic_c".to_string()),
-        ];
+    proptest! {
+        // Test that the matcher never panics, even on arbitrary strings
+        #[test]
+        fn test_topic_matches_total(pattern in ".*", topic in ".*") {
+            topic_matches(&pattern, &topic);
+        }
 
-        // Register all handlers
-        runner.msg_bus.register(create_actor_handler(
-            "D".to_string(),
-            "topic_d".to_string(),
-            d_actions,
-            trace.clone(),
-        ));
+        // Test that every topic matches itself, wildcards and all
+        #[test]
+        fn test_topic_matches_reflexive(topic in topic(5)) {
+            prop_assert!(topic_matches(&topic, &topic));
+        }
 
-        runner.msg_bus.register(create_actor_handler(
-            "E".to_string(),
-            "topic_e".to_string(),
-            e_actions,
-            trace.clone(),
-        ));
+        // Test that without wildcards a pattern matches exactly its own topic, unless it is
+        // the empty broadcast pattern
+        #[test]
+        fn test_topic_matches_literal(pattern in literal_topic(4), topic in literal_topic(4)) {
+            let expected = pattern.is_empty() || pattern == topic;
+            prop_assert_eq!(topic_matches(&pattern, &topic), expected);
+        }
 
-        runner.msg_bus.register(create_actor_handler(
-            "C".to_string(),
-            "topic_c".to_string(),
-            c_actions,
-            trace.clone(),
-        ));
+        // Test that a trailing `#` matches any suffix of its prefix
+        #[test]
+        fn test_topic_matches_hash_suffix(prefix in topic(3), suffix in topic(3)) {
+            let pattern = format!("{prefix}.#");
+            let topic = format!("{prefix}.{suffix}");
+            prop_assert!(topic_matches(&pattern, &topic));
+        }
 
-        runner.msg_bus.register(create_actor_handler(
-            "B".to_string(),
-            "topic_b".to_string(),
-            b_actions,
-            trace.clone(),
-        ));
+        // Test that wildcards, `#` included wherever it appears, match the same way on either
+        // side, apart from the empty broadcast pattern
+        #[test]
+        fn test_topic_matches_symmetric(pattern in topic(4), topic in topic(4)) {
+            prop_assume!(!pattern.is_empty() && !topic.is_empty());
+            prop_assert_eq!(topic_matches(&pattern, &topic), topic_matches(&topic, &pattern));
+        }
 
-        runner.msg_bus.register(create_actor_handler(
-            "A".to_string(),
-            "topic_a".to_string(),
-            a_actions,
-            trace.clone(),
-        ));
+        // Test that a `#` before the last segment matches any middle, but still requires the
+        // segments around it
+        #[test]
+        fn test_topic_matches_inner_hash(
+            prefix in literal_topic(3),
+            middle in literal_topic(3),
+            last in literal_topic(1),
+            other in literal_topic(1),
+        ) {
+            let pattern = format!("{prefix}.#.{last}");
+            let topic = format!("{prefix}.{middle}.{other}");
+            prop_assert_eq!(topic_matches(&pattern, &topic), other == last);
+        }
+    }
 
-        // Start with A
-        runner.push(Task::Send(SendTask::new(
-            "topic_a".to_string(),
-            (runner.msg_bus.endpoints["topic_a"].actor_fn)(),
-            Rc::new(()),
-        )));
 
-        // Run and verify
+    // Test the yield and completion observers on the static chain: A -> B -> C
+    #[test]
+    fn test_static_chain_observers() {
+        let yields = Rc::new(RefCell::new(0));
+        let completions = Rc::new(RefCell::new(Vec::new()));
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        let yields_clone = yields.clone();
+        runner.on_yield(move |_cmd| *yields_clone.borrow_mut() += 1);
+        let completions_clone = completions.clone();
+        runner.on_complete(move |handler_id| {
+            completions_clone.borrow_mut().push(handler_id.to_string())
+        });
+        push_static_chain(&mut runner, &trace);
         runner.run();
 
-        // Expected trace: A enters, B enters, D enters, D exits, E enters, E exits, B exits, C enters, C exits, A exits
-        let expected_trace = vec![
-            TraceEvent::Enter("A".to_string()),
-            TraceEvent::Enter("B".to_string()),
-            TraceEvent::Enter("D".to_string()),
-            TraceEvent::Exit("D".to_string()),
-            TraceEvent::Enter("E".to_string()),
-            TraceEvent::Exit("E".to_string()),
-            TraceEvent::Exit("B".to_string()),
-            TraceEvent::Enter("C".to_string()),
-            TraceEvent::Exit("C".to_string()),
-            TraceEvent::Exit("A".to_string()),
-        ];
+        assert_eq!(*yields.borrow(), 2);
+        assert_eq!(*completions.borrow(), vec!["C", "B", "A"]);
+    }
 
-        assert!(
-            is_well_formed(&trace.borrow()),
-            "Trace is not well-formed: {:?}",
-            *trace.borrow()
-        );
+    // A hand-written handler that records its entry, sends to `next` if set and records
+    // its exit, written the way handlers are on the `stable` backend.
+    struct Relay {
+        id: &'static str,
+        next: Option<&'static str>,
+        trace: Trace,
+        sent: bool,
+    }
 
-        assert_eq!(
-            *trace.borrow(),
-            expected_trace,
-            "Trace mismatch: {:?}",
-            *trace.borrow()
+    impl Coroutine<Envelope> for Relay {
+        type Yield = Command;
+        type Return = HandlerResult;
+
+        fn resume(
+            mut self: Pin<&mut Self>,
+            _msg: Envelope,
+        ) -> CoroutineState<Command, HandlerResult> {
+            if !self.sent {
+                self.sent = true;
+                self.trace
+                    .borrow_mut()
+                    .push(TraceEvent::Enter(self.id.to_string()));
+                if let Some(next) = self.next {
+                    return CoroutineState::Yielded(Command::Send {
+                        topic: next.into(),
+                        msg: Rc::new(()),
+                    });
+                }
+            }
+            self.trace
+                .borrow_mut()
+                .push(TraceEvent::Exit(self.id.to_string()));
+            CoroutineState::Complete(Ok(None))
+        }
+    }
+
+    // Test for static chain with state machine handlers: A -> B -> C, which traces the same
+    // on either coroutine backend
+    #[test]
+    fn test_static_chain_state_machines() {
+        let trace: Trace = Rc::new(RefCell::new(Vec::new()));
+        let mut runner = TaskRunner::new();
+        for (id, topic, next) in [
+            ("C", "topic_c", None),
+            ("B", "topic_b", Some("topic_c")),
+            ("A", "topic_a", Some("topic_b")),
+        ] {
+            let trace = trace.clone();
+            runner
+                .msg_bus
+                .register(Subscription::new(topic, id, move || {
+                    Box::pin(Relay {
+                        id,
+                        next,
+                        trace: trace.clone(),
+                        sent: false,
+                    })
+                }));
+        }
+        let root = SendTask::for_subscription(
+            &runner.msg_bus.endpoints["topic_a"][0],
+            "topic_a",
+            Rc::new(()),
         );
+
+        let events = run_and_collect_trace(&mut runner, Task::Send(root), &trace);
+
+        let expected: Vec<TraceEvent> = ["A", "B", "C"]
+            .map(|id| TraceEvent::Enter(id.to_string()))
+            .into_iter()
+            .chain(["C", "B", "A"].map(|id| TraceEvent::Exit(id.to_string())))
+            .collect();
+        assert_eq!(events, expected);
     }
 }
 
@@ -714,23 +6502,17 @@ mod property_tests {
 
 
 // Posei Experiments: Code update - 20260101154156
-
 // Posei Experiments: Code update - 20260101154157
-
 // Posei Experiments: Code update - 20260101154201
-
 // Posei Experiments: Code update - 20260101154202
-
 // Posei Experiments: Code update - 20260101154203
 # Posei Experiments: Commit enhancement - 20260101154203
 
-
 // Posei Experiments: Code update - 20260101154206
 
 // Posei Experiments: Code update - 20260101154208
 # Posei Experiments: Commit enhancement - 20260101154208
 
-
 // Posei Experiments: Code update - 20260101154209
 
 // Posei Experiments: Code update - 20260101154210
@@ -0,0 +1,31 @@
+//! The coroutine backend of the `stable` feature, for toolchains without the nightly
+//! `coroutines` feature.
+//!
+//! The traits here mirror `core::ops::Coroutine` and `core::ops::CoroutineState`, so
+//! [`ActorCoroutine`](crate::ActorCoroutine), [`Command`](crate::Command) and
+//! [`TaskRunner`](crate::TaskRunner) are unchanged. What changes is how handlers are written:
+//! without `#[coroutine]` closures, a handler is a state machine that implements
+//! [`Coroutine`] by hand, keeping whatever it needs to pick up where it left off.
+
+use std::pin::Pin;
+
+/// What resuming a coroutine produced. Mirrors `core::ops::CoroutineState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CoroutineState<Y, R> {
+    /// The coroutine suspended with a value and can be resumed again.
+    Yielded(Y),
+    /// The coroutine finished with a value and must not be resumed again.
+    Complete(R),
+}
+
+/// A computation that is resumed with an `R` until it completes. Mirrors
+/// `core::ops::Coroutine`.
+pub trait Coroutine<R = ()> {
+    /// The type of value this coroutine yields.
+    type Yield;
+    /// The type of value this coroutine returns.
+    type Return;
+
+    /// Runs the coroutine until it next yields or completes.
+    fn resume(self: Pin<&mut Self>, arg: R) -> CoroutineState<Self::Yield, Self::Return>;
+}
@@ -0,0 +1,470 @@
+//! Helpers for testing message flows: handlers that record a trace of their execution or
+//! the messages delivered to them, a check that a trace is properly nested, proptest
+//! strategies for random actor graphs and topics, and a seeded soak test of route churn.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use proptest::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub use crate::TraceEvent;
+use crate::{
+    ActorFn, Command, CommandRecord, Coroutine, CoroutineState, Envelope, HandlerResult,
+    PublishTask, RouteInfo, SendTask, Subscription, Task, TaskRunner,
+};
+
+/// The trace shared by the handlers of one test.
+pub type Trace = Rc<RefCell<Vec<TraceEvent>>>;
+
+/// A command a handler from `create_actor_handler` yields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActorAction {
+    /// Send to a topic.
+    Send(String),
+    /// Publish to a pattern.
+    Publish(String),
+}
+
+/// Returns whether every `Exit` closes the innermost open `Enter` and nothing is left open.
+pub fn is_well_formed(trace: &[TraceEvent]) -> bool {
+    let mut stack = Vec::new();
+
+    for event in trace {
+        match event {
+            TraceEvent::Enter(id) => {
+                stack.push(id.clone());
+            }
+            TraceEvent::Exit(id) => {
+                if stack.pop() != Some(id.clone()) {
+                    return false; // Mismatched exit
+                }
+            }
+        }
+    }
+
+    stack.is_empty() // Stack should be empty at the end
+}
+
+/// Creates a subscription whose handler records its entry, yields `actions` in order and
+/// records its exit. The handler is a hand-written state machine rather than a
+/// `#[coroutine]` closure so that it works on either coroutine backend.
+pub fn create_actor_handler(
+    id: String,
+    topic: String,
+    actions: Vec<ActorAction>,
+    trace: Trace,
+) -> Subscription {
+    let id_clone = id.clone();
+    Subscription::new(topic, id_clone, move || {
+        Box::pin(ScriptedActor {
+            id: id.clone(),
+            actions: actions.clone(),
+            trace: trace.clone(),
+            next: None,
+        })
+    })
+}
+
+/// The state machine behind `create_actor_handler`.
+struct ScriptedActor {
+    id: String,
+    actions: Vec<ActorAction>,
+    trace: Trace,
+    /// The index of the next action to yield, or `None` before the first resume.
+    next: Option<usize>,
+}
+
+impl Coroutine<Envelope> for ScriptedActor {
+    type Yield = Command;
+    type Return = HandlerResult;
+
+    fn resume(
+        mut self: std::pin::Pin<&mut Self>,
+        _msg: Envelope,
+    ) -> CoroutineState<Command, HandlerResult> {
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                // Record entry
+                self.trace
+                    .borrow_mut()
+                    .push(TraceEvent::Enter(self.id.clone()));
+                0
+            }
+        };
+        self.next = Some(next + 1);
+        let command = match self.actions.get(next) {
+            Some(ActorAction::Send(to_topic)) => Command::Send {
+                topic: to_topic.as_str().into(),
+                msg: Rc::new(()),
+            },
+            Some(ActorAction::Publish(pattern)) => Command::Publish {
+                pattern: pattern.as_str().into(),
+                msg: Rc::new(()),
+            },
+            None => {
+                // Record exit
+                self.trace
+                    .borrow_mut()
+                    .push(TraceEvent::Exit(self.id.clone()));
+                return CoroutineState::Complete(Ok(None));
+            }
+        };
+        CoroutineState::Yielded(command)
+    }
+}
+
+/// Records the messages delivered to the handlers it creates, so tests can assert on them
+/// after a run instead of threading counters through handlers. Clones share the record.
+#[derive(Clone, Default)]
+pub struct DeliveryRecorder {
+    received: Rc<RefCell<Vec<Rc<dyn Any>>>>,
+}
+
+impl DeliveryRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a subscription whose handler records the message it is delivered and
+    /// completes without yielding.
+    pub fn subscription(&self, topic: &str, handler_id: &str) -> Subscription {
+        let received = self.received.clone();
+        Subscription::new(topic, handler_id, move || {
+            Box::pin(RecordingHandler {
+                received: received.clone(),
+            })
+        })
+    }
+
+    /// The number of messages delivered to the recorder's handlers.
+    pub fn times_called(&self) -> usize {
+        self.received.borrow().len()
+    }
+
+    /// The messages delivered that are `T`s, in delivery order.
+    pub fn received<T: Any>(&self) -> Vec<Rc<T>> {
+        self.received
+            .borrow()
+            .iter()
+            .filter_map(|msg| msg.clone().downcast().ok())
+            .collect()
+    }
+}
+
+/// The handler behind `DeliveryRecorder::subscription`.
+struct RecordingHandler {
+    received: Rc<RefCell<Vec<Rc<dyn Any>>>>,
+}
+
+impl Coroutine<Envelope> for RecordingHandler {
+    type Yield = Command;
+    type Return = HandlerResult;
+
+    fn resume(
+        self: std::pin::Pin<&mut Self>,
+        msg: Envelope,
+    ) -> CoroutineState<Command, HandlerResult> {
+        self.received.borrow_mut().push(msg.msg);
+        CoroutineState::Complete(Ok(None))
+    }
+}
+
+/// Pushes `root`, runs `runner` until it is idle and returns the events recorded to
+/// `trace` in the meantime.
+pub fn run_and_collect_trace(runner: &mut TaskRunner, root: Task, trace: &Trace) -> Vec<TraceEvent> {
+    trace.borrow_mut().clear();
+    runner.push(root);
+    runner.run();
+    trace.take()
+}
+
+/// Runs a fresh instance of `actor_fn`'s handler on its own, without a runner or bus, and
+/// returns the commands it yields until it completes. The handler is first resumed with
+/// `msg` and then with `()` in place of whatever a runner would resume it with, so the
+/// probe follows the path the handler takes when nothing replies. Loops forever on a
+/// handler that never completes.
+pub fn probe_handler(actor_fn: &ActorFn, msg: Rc<dyn Any>) -> Vec<CommandRecord> {
+    let mut send = SendTask::new("", actor_fn(), msg.clone());
+    let mut input = msg;
+    let mut commands = Vec::new();
+    while let CoroutineState::Yielded(cmd) = send.resume(input) {
+        commands.push(CommandRecord::new("", &cmd));
+        input = Rc::new(());
+    }
+    commands
+}
+
+/// A directed acyclic graph of actors. Actor `i` handles `topic(i)` and sends to each
+/// actor in `edges[i]` in turn; edges only point to higher indices. Subscribed instead of
+/// registered, actor `i` publishes once to `fanout_topic(i)`, which its targets subscribe to.
+#[derive(Debug, Clone)]
+pub struct ActorGraph {
+    pub edges: Vec<Vec<usize>>,
+}
+
+impl ActorGraph {
+    pub fn topic(actor: usize) -> String {
+        format!("topic_{actor}")
+    }
+
+    pub fn handler_id(actor: usize) -> String {
+        format!("actor_{actor}")
+    }
+
+    pub fn fanout_topic(actor: usize) -> String {
+        format!("fanout_{actor}")
+    }
+
+    /// Registers an endpoint for every actor, recording to `trace`.
+    pub fn register(&self, runner: &mut TaskRunner, trace: &Trace) {
+        for (actor, targets) in self.edges.iter().enumerate() {
+            let actions = targets
+                .iter()
+                .map(|&target| ActorAction::Send(Self::topic(target)))
+                .collect();
+            runner.msg_bus.register(create_actor_handler(
+                Self::handler_id(actor),
+                Self::topic(actor),
+                actions,
+                trace.clone(),
+            ));
+        }
+    }
+
+    /// Subscribes every actor, recording to `trace`: actor 0 to `topic(0)` and every other
+    /// actor to the fan-out topic of each actor with an edge to it. An actor with several
+    /// edges to the same target thus reaches it only once.
+    pub fn subscribe(&self, runner: &mut TaskRunner, trace: &Trace) {
+        let mut topics = vec![Vec::new(); self.edges.len()];
+        if let Some(root) = topics.first_mut() {
+            root.push(Self::topic(0));
+        }
+        for (actor, targets) in self.edges.iter().enumerate() {
+            for &target in targets {
+                let topic = Self::fanout_topic(actor);
+                if !topics[target].contains(&topic) {
+                    topics[target].push(topic);
+                }
+            }
+        }
+        for (actor, (targets, topics)) in self.edges.iter().zip(topics).enumerate() {
+            let actions = if targets.is_empty() {
+                Vec::new()
+            } else {
+                vec![ActorAction::Publish(Self::fanout_topic(actor))]
+            };
+            for topic in topics {
+                runner.msg_bus.subscribe(create_actor_handler(
+                    Self::handler_id(actor),
+                    topic,
+                    actions.clone(),
+                    trace.clone(),
+                ));
+            }
+        }
+    }
+
+    /// A publish to actor 0. The graph must have been subscribed on `runner`.
+    pub fn root_publish(&self) -> Task {
+        Task::Publish(PublishTask::new(Self::topic(0), Rc::new(())))
+    }
+
+    /// A send to actor 0. The graph must have been registered on `runner`.
+    pub fn root(&self, runner: &TaskRunner) -> Task {
+        let topic = Self::topic(0);
+        let sub = runner.msg_bus.endpoint(&topic).expect("graph is registered");
+        Task::Send(SendTask::for_subscription(sub, topic, Rc::new(())))
+    }
+
+    /// How many times each actor runs when the flow starts at actor 0.
+    pub fn invocations(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.edges.len()];
+        if let Some(root) = counts.first_mut() {
+            *root = 1;
+        }
+        // Edges point forward, so every caller's count is final before its targets'.
+        for (actor, targets) in self.edges.iter().enumerate() {
+            for &target in targets {
+                counts[target] += counts[actor];
+            }
+        }
+        counts
+    }
+}
+
+/// Generates acyclic graphs of 1 to `max_actors` actors, each sending to at most
+/// `max_fanout` later actors.
+pub fn acyclic_graph(max_actors: usize, max_fanout: usize) -> impl Strategy<Value = ActorGraph> {
+    (1..=max_actors).prop_flat_map(move |actors| {
+        prop::collection::vec(
+            prop::collection::vec(any::<prop::sample::Index>(), 0..=max_fanout),
+            actors,
+        )
+        .prop_map(move |targets| ActorGraph {
+            edges: targets
+                .into_iter()
+                .enumerate()
+                .map(|(actor, targets)| {
+                    let later = actors - actor - 1;
+                    if later == 0 {
+                        return Vec::new();
+                    }
+                    targets
+                        .iter()
+                        .map(|index| actor + 1 + index.index(later))
+                        .collect()
+                })
+                .collect(),
+        })
+    })
+}
+
+/// Generates topics of 1 to `max_segments` segments drawn from a small set that includes
+/// wildcards and empty segments, so patterns and topics often share segments.
+pub fn topic(max_segments: usize) -> impl Strategy<Value = String> {
+    joined_segments(&["", "a", "b", "ab", "*", "#", "a*", "#b"], max_segments)
+}
+
+/// Generates topics like `topic` does, but without wildcards.
+pub fn literal_topic(max_segments: usize) -> impl Strategy<Value = String> {
+    joined_segments(&["", "a", "b", "ab"], max_segments)
+}
+
+fn joined_segments(
+    segments: &'static [&'static str],
+    max_segments: usize,
+) -> impl Strategy<Value = String> {
+    prop::collection::vec(prop::sample::select(segments), 1..=max_segments)
+        .prop_map(|segments| segments.join("."))
+}
+
+/// The number of topics `soak` churns routes on, `soak.0` to `soak.5`.
+const SOAK_TOPICS: usize = 6;
+/// The number of handler IDs `soak` registers and subscribes under.
+const SOAK_HANDLERS: usize = 4;
+
+/// Randomly registers, deregisters, subscribes, unsubscribes, sends and publishes on the
+/// topics `soak.0` to `soak.5` and the pattern `soak.*`, for `iterations` operations
+/// chosen by a PRNG seeded with `seed`, so a failing run can be repeated. A handler on
+/// `soak.i` only sends and publishes to higher-numbered topics, so every flow ends as
+/// long as `runner` starts with no routes of its own on those topics.
+///
+/// Panics if after any operation the bus's routes differ from those the operations so
+/// far should have left, a pattern's subscriber count differs from the number of live
+/// handlers subscribed to match it, or tasks are left pending.
+pub fn soak(runner: &mut TaskRunner, iterations: usize, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let trace = Trace::default();
+    let routing = runner.msg_bus.clone_routing();
+    let mut endpoints: BTreeSet<RouteInfo> = routing.endpoints.into_iter().collect();
+    let mut subscriptions: BTreeSet<RouteInfo> = routing.subscriptions.into_iter().collect();
+    let patterns: Vec<String> = (0..SOAK_TOPICS)
+        .map(soak_topic)
+        .chain(["soak.*".to_string()])
+        .collect();
+
+    for iteration in 0..iterations {
+        let topic = rng.random_range(0..SOAK_TOPICS);
+        let handler_id = format!("soak_{}", rng.random_range(0..SOAK_HANDLERS));
+        match rng.random_range(0..6) {
+            0 => {
+                let sub = soak_handler(&mut rng, Some(topic), handler_id, &trace);
+                replace_route(&mut endpoints, RouteInfo::from(&sub));
+                runner.msg_bus.register(sub);
+            }
+            1 => {
+                let topic = soak_topic(topic);
+                endpoints.retain(|route| route.topic != topic);
+                runner.msg_bus.deregister(&topic);
+            }
+            2 => {
+                let topic = (!rng.random_bool(0.25)).then_some(topic);
+                let sub = soak_handler(&mut rng, topic, handler_id, &trace);
+                replace_route(&mut subscriptions, RouteInfo::from(&sub));
+                runner.msg_bus.subscribe(sub);
+            }
+            3 => {
+                let topic = &patterns[rng.random_range(0..patterns.len())];
+                subscriptions
+                    .retain(|route| route.topic != *topic || route.handler_id != handler_id);
+                runner.msg_bus.remove_subscription(topic, &handler_id);
+            }
+            4 => {
+                runner.request(soak_topic(topic), Rc::new(iteration));
+            }
+            _ => {
+                let publish = PublishTask::new(soak_topic(topic), Rc::new(iteration));
+                runner.push(Task::Publish(publish));
+                runner.run();
+            }
+        }
+        trace.borrow_mut().clear();
+
+        assert!(
+            runner.tasks.is_empty(),
+            "iteration {iteration} left tasks pending"
+        );
+        let routing = runner.msg_bus.clone_routing();
+        assert!(
+            routing.endpoints.iter().eq(&endpoints),
+            "iteration {iteration} left endpoints {:?}",
+            routing.endpoints
+        );
+        assert!(
+            routing.subscriptions.iter().eq(&subscriptions),
+            "iteration {iteration} left subscriptions {:?}",
+            routing.subscriptions
+        );
+        for pattern in &patterns {
+            let reached: BTreeSet<&str> = subscriptions
+                .iter()
+                .filter(|route| runner.msg_bus.matches(pattern, &route.topic))
+                .map(|route| route.handler_id.as_str())
+                .collect();
+            assert_eq!(
+                runner.msg_bus.subscriber_count(pattern),
+                reached.len(),
+                "iteration {iteration} miscounted the subscribers of {pattern}"
+            );
+        }
+    }
+}
+
+fn soak_topic(topic: usize) -> String {
+    format!("soak.{topic}")
+}
+
+/// A handler with a random priority, on `soak.{topic}` sending and publishing to a few
+/// higher-numbered topics, or on `soak.*` doing nothing.
+fn soak_handler(
+    rng: &mut StdRng,
+    topic: Option<usize>,
+    handler_id: String,
+    trace: &Trace,
+) -> Subscription {
+    let mut actions = Vec::new();
+    if let Some(topic) = topic.filter(|topic| topic + 1 < SOAK_TOPICS) {
+        for _ in 0..rng.random_range(0..=2) {
+            let to = soak_topic(rng.random_range(topic + 1..SOAK_TOPICS));
+            actions.push(match rng.random_bool(0.5) {
+                true => ActorAction::Send(to),
+                false => ActorAction::Publish(to),
+            });
+        }
+    }
+    let topic = topic.map_or_else(|| "soak.*".to_string(), soak_topic);
+    let mut sub = create_actor_handler(handler_id, topic, actions, trace.clone());
+    sub.priority = rng.random_range(0..4);
+    sub
+}
+
+/// Adds `route`, replacing the route of the same handler ID on the same topic.
+fn replace_route(routes: &mut BTreeSet<RouteInfo>, route: RouteInfo) {
+    routes.retain(|old| old.topic != route.topic || old.handler_id != route.handler_id);
+    routes.insert(route);
+}
@@ -0,0 +1,2803 @@
+use super::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Test 1: Register an endpoint, send messages, then deregister.
+#[test]
+fn test_register_deregister_and_send() {
+    let counter = Rc::new(RefCell::new(0));
+    let counter_clone = counter.clone();
+
+    let mut bus = MessageBus::new();
+
+    // Register an endpoint which increments our counter.
+    bus.register(Subscription::new("endpoint_topic", "ep1", move || {
+        let counter = counter.clone();
+        Box::pin(
+            #[coroutine]
+            move |_msg: Envelope| {
+                *counter.borrow_mut() += 1;
+                Ok(None)
+            },
+        )
+    }));
+
+    // Send a message and run.
+    let task = Task::Send(SendTask::new(
+        "endpoint_topic".to_string(),
+        (bus.endpoints["endpoint_topic"][0].actor_fn)(),
+        Rc::new(()),
+    ));
+    let mut runner = TaskRunner::new();
+    runner.push(task);
+    runner.run();
+    assert_eq!(*counter_clone.borrow(), 1);
+
+    // Now deregister the endpoint and try to send another message.
+    bus.deregister("endpoint_topic");
+    // Message won't be delivered since endpoint is gone
+    assert_eq!(*counter_clone.borrow(), 1);
+}
+
+/// Test 2: Subscribe multiple handlers, send messages, unsubscribe one and test interleaving.
+#[test]
+fn test_subscribe_multiple_and_unsubscribe() {
+    let counter1 = Rc::new(RefCell::new(0));
+    let counter2 = Rc::new(RefCell::new(0));
+    let sub_counter1 = counter1.clone();
+    let sub_counter2 = counter2.clone();
+
+    let mut runner = TaskRunner::new();
+
+    // Register two subscriptions on the same topic.
+    runner
+        .msg_bus
+        .subscribe(Subscription::new("pubsub_topic", "sub1", move || {
+            let value = sub_counter1.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *value.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+    runner
+        .msg_bus
+        .subscribe(Subscription::new("pubsub_topic", "sub2", move || {
+            let value = sub_counter2.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *value.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+
+    // Send a message; both subscriptions should process it.
+    runner.push(Task::Publish(PublishTask::new(
+        "pubsub_topic".to_string(),
+        Rc::new(()),
+    )));
+    runner.run();
+    assert_eq!(*counter1.borrow(), 1);
+    assert_eq!(*counter2.borrow(), 1);
+
+    // Unsubscribe the first subscription.
+    runner.msg_bus.remove_subscription("pubsub_topic", "sub1");
+
+    // Send another message; only the second subscription should process it.
+    runner.push(Task::Publish(PublishTask::new(
+        "pubsub_topic".to_string(),
+        Rc::new(()),
+    )));
+    runner.run();
+    assert_eq!(*counter1.borrow(), 1);
+    assert_eq!(*counter2.borrow(), 2);
+}
+
+/// Test 3: Subscribe several handlers at once from an iterator.
+#[test]
+fn test_subscribe_all() {
+    let mut bus = MessageBus::new();
+
+    bus.subscribe_all(["sub1", "sub2", "sub3"].into_iter().map(|handler_id| {
+        Subscription::new("bulk_topic", handler_id, || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| Ok(None),
+            )
+        })
+    }));
+
+    assert_eq!(bus.subscriptions.len(), 3);
+    for handler_id in ["sub1", "sub2", "sub3"] {
+        assert!(bus
+            .subscription_topics()
+            .any(|sub| sub == ("bulk_topic", handler_id)));
+    }
+}
+
+fn noop_subscription(topic: &str, handler_id: &str) -> Subscription {
+    Subscription::new(topic, handler_id, || {
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| Ok(None),
+        )
+    })
+}
+
+/// Test 4: Classify topics by endpoint and subscription presence.
+#[test]
+fn test_route_kind() {
+    let mut bus = MessageBus::new();
+    bus.register(noop_subscription("endpoint_only", "ep1"));
+    bus.subscribe(noop_subscription("subscription_only", "sub1"));
+    bus.register(noop_subscription("both", "ep2"));
+    bus.subscribe(noop_subscription("both", "sub2"));
+
+    assert_eq!(bus.route_kind("unknown"), RouteKind::None);
+    assert_eq!(bus.route_kind("endpoint_only"), RouteKind::Endpoint);
+    assert_eq!(bus.route_kind("subscription_only"), RouteKind::Subscription);
+    assert_eq!(bus.route_kind("both"), RouteKind::Both);
+}
+
+/// Test 5: Snapshot a bus's routing and rebuild it on a fresh bus.
+#[test]
+fn test_clone_and_apply_routing() {
+    let mut bus = MessageBus::new();
+    bus.register(noop_subscription("endpoint_topic", "ep1").with_priority(3));
+    bus.subscribe(noop_subscription("pubsub_topic", "sub1"));
+    bus.subscribe(noop_subscription("pubsub_topic", "sub2").with_priority(7));
+
+    let snapshot = bus.clone_routing();
+
+    let mut rebuilt = MessageBus::new();
+    rebuilt.apply_routing(&snapshot, |_route| {
+        Box::new(|| {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| Ok(None),
+            )
+        })
+    });
+
+    assert_eq!(rebuilt.clone_routing(), snapshot);
+    assert_eq!(snapshot.endpoints.len(), 1);
+    assert_eq!(snapshot.subscriptions.len(), 2);
+    assert_eq!(snapshot.subscriptions[1].priority, 7);
+}
+
+struct Topics;
+
+impl Topics {
+    const PING: Topic = topic!("ping");
+    const PONG: Topic = topic!("pong");
+}
+
+/// Test 6: Route a message end-to-end using `const` topic declarations.
+#[test]
+fn test_const_topics_route() {
+    let pongs = Rc::new(RefCell::new(0));
+    let pongs_clone = pongs.clone();
+    let mut runner = TaskRunner::new();
+
+    runner
+        .msg_bus
+        .register(Subscription::new(Topics::PING, "pinger", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::Send {
+                        topic: Topics::PONG,
+                        msg: Rc::new(()),
+                    };
+                    Ok(None)
+                },
+            )
+        }));
+    runner
+        .msg_bus
+        .register(Subscription::new(Topics::PONG, "ponger", move || {
+            let pongs = pongs_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *pongs.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        Topics::PING,
+        (runner.msg_bus.endpoints[Topics::PING.as_str()][0].actor_fn)(),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*pongs.borrow(), 1);
+}
+
+/// Test 7: A top-N publish only reaches the highest-priority subscribers.
+#[test]
+fn test_publish_top_n() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+
+    for priority in 1..=5u8 {
+        let delivered = delivered.clone();
+        runner.msg_bus.subscribe(
+            Subscription::new("canary", format!("sub{}", priority), move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(priority);
+                        Ok(None)
+                    },
+                )
+            })
+            .with_priority(priority),
+        );
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::PublishTopN {
+                    pattern: "canary".into(),
+                    msg: Rc::new(()),
+                    n: 2,
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*delivered.borrow(), vec![5, 4]);
+}
+
+/// Test 8: An endpoint deregistering itself finishes its current delivery, but
+/// later sends to it are dead-lettered.
+#[test]
+fn test_deregister_during_send() {
+    let b_runs = Rc::new(RefCell::new(0));
+    let b_completed = Rc::new(RefCell::new(0));
+    let mut runner = TaskRunner::new();
+
+    runner
+        .msg_bus
+        .register(Subscription::new("topic_a", "A", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    for _ in 0..2 {
+                        yield Command::Send {
+                            topic: "topic_b".into(),
+                            msg: Rc::new(()),
+                        };
+                    }
+                    Ok(None)
+                },
+            )
+        }));
+    let (runs, completed) = (b_runs.clone(), b_completed.clone());
+    runner
+        .msg_bus
+        .register(Subscription::new("topic_b", "B", move || {
+            let (runs, completed) = (runs.clone(), completed.clone());
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *runs.borrow_mut() += 1;
+                    yield Command::Deregister("topic_b".into());
+                    *completed.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        "topic_a",
+        (runner.msg_bus.endpoints["topic_a"][0].actor_fn)(),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*b_runs.borrow(), 1);
+    assert_eq!(*b_completed.borrow(), 1);
+    assert_eq!(runner.dead_letters.len(), 1);
+    assert_eq!(runner.dead_letters[0].topic, "topic_b");
+}
+
+/// Test 9: A publish report counts successful and failed handlers.
+#[test]
+fn test_publish_report() {
+    let mut runner = TaskRunner::new();
+    for (handler_id, fails) in [("ok1", false), ("bad", true), ("ok2", false)] {
+        runner
+            .msg_bus
+            .subscribe(Subscription::new("reports", handler_id, move || {
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        if fails {
+                            return Err(HandlerError("rejected".to_string()));
+                        }
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.push(Task::Publish(PublishTask::new("reports", Rc::new(()))));
+    runner.run();
+
+    assert_eq!(
+        runner.publish_reports,
+        vec![PublishReport {
+            pattern: "reports".into(),
+            delivered: 2,
+            failed: 1,
+            skipped: Vec::new(),
+        }]
+    );
+}
+
+/// Test 10: A custom matcher replaces exact topic matching.
+#[test]
+fn test_custom_matcher() {
+    let counter = Rc::new(RefCell::new(0));
+    let counter_clone = counter.clone();
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .set_matcher(Box::new(|pattern, topic| pattern.eq_ignore_ascii_case(topic)));
+    runner
+        .msg_bus
+        .subscribe(Subscription::new("events", "sub1", move || {
+            let counter = counter_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *counter.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+
+    assert_eq!(runner.msg_bus.subscriber_count("EVENTS"), 1);
+    runner.push(Task::Publish(PublishTask::new("EVENTS", Rc::new(()))));
+    runner.run();
+
+    assert_eq!(*counter.borrow(), 1);
+}
+
+/// Test 11: A handler that keeps yielding is cut off after `max_resumes`.
+#[test]
+fn test_max_resumes() {
+    let sunk = Rc::new(RefCell::new(0));
+    let sunk_clone = sunk.clone();
+    let mut runner = TaskRunner::new();
+    runner.max_resumes = Some(10);
+    runner
+        .msg_bus
+        .register(Subscription::new("sink", "sink", move || {
+            let sunk = sunk_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *sunk.borrow_mut() += 1;
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        "runaway",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                for _ in 0..1000 {
+                    yield Command::Send {
+                        topic: "sink".into(),
+                        msg: Rc::new(()),
+                    };
+                }
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*sunk.borrow(), 10);
+    assert_eq!(
+        runner.warnings,
+        vec![RunnerWarning::RunawayHandler {
+            topic: "runaway".into(),
+            handler_id: String::new(),
+            resumes: 10,
+        }]
+    );
+}
+
+
+/// Test 12: Register through `endpoint_entry` only when the slot is vacant.
+#[test]
+fn test_endpoint_entry() {
+    let mut bus = MessageBus::new();
+    for handler_id in ["first", "second"] {
+        match bus.endpoint_entry("topic") {
+            EndpointEntry::Vacant(entry) => {
+                entry.register(noop_subscription("topic", handler_id));
+            }
+            EndpointEntry::Occupied(entry) => {
+                assert_eq!(entry.get().handler_id, "first");
+            }
+        }
+    }
+
+    match bus.endpoint_entry("topic") {
+        EndpointEntry::Occupied(entry) => assert_eq!(entry.get().handler_id, "first"),
+        EndpointEntry::Vacant(_) => panic!("endpoint should be registered"),
+    }
+    assert_eq!(bus.clone_routing().endpoints.len(), 1);
+}
+
+
+/// Test 13: Each root send starts its own flow and the flow ends once it drains.
+#[test]
+fn test_active_flows() {
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.register(noop_subscription("sink", "sink"));
+    for _ in 0..2 {
+        runner.push(Task::Send(SendTask::new(
+            "root",
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::Send {
+                        topic: "sink".into(),
+                        msg: Rc::new(()),
+                    };
+                    Ok(None)
+                },
+            ),
+            Rc::new(()),
+        )));
+    }
+    let ids: Vec<_> = runner.tasks.iter().map(Task::correlation_id).collect();
+    assert_ne!(ids[0], ids[1]);
+
+    runner.step();
+    assert_eq!(runner.tasks.len(), 3);
+    assert_eq!(runner.active_flows(), 2);
+
+    runner.run();
+    assert_eq!(runner.active_flows(), 0);
+}
+
+
+/// Test 14: A wildcard subscriber sees the concrete topic it was published on.
+#[test]
+fn test_wildcard_matched_topic() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let seen_clone = seen.clone();
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.subscribe(Subscription::new(
+        "sensors.*",
+        "sensor_listener",
+        move || {
+            let seen = seen_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |env: Envelope| {
+                    seen.borrow_mut()
+                        .push((env.matched_topic.to_string(), env.handler_id.clone()));
+                    Ok(None)
+                },
+            )
+        },
+    ));
+
+    runner.push(Task::Publish(PublishTask::new("sensors.temp", Rc::new(()))));
+    runner.push(Task::Publish(PublishTask::new("actuators.valve", Rc::new(()))));
+    runner.run();
+
+    assert_eq!(
+        *seen.borrow(),
+        vec![("sensors.temp".to_string(), "sensor_listener".to_string())]
+    );
+    assert!(topic_matches("sensors.temp.max", "sensors.#"));
+    assert!(!topic_matches("sensors.temp.max", "sensors.*"));
+}
+
+
+/// Test 15: `request` runs to completion and returns the endpoint's reply.
+#[test]
+fn test_request() {
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("answer", "answerer", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::Send {
+                        topic: "missing".into(),
+                        msg: Rc::new(()),
+                    };
+                    Ok(Some(Rc::new(7usize) as Rc<dyn Any>))
+                },
+            )
+        }));
+
+    let reply = runner.request("answer", Rc::new(())).expect("handler should reply");
+    assert_eq!(reply.downcast_ref::<usize>(), Some(&7));
+    assert!(runner.tasks.is_empty());
+    assert_eq!(runner.dead_letters.len(), 1);
+
+    assert!(runner.request("missing", Rc::new(())).is_none());
+}
+
+
+/// Test 16: Remove all of a handler's endpoints and subscriptions at once.
+#[test]
+fn test_remove_handler() {
+    let mut bus = MessageBus::new();
+    for topic in ["a", "b", "c"] {
+        bus.register(noop_subscription(topic, "comp1"));
+    }
+    for topic in ["x", "y"] {
+        bus.subscribe(noop_subscription(topic, "comp1"));
+    }
+    bus.register(noop_subscription("d", "comp2"));
+    bus.subscribe(noop_subscription("x", "comp2"));
+
+    assert_eq!(bus.remove_handler("comp1"), 5);
+
+    let routing = bus.clone_routing();
+    assert!(routing
+        .endpoints
+        .iter()
+        .chain(&routing.subscriptions)
+        .all(|route| route.handler_id == "comp2"));
+    assert_eq!(routing.endpoints.len() + routing.subscriptions.len(), 2);
+    assert_eq!(bus.remove_handler("comp1"), 0);
+}
+
+
+/// Test 17: Export handler timings as Chrome trace events, in ticks of the runner's clock.
+#[cfg(feature = "timing")]
+#[test]
+fn test_trace_to_chrome_json() {
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("leaf", "leaf", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| Ok(None),
+            )
+        }));
+    runner
+        .msg_bus
+        .register(Subscription::new("root", "root", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    for _ in 0..2 {
+                        yield Command::Send {
+                            topic: "leaf".into(),
+                            msg: Rc::new(()),
+                        };
+                    }
+                    Ok(None)
+                },
+            )
+        }));
+    runner.request("root", Rc::new(()));
+
+    let trace: serde_json::Value = serde_json::from_str(&runner.trace_to_chrome_json()).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+    let names: Vec<_> = events.iter().map(|e| e["name"].as_str().unwrap()).collect();
+    assert_eq!(names, vec!["leaf", "leaf", "root"]);
+    // With the default `LogicalClock`, each leaf starts and completes within one step,
+    // while the root completes four ticks after its first resume.
+    let spans: Vec<_> = events
+        .iter()
+        .map(|e| (e["ts"].as_u64().unwrap(), e["dur"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(spans, vec![(1, 0), (3, 0), (0, 4)]);
+    for event in events {
+        assert_eq!(event["ph"], "X");
+    }
+}
+
+
+/// Test 18: An owned payload is moved into the handler rather than shared.
+#[test]
+fn test_send_owned() {
+    struct Payload {
+        data: Vec<u8>,
+        drops: Rc<RefCell<usize>>,
+    }
+
+    impl Drop for Payload {
+        fn drop(&mut self) {
+            *self.drops.borrow_mut() += 1;
+        }
+    }
+
+    let drops = Rc::new(RefCell::new(0));
+    let received = Rc::new(RefCell::new(0));
+    let received_clone = received.clone();
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("bulk", "bulk", move || {
+            let received = received_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |mut env: Envelope| {
+                    let payload = env.take_owned::<Payload>().expect("owned payload");
+                    *received.borrow_mut() = payload.data.len();
+                    Ok(None)
+                },
+            )
+        }));
+
+    let payload = Payload {
+        data: vec![0; 1 << 20],
+        drops: drops.clone(),
+    };
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |_msg: Envelope| {
+                yield Command::SendOwned {
+                    topic: "bulk".into(),
+                    msg: Box::new(payload),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*received.borrow(), 1 << 20);
+    assert_eq!(*drops.borrow(), 1);
+}
+
+
+/// Test 19: `SendBest` reaches only the highest-priority of several endpoints.
+#[test]
+fn test_send_best() {
+    let ran = Rc::new(RefCell::new(Vec::new()));
+    let endpoint = |handler_id: &'static str, priority: u8| {
+        let ran = ran.clone();
+        Subscription::new("work", handler_id, move || {
+            let ran = ran.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    ran.borrow_mut().push(handler_id);
+                    Ok(None)
+                },
+            )
+        })
+        .with_priority(priority)
+    };
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.register(endpoint("low", 1));
+    runner.msg_bus.register(endpoint("high", 2));
+
+    for best in [true, false] {
+        runner.push(Task::Send(SendTask::new(
+            "root",
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    let topic: Topic = "work".into();
+                    let msg: Rc<dyn Any> = Rc::new(());
+                    yield if best {
+                        Command::SendBest { topic, msg }
+                    } else {
+                        Command::Send { topic, msg }
+                    };
+                    Ok(None)
+                },
+            ),
+            Rc::new(()),
+        )));
+        runner.run();
+    }
+
+    assert_eq!(*ran.borrow(), vec!["high", "high", "low"]);
+}
+
+
+/// Test 20: A handler queries the bus and branches on the answer.
+#[test]
+fn test_query_subscriber_count() {
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.subscribe(noop_subscription("x", "sub1"));
+    runner.msg_bus.subscribe(noop_subscription("x", "sub2"));
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                let reply = Rc::new(RefCell::new(None));
+                yield Command::Query {
+                    query: BusQuery::SubscriberCount("x".into()),
+                    reply: reply.clone(),
+                };
+                let answer = reply.borrow_mut().take();
+                let topic = match answer {
+                    Some(BusAnswer::SubscriberCount(2)) => "two",
+                    _ => "other",
+                };
+                yield Command::Send {
+                    topic: topic.into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    let topics: Vec<_> = runner
+        .dead_letters
+        .iter()
+        .map(|letter| letter.topic.as_str())
+        .collect();
+    assert_eq!(topics, vec!["two"]);
+}
+
+
+/// Test 21: With a `QueueStore`, spawned tasks run in the order they were spawned.
+#[test]
+fn test_queue_store_order() {
+    use crate::testing::{create_actor_handler, ActorAction, TraceEvent};
+
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::with_store(QueueStore::default());
+    for (id, targets) in [
+        ("A", vec!["B", "C"]),
+        ("B", vec!["D"]),
+        ("C", vec!["E"]),
+        ("D", vec![]),
+        ("E", vec![]),
+    ] {
+        let actions = targets
+            .into_iter()
+            .map(|target| ActorAction::Send(target.to_string()))
+            .collect();
+        runner.msg_bus.register(create_actor_handler(
+            id.to_string(),
+            id.to_string(),
+            actions,
+            trace.clone(),
+        ));
+    }
+    let root = SendTask::for_subscription(&runner.msg_bus.endpoints["A"][0], "A", Rc::new(()));
+    runner.push(Task::Send(root));
+    runner.run();
+
+    let expected: Vec<_> = ["A", "B", "C", "D", "E"]
+        .into_iter()
+        .flat_map(|id| [TraceEvent::Enter(id.to_string()), TraceEvent::Exit(id.to_string())])
+        .collect();
+    assert_eq!(*trace.borrow(), expected);
+}
+
+
+/// Test 22: The runner's transform rewrites the payload on every hop.
+#[test]
+fn test_transform() {
+    let seen = Rc::new(RefCell::new(None));
+    let mut runner = TaskRunner::new();
+    runner.transform = Some(Box::new(|msg| {
+        let value = *msg.downcast_ref::<u32>().unwrap();
+        Rc::new(value + 1)
+    }));
+    for (topic, next) in [("hop1", Some("hop2")), ("hop2", Some("hop3")), ("hop3", None)] {
+        let seen = seen.clone();
+        runner
+            .msg_bus
+            .register(Subscription::new(topic, topic, move || {
+                let seen = seen.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |env: Envelope| {
+                        match next {
+                            Some(next) => {
+                                yield Command::Send {
+                                    topic: next.into(),
+                                    msg: env.msg.clone(),
+                                };
+                            }
+                            None => *seen.borrow_mut() = env.downcast_ref::<u32>().copied(),
+                        }
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Send {
+                    topic: "hop1".into(),
+                    msg: Rc::new(10u32),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*seen.borrow(), Some(13));
+}
+
+
+/// Test 23: Publishing to the empty pattern reaches every subscription once.
+#[test]
+fn test_empty_pattern_broadcast() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for topic in ["orders", "sensors.temp", "audit"] {
+        let delivered = delivered.clone();
+        runner.msg_bus.subscribe(Subscription::new(
+            topic,
+            format!("{topic}_listener"),
+            move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(topic);
+                        Ok(None)
+                    },
+                )
+            },
+        ));
+    }
+
+    runner.push(Task::Publish(PublishTask::new("", Rc::new(()))));
+    runner.run();
+
+    let mut delivered = delivered.take();
+    delivered.sort();
+    assert_eq!(delivered, vec!["audit", "orders", "sensors.temp"]);
+}
+
+/// Test 24: A stateful handler's state persists across deliveries.
+#[test]
+fn test_stateful_subscription() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let seen_clone = seen.clone();
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.register_stateful(StatefulSubscription {
+        actor_fn: Box::new(move |count: &mut u32| {
+            *count += 1;
+            let count = *count;
+            let seen = seen_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    seen.borrow_mut().push(count);
+                    Ok(None)
+                },
+            )
+        }),
+        handler_id: "counter".to_string(),
+        topic: "count".into(),
+        priority: 0,
+    });
+
+    for _ in 0..3 {
+        runner.request("count", Rc::new(()));
+    }
+
+    assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+    let state = runner.msg_bus.state::<u32>("counter").unwrap();
+    assert_eq!(*state.borrow(), 3);
+    assert!(runner.msg_bus.state::<String>("counter").is_none());
+}
+
+/// Test 25: A large publish reports its delivery progress.
+#[test]
+fn test_publish_progress() {
+    let mut runner = TaskRunner::new();
+    for i in 0..100 {
+        runner
+            .msg_bus
+            .subscribe(noop_subscription("fanout", &format!("sub{i}")));
+    }
+    runner.push(Task::Publish(PublishTask::new("fanout", Rc::new(()))));
+    assert_eq!(runner.pending()[0].progress, Some((0, 0)));
+
+    // Each delivery takes one step to spawn the send and one to complete it.
+    for _ in 0..60 {
+        runner.step();
+    }
+
+    let pending = runner.pending();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].kind, "Publish");
+    assert_eq!(pending[0].progress, Some((30, 100)));
+}
+
+/// Test 26: A send to a subscription-only topic follows the runner's `SendFallback`.
+#[test]
+fn test_send_fallback() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let sender = || {
+        Task::Send(SendTask::new(
+            "sender",
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::Send {
+                        topic: "events".into(),
+                        msg: Rc::new(()),
+                    };
+                    Ok(None)
+                },
+            ),
+            Rc::new(()),
+        ))
+    };
+
+    let mut runner = TaskRunner::new();
+    runner.send_fallback = SendFallback::FanOutToSubscribers;
+    for handler_id in ["sub1", "sub2"] {
+        let delivered = delivered.clone();
+        runner
+            .msg_bus
+            .subscribe(Subscription::new("events", handler_id, move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(handler_id);
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+    runner.push(sender());
+    runner.run();
+
+    assert_eq!(*delivered.borrow(), vec!["sub1", "sub2"]);
+    assert!(runner.dead_letters.is_empty());
+
+    let mut runner = TaskRunner::new();
+    runner.send_fallback = SendFallback::Error;
+    runner.push(sender());
+    runner.run();
+
+    assert!(runner.dead_letters.is_empty());
+    assert_eq!(
+        runner.warnings,
+        vec![RunnerWarning::UndeliverableSend {
+            topic: "events".into()
+        }]
+    );
+}
+
+/// Test 27: Draining dead letters yields each once and empties the list.
+#[test]
+fn test_drain_dead_letters() {
+    let mut runner = TaskRunner::new();
+    runner.push(Task::Send(SendTask::new(
+        "sender",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                for (topic, value) in [("lost1", 1u32), ("lost2", 2)] {
+                    yield Command::Send {
+                        topic: topic.into(),
+                        msg: Rc::new(value),
+                    };
+                }
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    let drained: Vec<_> = runner
+        .drain_dead_letters()
+        .map(|letter| {
+            let value = *letter.msg.downcast_ref::<u32>().unwrap();
+            (letter.topic.to_string(), value)
+        })
+        .collect();
+    assert_eq!(
+        drained,
+        vec![("lost1".to_string(), 1), ("lost2".to_string(), 2)]
+    );
+    assert!(runner.dead_letters.is_empty());
+}
+
+/// Test 28: Topic middleware rejects malformed payments only.
+#[test]
+fn test_topic_middleware() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for topic in ["payments.card", "orders"] {
+        let delivered = delivered.clone();
+        runner
+            .msg_bus
+            .register(Subscription::new(topic, topic, move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |env: Envelope| {
+                        delivered
+                            .borrow_mut()
+                            .push((topic, *env.downcast_ref::<i64>().unwrap()));
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+    let commands = Rc::new(RefCell::new(0));
+    let commands_clone = commands.clone();
+    runner.add_middleware(Box::new(move |_cmd| {
+        *commands_clone.borrow_mut() += 1;
+        true
+    }));
+    runner.add_topic_middleware(
+        "payments.*",
+        Box::new(|cmd| match cmd {
+            Command::Send { msg, .. } => msg.downcast_ref::<i64>().is_some_and(|&v| v > 0),
+            _ => true,
+        }),
+    );
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                for (topic, amount) in [
+                    ("payments.card", -5i64),
+                    ("payments.card", 10),
+                    ("orders", -1),
+                ] {
+                    yield Command::Send {
+                        topic: topic.into(),
+                        msg: Rc::new(amount),
+                    };
+                }
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(
+        *delivered.borrow(),
+        vec![("payments.card", 10), ("orders", -1)]
+    );
+    assert_eq!(*commands.borrow(), 3);
+}
+
+// Counts the messages it receives, sending each running count to `counted`.
+#[derive(Default)]
+struct Counter {
+    count: usize,
+}
+
+impl Actor for Counter {
+    fn handle(&mut self, _msg: &Envelope) -> Vec<Command> {
+        self.count += 1;
+        vec![Command::Send {
+            topic: "counted".into(),
+            msg: Rc::new(self.count),
+        }]
+    }
+}
+
+/// Test 29: A struct actor keeps its count across deliveries and yields its commands.
+#[test]
+fn test_register_actor() {
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register_actor("count", "counter", Counter::default());
+    let counted = Rc::new(RefCell::new(Vec::new()));
+    let counted_clone = counted.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("counted", "recorder", move || {
+            let counted = counted_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |env: Envelope| {
+                    counted
+                        .borrow_mut()
+                        .push(*env.downcast_ref::<usize>().unwrap());
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                for _ in 0..3 {
+                    yield Command::Send {
+                        topic: "count".into(),
+                        msg: Rc::new(()),
+                    };
+                }
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*counted.borrow(), vec![1, 2, 3]);
+    let counter = runner.msg_bus.state::<Counter>("counter").unwrap();
+    assert_eq!(counter.borrow().count, 3);
+}
+
+/// Test 30: Subscription and registration checks track the bus's routes.
+#[test]
+fn test_is_subscribed_and_registered() {
+    let mut bus = MessageBus::new();
+    assert!(!bus.is_subscribed("prices", "sub1"));
+    bus.subscribe(noop_subscription("prices", "sub1"));
+    assert!(bus.is_subscribed("prices", "sub1"));
+    assert!(!bus.is_subscribed("prices", "sub2"));
+    assert!(!bus.is_subscribed("orders", "sub1"));
+    bus.remove_subscription("prices", "sub1");
+    assert!(!bus.is_subscribed("prices", "sub1"));
+
+    assert!(!bus.is_registered("orders"));
+    bus.register(noop_subscription("orders", "ep1"));
+    assert!(bus.is_registered("orders"));
+    assert!(!bus.is_registered("prices"));
+    bus.deregister("orders");
+    assert!(!bus.is_registered("orders"));
+}
+
+/// Test 31: A tagged byte payload is rebuilt as its registered type before delivery.
+#[test]
+fn test_send_tagged() {
+    const U32_TAG: u32 = 7;
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received_clone = received.clone();
+    let mut runner = TaskRunner::new();
+    runner.msg_bus.register_type::<u32>(U32_TAG, |bytes| {
+        Rc::new(u32::from_le_bytes(bytes.try_into().unwrap()))
+    });
+    runner
+        .msg_bus
+        .register(Subscription::new("ffi", "ffi_handler", move || {
+            let received = received_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |env: Envelope| {
+                    received
+                        .borrow_mut()
+                        .push(*env.downcast_ref::<u32>().unwrap());
+                    Ok(None)
+                },
+            )
+        }));
+
+    assert!(runner.send_tagged("ffi", U32_TAG, &0xdead_beef_u32.to_le_bytes()));
+    runner.run();
+    assert_eq!(*received.borrow(), vec![0xdead_beef]);
+
+    // Unknown tags are rejected without pushing anything.
+    assert!(!runner.send_tagged("ffi", 8, &[0; 4]));
+    assert!(runner.tasks.is_empty());
+
+    // A `from_bytes` that builds another type than registered is rejected too.
+    runner
+        .msg_bus
+        .register_type::<u64>(9, |bytes| Rc::new(bytes.len() as u32));
+    assert!(runner.msg_bus.decode(9, &[0; 4]).is_none());
+}
+
+/// Test 32: Disabling a group stops its subscribers receiving publishes until re-enabled.
+#[test]
+fn test_subscription_groups() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for (handler_id, group) in [
+        ("quote_logger", "audit"),
+        ("quote_archiver", "audit"),
+        ("quote_pricer", "pricing"),
+    ] {
+        let delivered = delivered.clone();
+        runner.msg_bus.subscribe(
+            Subscription::new("quotes", handler_id, move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(handler_id);
+                        Ok(None)
+                    },
+                )
+            })
+            .with_group(group),
+        );
+    }
+
+    runner.msg_bus.set_group_enabled("audit", false);
+    runner.push(Task::Publish(PublishTask::new("quotes", Rc::new(()))));
+    runner.run();
+    assert_eq!(*delivered.borrow(), vec!["quote_pricer"]);
+
+    delivered.borrow_mut().clear();
+    runner.msg_bus.set_group_enabled("audit", true);
+    runner.push(Task::Publish(PublishTask::new("quotes", Rc::new(()))));
+    runner.run();
+    assert_eq!(
+        *delivered.borrow(),
+        vec!["quote_archiver", "quote_logger", "quote_pricer"]
+    );
+}
+
+/// Test 33: 128-bit values survive the split into FFI halves.
+#[test]
+fn test_128_bit_ffi_round_trip() {
+    use crate::ffi::{Int128, UInt128};
+
+    let max = UInt128::from(u128::MAX);
+    assert_eq!(
+        max,
+        UInt128 {
+            hi: u64::MAX,
+            lo: u64::MAX
+        }
+    );
+    assert_eq!(u128::from(max), u128::MAX);
+    let value = (7u128 << 64) | 9;
+    assert_eq!(UInt128::from(value), UInt128 { hi: 7, lo: 9 });
+    assert_eq!(u128::from(UInt128::from(value)), value);
+
+    for value in [i128::MIN, -1, 0, 1, i128::MAX] {
+        assert_eq!(i128::from(Int128::from(value)), value);
+    }
+    assert_eq!(
+        Int128::from(-1),
+        Int128 {
+            hi: u64::MAX,
+            lo: u64::MAX
+        }
+    );
+}
+
+/// Test 34: A dry run resolves a wildcard publish's recipients without running them.
+#[test]
+fn test_dry_run() {
+    let ran = Rc::new(RefCell::new(0));
+    let mut runner = TaskRunner::new();
+    for (topic, handler_id) in [
+        ("sensors.temp", "thermometer"),
+        ("sensors.humidity", "hygrometer"),
+        ("alerts.fire", "alarm"),
+    ] {
+        let ran = ran.clone();
+        runner
+            .msg_bus
+            .subscribe(Subscription::new(topic, handler_id, move || {
+                let ran = ran.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        *ran.borrow_mut() += 1;
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.dry_run = true;
+    runner.push(Task::Publish(PublishTask::new("sensors.*", Rc::new(()))));
+    runner.run();
+
+    assert_eq!(*ran.borrow(), 0);
+    let handler_ids: Vec<&str> = runner
+        .resolved_routes
+        .iter()
+        .map(|(topic, handler_id)| {
+            assert_eq!(topic, "sensors.*");
+            handler_id.as_str()
+        })
+        .collect();
+    assert_eq!(handler_ids, vec!["hygrometer", "thermometer"]);
+}
+
+/// Test 35: Each pipeline stage increments the number and passes it to the next.
+#[test]
+fn test_pipeline() {
+    fn increment() -> ActorFn {
+        Box::new(|| {
+            Box::pin(
+                #[coroutine]
+                |env: Envelope| {
+                    let n = *env.downcast_ref::<u32>().unwrap();
+                    Ok(Some(Rc::new(n + 1) as Rc<dyn Any>))
+                },
+            )
+        })
+    }
+
+    let result = Rc::new(RefCell::new(None));
+    let result_clone = result.clone();
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("result", "recorder", move || {
+            let result = result_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |env: Envelope| {
+                    *result.borrow_mut() = env.downcast_ref::<u32>().copied();
+                    Ok(None)
+                },
+            )
+        }));
+    Pipeline::new()
+        .stage("first", increment())
+        .stage("second", increment())
+        .stage("third", increment())
+        .output("result")
+        .register(&mut runner.msg_bus);
+
+    let reply = runner.request("first", Rc::new(5u32)).unwrap();
+
+    assert_eq!(reply.downcast_ref::<u32>(), Some(&6));
+    assert_eq!(*result.borrow(), Some(8));
+    assert!(runner.msg_bus.is_registered("second"));
+}
+
+/// Test 36: A send's endpoints are taken highest priority first under a `QueueStore` too.
+#[test]
+fn test_queue_store_send_priority() {
+    let ran = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::with_store(QueueStore::default());
+    for (handler_id, priority) in [("low", 1), ("high", 5)] {
+        let ran = ran.clone();
+        runner.msg_bus.register(
+            Subscription::new("work", handler_id, move || {
+                let ran = ran.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        ran.borrow_mut().push(handler_id);
+                        Ok(None)
+                    },
+                )
+            })
+            .with_priority(priority),
+        );
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Send {
+                    topic: "work".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*ran.borrow(), vec!["high", "low"]);
+}
+
+/// Test 37: A handler making a request is resumed with the reply as its next input.
+#[test]
+fn test_command_request() {
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received_clone = received.clone();
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("double", "doubler", || {
+            Box::pin(
+                #[coroutine]
+                |env: Envelope| {
+                    let n = *env.downcast_ref::<u32>().unwrap();
+                    Ok(Some(Rc::new(n * 2) as Rc<dyn Any>))
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |env: Envelope| {
+                received
+                    .borrow_mut()
+                    .push(*env.downcast_ref::<u32>().unwrap());
+                let reply = yield Command::Request {
+                    topic: "double".into(),
+                    msg: Rc::new(21u32),
+                };
+                received
+                    .borrow_mut()
+                    .push(*reply.downcast_ref::<u32>().unwrap());
+                let reply = yield Command::Request {
+                    topic: "missing".into(),
+                    msg: Rc::new(0u32),
+                };
+                assert!(reply.downcast_ref::<()>().is_some());
+                Ok(None)
+            },
+        ),
+        Rc::new(1u32),
+    )));
+    runner.run();
+
+    assert_eq!(*received_clone.borrow(), vec![1, 42]);
+    assert_eq!(runner.dead_letters.len(), 1);
+}
+
+/// Test 38: Cancelling one flow mid-run stops its handlers while another completes.
+#[test]
+fn test_cancel_flow() {
+    use crate::testing::{create_actor_handler, ActorAction, TraceEvent};
+
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for (id, next) in [
+        ("A", Some("B")),
+        ("B", Some("C")),
+        ("C", None),
+        ("X", Some("Y")),
+        ("Y", Some("Z")),
+        ("Z", None),
+    ] {
+        let actions = next
+            .map(|next| vec![ActorAction::Send(next.to_string())])
+            .unwrap_or_default();
+        runner.msg_bus.register(create_actor_handler(
+            id.to_string(),
+            id.to_string(),
+            actions,
+            trace.clone(),
+        ));
+    }
+    for root in ["A", "X"] {
+        let sub = runner.msg_bus.endpoint(root).unwrap();
+        let send = SendTask::for_subscription(sub, root, Rc::new(()));
+        runner.push(Task::Send(send));
+    }
+    let cancelled = runner.pending()[1].correlation_id;
+
+    // X runs first, sending to Y, which sends to Z.
+    runner.step();
+    runner.step();
+    assert_eq!(runner.cancel_flow(cancelled), 3);
+    assert_eq!(runner.active_flows(), 1);
+    runner.run();
+
+    let entered: Vec<String> = trace
+        .borrow()
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Enter(id) => Some(id.clone()),
+            TraceEvent::Exit(_) => None,
+        })
+        .collect();
+    assert_eq!(entered, vec!["X", "Y", "A", "B", "C"]);
+    assert!(!trace
+        .borrow()
+        .iter()
+        .any(|event| *event == TraceEvent::Exit("X".to_string())));
+    assert_eq!(runner.cancel_flow(cancelled), 0);
+
+    // Under interleaving, the publish's subscribers and what they spawned are held in its
+    // bands rather than the runner's store, and are cancelled with it all the same.
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .set_publish_order(PublishOrder::PriorityInterleaved);
+    for (id, topic, next) in [
+        ("log", "log", None),
+        ("X", "X", Some("log")),
+        ("a", "ticks", Some("log")),
+        ("b", "ticks", Some("log")),
+    ] {
+        let actions = next
+            .map(|next| vec![ActorAction::Send(next.to_string())])
+            .unwrap_or_default();
+        let sub = create_actor_handler(id.to_string(), topic.to_string(), actions, trace.clone());
+        if topic == "ticks" {
+            runner.msg_bus.subscribe(sub);
+        } else {
+            runner.msg_bus.register(sub);
+        }
+    }
+    let sub = runner.msg_bus.endpoint("X").unwrap();
+    let send = SendTask::for_subscription(sub, "X", Rc::new(()));
+    runner.push(Task::Send(send));
+    runner.push(Task::Publish(PublishTask::new("ticks", Rc::new(()))));
+    let cancelled = runner.pending()[1].correlation_id;
+
+    // a sends to log, which completes, then b sends to log.
+    for _ in 0..3 {
+        runner.step();
+    }
+    assert_eq!(runner.queue_depth("ticks"), 3);
+    assert_eq!(runner.queue_depth("log"), 1);
+    assert_eq!(runner.cancel_flow(cancelled), 4);
+    assert_eq!(runner.queue_depth("ticks"), 0);
+    assert_eq!(runner.queue_depth("log"), 0);
+    assert_eq!(runner.active_flows(), 1);
+    trace.borrow_mut().clear();
+    runner.run();
+
+    let enter = |id: &str| TraceEvent::Enter(id.to_string());
+    let exit = |id: &str| TraceEvent::Exit(id.to_string());
+    assert_eq!(
+        *trace.borrow(),
+        [enter("X"), enter("log"), exit("log"), exit("X")]
+    );
+    assert_eq!(runner.queue_depth("X"), 0);
+    assert_eq!(runner.active_flows(), 0);
+}
+
+/// Test 39: A missing-endpoint callback registers the endpoint on demand and retries.
+#[test]
+fn test_on_missing_endpoint() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let delivered_clone = delivered.clone();
+    let mut runner = TaskRunner::new();
+    runner.on_missing_endpoint(move |bus, topic, _msg| {
+        if topic == "ignored" {
+            return MissingEndpointAction::Drop;
+        }
+        let delivered = delivered_clone.clone();
+        bus.register(Subscription::new(
+            topic,
+            format!("lazy_{topic}"),
+            move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |env: Envelope| {
+                        delivered
+                            .borrow_mut()
+                            .push(*env.downcast_ref::<u32>().unwrap());
+                        Ok(None)
+                    },
+                )
+            },
+        ));
+        MissingEndpointAction::Retry
+    });
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                for (topic, n) in [("lazy", 1u32), ("ignored", 2), ("lazy", 3)] {
+                    yield Command::Send {
+                        topic: topic.into(),
+                        msg: Rc::new(n),
+                    };
+                }
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*delivered.borrow(), vec![1, 3]);
+    assert!(runner.msg_bus.is_registered("lazy"));
+    assert!(!runner.msg_bus.is_registered("ignored"));
+    assert!(runner.dead_letters.is_empty());
+}
+
+/// Test 40: Updating a subscription's priority changes the publish delivery order.
+#[test]
+fn test_update_priority() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for (handler_id, priority) in [("first", 2), ("second", 1)] {
+        let delivered = delivered.clone();
+        runner.msg_bus.subscribe(
+            Subscription::new("events", handler_id, move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(handler_id);
+                        Ok(None)
+                    },
+                )
+            })
+            .with_priority(priority),
+        );
+    }
+
+    runner.push(Task::Publish(PublishTask::new("events", Rc::new(()))));
+    runner.run();
+    assert_eq!(*delivered.borrow(), vec!["first", "second"]);
+
+    assert!(runner.msg_bus.update_priority("events", "second", 3));
+    assert!(!runner.msg_bus.update_priority("events", "missing", 3));
+    delivered.borrow_mut().clear();
+    runner.push(Task::Publish(PublishTask::new("events", Rc::new(()))));
+    runner.run();
+    assert_eq!(*delivered.borrow(), vec!["second", "first"]);
+}
+
+/// Test 41: Each step reports whether it resumed, spawned, completed or found nothing.
+#[test]
+fn test_step_outcome() {
+    let mut runner = TaskRunner::new();
+    assert_eq!(runner.step(), StepOutcome::Idle);
+
+    runner.msg_bus.subscribe(noop_subscription("news", "reader"));
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Publish {
+                    pattern: "news".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+
+    let outcomes: Vec<StepOutcome> = (0..6).map(|_| runner.step()).collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            StepOutcome::Resumed,
+            StepOutcome::Spawned,
+            StepOutcome::Completed,
+            StepOutcome::Completed,
+            StepOutcome::Completed,
+            StepOutcome::Idle,
+        ]
+    );
+}
+
+/// Test 42: A bounded publish delivers to `max_recipients` subscribers and records the rest
+/// as skipped.
+#[test]
+fn test_publish_bounded() {
+    let delivered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+
+    for i in 1..=4 {
+        let delivered = delivered.clone();
+        runner
+            .msg_bus
+            .subscribe(Subscription::new("ticks", format!("sub{}", i), move || {
+                let delivered = delivered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        delivered.borrow_mut().push(i);
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::PublishBounded {
+                    pattern: "ticks".into(),
+                    msg: Rc::new(()),
+                    max_recipients: 2,
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*delivered.borrow(), vec![1, 2]);
+    assert_eq!(
+        runner.publish_reports,
+        vec![PublishReport {
+            pattern: "ticks".into(),
+            delivered: 2,
+            failed: 0,
+            skipped: vec!["sub3".to_string(), "sub4".to_string()],
+        }]
+    );
+    assert_eq!(runner.dead_letters.len(), 2);
+}
+
+/// Test 43: Anonymous subscriptions get distinct handler IDs instead of replacing each other.
+#[test]
+fn test_subscribe_anon() {
+    let delivered = Rc::new(RefCell::new(0));
+    let mut runner = TaskRunner::new();
+
+    let ids: Vec<String> = (0..3)
+        .map(|_| {
+            let delivered = delivered.clone();
+            runner.msg_bus.subscribe_anon(
+                "news",
+                0,
+                Box::new(move || {
+                    let delivered = delivered.clone();
+                    Box::pin(
+                        #[coroutine]
+                        move |_msg: Envelope| {
+                            *delivered.borrow_mut() += 1;
+                            Ok(None)
+                        },
+                    )
+                }),
+            )
+        })
+        .collect();
+    assert_eq!(ids, vec!["news#1", "news#2", "news#3"]);
+
+    runner.push(Task::Publish(PublishTask::new("news", Rc::new(()))));
+    runner.run();
+    assert_eq!(*delivered.borrow(), 3);
+
+    runner.msg_bus.remove_subscription("news", &ids[1]);
+    assert!(!runner.msg_bus.is_subscribed("news", &ids[1]));
+}
+
+/// Test 44: A tee runs only once the teeing handler has carried on, unlike a send.
+#[test]
+fn test_tee() {
+    let ran = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for topic in ["audit", "next"] {
+        let ran = ran.clone();
+        runner
+            .msg_bus
+            .register(Subscription::new(topic, topic, move || {
+                let ran = ran.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        ran.borrow_mut().push(topic);
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Tee {
+                    topic: "audit".into(),
+                    msg: Rc::new(()),
+                };
+                yield Command::Send {
+                    topic: "next".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*ran.borrow(), vec!["next", "audit"]);
+    assert!(runner.dead_letters.is_empty());
+}
+
+/// Test 45: A send to a topic without endpoints goes to the default endpoint, which sees
+/// the topic it was sent to.
+#[test]
+fn test_default_endpoint() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    let seen_by_handler = seen.clone();
+    runner
+        .msg_bus
+        .set_default_endpoint(Subscription::new("fallback", "fallback", move || {
+            let seen = seen_by_handler.clone();
+            Box::pin(
+                #[coroutine]
+                move |msg: Envelope| {
+                    seen.borrow_mut().push(msg.matched_topic.to_string());
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Send {
+                    topic: "unknown".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*seen.borrow(), vec!["unknown".to_string()]);
+    assert!(runner.dead_letters.is_empty());
+}
+
+/// Test 46: A publish's remaining work counts down as it spawns a send per subscriber.
+#[test]
+fn test_task_remaining() {
+    let mut runner = TaskRunner::new();
+    for i in 0..5 {
+        runner
+            .msg_bus
+            .subscribe(noop_subscription("news", &format!("reader{}", i)));
+    }
+    runner.push(Task::Publish(PublishTask::new("news", Rc::new(()))));
+
+    let publish_remaining = |runner: &TaskRunner| {
+        runner
+            .tasks
+            .iter()
+            .find(|task| matches!(task, Task::Publish(_)))
+            .map(|task| task.remaining(&runner.msg_bus))
+    };
+
+    let mut remaining = vec![publish_remaining(&runner).unwrap()];
+    while runner.step() != StepOutcome::Idle {
+        if let Some(n) = publish_remaining(&runner) {
+            if remaining.last() != Some(&n) {
+                remaining.push(n);
+            }
+        }
+    }
+    assert_eq!(remaining, vec![5, 4, 3, 2, 1, 0]);
+
+    let send = Task::Send(SendTask::new(
+        "news",
+        (noop_subscription("news", "x").actor_fn)(),
+        Rc::new(()),
+    ));
+    assert_eq!(send.remaining(&runner.msg_bus), 1);
+}
+
+/// Test 47: A resume argument factory replaces the message a handler is resumed with.
+#[test]
+fn test_resume_arg_fn() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    let mut next = 0u32;
+    runner.resume_arg_fn(move |_send| {
+        next += 1;
+        Rc::new(next)
+    });
+
+    let seen_by_handler = seen.clone();
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |mut msg: Envelope| {
+                for _ in 0..2 {
+                    seen_by_handler
+                        .borrow_mut()
+                        .push(*msg.downcast_ref::<u32>().unwrap());
+                    msg = yield Command::Query {
+                        query: BusQuery::TopicExists("root".into()),
+                        reply: Rc::new(RefCell::new(None)),
+                    };
+                }
+                seen_by_handler
+                    .borrow_mut()
+                    .push(*msg.downcast_ref::<u32>().unwrap());
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+}
+
+/// Test 48: A handler matched by a publish under two subscriptions runs once, and the
+/// repeated match is reported.
+#[test]
+fn test_duplicate_match() {
+    let runs = Rc::new(RefCell::new(0));
+    let mut runner = TaskRunner::new();
+    runner.warn_duplicate_matches = true;
+    for topic in ["news.*", "news.sports"] {
+        let runs = runs.clone();
+        runner
+            .msg_bus
+            .subscribe(Subscription::new(topic, "reader", move || {
+                let runs = runs.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        *runs.borrow_mut() += 1;
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    runner.push(Task::Publish(PublishTask::new("news.sports", Rc::new(()))));
+    runner.run();
+
+    assert_eq!(*runs.borrow(), 1);
+    assert_eq!(
+        runner.warnings,
+        vec![RunnerWarning::DuplicateMatch {
+            pattern: "news.sports".into(),
+            topic: "news.sports".into(),
+            handler_id: "reader".to_string(),
+        }]
+    );
+}
+
+/// Test 49: Headers set on the root send reach handlers several hops downstream, along
+/// with those added on the way.
+#[test]
+fn test_headers() {
+    let seen = Rc::new(RefCell::new(None));
+    let mut runner = TaskRunner::new();
+
+    runner
+        .msg_bus
+        .register(Subscription::new("hop1", "hop1", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::SendWithHeaders {
+                        topic: "hop2".into(),
+                        msg: Rc::new(()),
+                        headers: Headers::from([("hop".to_string(), "1".to_string())]),
+                    };
+                    Ok(None)
+                },
+            )
+        }));
+    runner
+        .msg_bus
+        .register(Subscription::new("hop2", "hop2", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::Send {
+                        topic: "hop3".into(),
+                        msg: Rc::new(()),
+                    };
+                    Ok(None)
+                },
+            )
+        }));
+    let seen_by_handler = seen.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("hop3", "hop3", move || {
+            let seen = seen_by_handler.clone();
+            Box::pin(
+                #[coroutine]
+                move |msg: Envelope| {
+                    *seen.borrow_mut() = Some((*msg.headers).clone());
+                    Ok(None)
+                },
+            )
+        }));
+
+    let root = SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Send {
+                    topic: "hop1".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )
+    .with_headers(Headers::from([("trace".to_string(), "abc".to_string())]));
+    runner.push(Task::Send(root));
+    runner.run();
+
+    let headers = seen.borrow_mut().take().unwrap();
+    assert_eq!(headers.get("trace").map(String::as_str), Some("abc"));
+    assert_eq!(headers.get("hop").map(String::as_str), Some("1"));
+}
+
+/// Test 50: Each command reports its own kind.
+#[test]
+fn test_command_kind() {
+    let msg = || -> Rc<dyn Any> { Rc::new(()) };
+    let cases = vec![
+        (
+            Command::Send {
+                topic: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::Send,
+        ),
+        (
+            Command::SendOwned {
+                topic: "t".into(),
+                msg: Box::new(()),
+            },
+            CommandKind::SendOwned,
+        ),
+        (
+            Command::SendWithHeaders {
+                topic: "t".into(),
+                msg: msg(),
+                headers: Headers::new(),
+            },
+            CommandKind::SendWithHeaders,
+        ),
+        (
+            Command::SendPrioritized {
+                topic: "t".into(),
+                msg: msg(),
+                msg_priority: 1,
+            },
+            CommandKind::SendPrioritized,
+        ),
+        (
+            Command::SendBest {
+                topic: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::SendBest,
+        ),
+        (
+            Command::Tee {
+                topic: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::Tee,
+        ),
+        (
+            Command::SendAndNotify {
+                topic: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::SendAndNotify,
+        ),
+        (
+            Command::Publish {
+                pattern: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::Publish,
+        ),
+        (
+            Command::PublishPrioritized {
+                pattern: "t".into(),
+                msg: msg(),
+                msg_priority: 1,
+            },
+            CommandKind::PublishPrioritized,
+        ),
+        (
+            Command::PublishTopN {
+                pattern: "t".into(),
+                msg: msg(),
+                n: 1,
+            },
+            CommandKind::PublishTopN,
+        ),
+        (
+            Command::PublishBounded {
+                pattern: "t".into(),
+                msg: msg(),
+                max_recipients: 1,
+            },
+            CommandKind::PublishBounded,
+        ),
+        (
+            Command::Register(noop_subscription("t", "h")),
+            CommandKind::Register,
+        ),
+        (Command::Deregister("t".into()), CommandKind::Deregister),
+        (
+            Command::Subscribe(noop_subscription("t", "h")),
+            CommandKind::Subscribe,
+        ),
+        (
+            Command::Unsubscribe(("t".into(), "h".to_string())),
+            CommandKind::Unsubscribe,
+        ),
+        (
+            Command::Request {
+                topic: "t".into(),
+                msg: msg(),
+            },
+            CommandKind::Request,
+        ),
+        (
+            Command::RequestInto {
+                topic: "t".into(),
+                msg: msg(),
+                reply: ReplyCell::new(),
+            },
+            CommandKind::RequestInto,
+        ),
+        (
+            Command::Query {
+                query: BusQuery::TopicExists("t".into()),
+                reply: Rc::new(RefCell::new(None)),
+            },
+            CommandKind::Query,
+        ),
+        (
+            Command::SendAfter {
+                topic: "t".into(),
+                msg: msg(),
+                delay: 1,
+            },
+            CommandKind::SendAfter,
+        ),
+        (Command::Requeue { msg: msg() }, CommandKind::Requeue),
+        (
+            Command::Fork {
+                branches: Vec::new(),
+                join_token: 1,
+            },
+            CommandKind::Fork,
+        ),
+        (Command::Many(Vec::new()), CommandKind::Many),
+    ];
+    for (cmd, kind) in cases {
+        assert_eq!(cmd.kind(), kind);
+        assert_eq!(cmd.name(), kind.name());
+    }
+}
+
+/// Test 51: A handler takes a typed reply from the cell it passed with a request.
+#[test]
+fn test_reply_cell() {
+    let result = Rc::new(RefCell::new(None));
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(Subscription::new("double", "doubler", || {
+            Box::pin(
+                #[coroutine]
+                |msg: Envelope| {
+                    let n = *msg.downcast_ref::<u32>().unwrap();
+                    Ok(Some(Rc::new(n * 2) as Rc<dyn Any>))
+                },
+            )
+        }));
+
+    let result_in_handler = result.clone();
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |_msg: Envelope| {
+                let reply = ReplyCell::<u32>::new();
+                yield Command::RequestInto {
+                    topic: "double".into(),
+                    msg: Rc::new(21u32),
+                    reply: reply.erased(),
+                };
+                *result_in_handler.borrow_mut() = reply.take();
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*result.borrow(), Some(42));
+}
+
+/// Test 52: A bus renders the same whatever order its routes were added in.
+#[test]
+fn test_bus_display_sorted() {
+    let routes = [("b", "h2"), ("a", "h3"), ("b", "h1"), ("c", "h1")];
+    let build = |routes: &[(&str, &str)]| {
+        let mut bus = MessageBus::new();
+        for (topic, handler_id) in routes {
+            bus.register(noop_subscription(topic, handler_id));
+            bus.subscribe(noop_subscription(topic, handler_id));
+        }
+        bus
+    };
+    let bus = build(&routes);
+    let reversed: Vec<_> = routes.iter().rev().copied().collect();
+
+    let rendered = bus.to_string();
+    assert_eq!(rendered, bus.to_string());
+    assert_eq!(rendered, build(&reversed).to_string());
+    assert_eq!(
+        rendered,
+        "Messagebus\n\
+         endpoints:\n\
+         a: sub::a:h3\n\
+         b: sub::b:h1\n\
+         b: sub::b:h2\n\
+         c: sub::c:h1\n\
+         subscriptions:\n\
+         sub::a:h3: a\n\
+         sub::b:h1: b\n\
+         sub::b:h2: b\n\
+         sub::c:h1: c\n"
+    );
+}
+
+/// Test 53: A send-and-notify reaches the endpoint first, then the topic's subscribers.
+#[test]
+fn test_send_and_notify() {
+    let entered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    let handler = |handler_id: &'static str| -> ActorFn {
+        let entered = entered.clone();
+        Box::new(move || {
+            let entered = entered.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    entered.borrow_mut().push(handler_id);
+                    Ok(None)
+                },
+            )
+        })
+    };
+    runner
+        .msg_bus
+        .register(Subscription::new("orders", "primary", handler("primary")));
+    for handler_id in ["audit", "metrics"] {
+        runner
+            .msg_bus
+            .subscribe(Subscription::new("orders", handler_id, handler(handler_id)));
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::SendAndNotify {
+                    topic: "orders".into(),
+                    msg: Rc::new(()),
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*entered.borrow(), vec!["primary", "audit", "metrics"]);
+    assert!(runner.dead_letters.is_empty());
+}
+
+/// Test 54: The cost of a publish counts its recipients and their priority range.
+#[test]
+fn test_publish_cost() {
+    let mut bus = MessageBus::new();
+    for (handler_id, priority) in [("a", 3), ("b", 7), ("c", 1), ("d", 5)] {
+        bus.subscribe(noop_subscription("prices", handler_id).with_priority(priority));
+    }
+    bus.subscribe(noop_subscription("volumes", "e"));
+
+    assert_eq!(
+        bus.publish_cost("prices"),
+        PublishCost {
+            recipients: 4,
+            max_priority: 7,
+            min_priority: 1,
+        }
+    );
+    assert_eq!(bus.publish_cost("nothing"), PublishCost::default());
+
+    // The range doesn't depend on delivery being by priority.
+    for order in [PublishOrder::Registration, PublishOrder::HandlerIdAsc] {
+        bus.set_publish_order(order);
+        assert_eq!(bus.publish_cost("prices").max_priority, 7);
+        assert_eq!(bus.publish_cost("prices").min_priority, 1);
+    }
+}
+
+/// Test 55: One yield of a batch spawns every send in it, in order, and nested batches
+/// are flattened.
+#[test]
+fn test_command_many() {
+    let entered = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    for topic in ["a", "b", "c"] {
+        let entered = entered.clone();
+        runner
+            .msg_bus
+            .register(Subscription::new(topic, topic, move || {
+                let entered = entered.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |_msg: Envelope| {
+                        entered.borrow_mut().push(topic);
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+
+    let send = |topic: &str| Command::Send {
+        topic: topic.into(),
+        msg: Rc::new(()),
+    };
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |_msg: Envelope| {
+                yield Command::Many(vec![
+                    send("a"),
+                    Command::Many(vec![Command::Many(vec![send("b")])]),
+                ]);
+                yield send("c");
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*entered.borrow(), vec!["a", "b", "c"]);
+}
+
+/// Test 56: A delayed send is delivered once its delay has passed on the logical clock.
+#[test]
+fn test_send_after() {
+    let recorder = testing::DeliveryRecorder::new();
+    let mut runner = TaskRunner::new();
+    runner
+        .msg_bus
+        .register(recorder.subscription("later", "later"));
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::SendAfter {
+                    topic: "later".into(),
+                    msg: Rc::new(()),
+                    delay: 3,
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+
+    // The root yields on tick 1 and completes on tick 2, and the send is due on tick 4.
+    let mut delivered_at = None;
+    while delivered_at.is_none() && runner.now() < 10 {
+        runner.step();
+        if recorder.times_called() > 0 {
+            delivered_at = Some(runner.now());
+        }
+    }
+    assert_eq!(delivered_at, Some(4));
+    assert!(runner.tasks.is_empty());
+}
+
+/// Test 57: Dead letters record why each message could not be delivered.
+#[test]
+fn test_dead_letter_reasons() {
+    let recorder = testing::DeliveryRecorder::new();
+    let mut runner = TaskRunner::new();
+    for handler_id in ["first", "second"] {
+        runner
+            .msg_bus
+            .subscribe(recorder.subscription("ticks", handler_id));
+    }
+
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            |_msg: Envelope| {
+                yield Command::Send {
+                    topic: "nowhere".into(),
+                    msg: Rc::new(()),
+                };
+                yield Command::PublishBounded {
+                    pattern: "ticks".into(),
+                    msg: Rc::new(()),
+                    max_recipients: 1,
+                };
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(recorder.times_called(), 1);
+    let letters: Vec<_> = runner
+        .dead_letters
+        .iter()
+        .map(|letter| (letter.topic.as_str(), letter.reason))
+        .collect();
+    assert_eq!(
+        letters,
+        vec![
+            ("nowhere", DeadLetterReason::NoEndpoint),
+            ("ticks", DeadLetterReason::OverCapacity),
+        ]
+    );
+}
+
+/// Test 58: Collecting subscriptions into a bus subscribes each of them.
+#[test]
+fn test_collect_bus() {
+    let bus: MessageBus = [("prices", "sub1"), ("prices", "sub2"), ("trades", "sub3")]
+        .into_iter()
+        .map(|(topic, handler_id)| noop_subscription(topic, handler_id))
+        .collect();
+
+    assert!(bus.is_subscribed("prices", "sub1"));
+    assert!(bus.is_subscribed("prices", "sub2"));
+    assert!(bus.is_subscribed("trades", "sub3"));
+    assert!(!bus.is_registered("prices"));
+
+    let bus = MessageBus::from_endpoints([noop_subscription("orders", "handler")]);
+    assert!(bus.is_registered("orders"));
+    assert_eq!(bus.route_kind("orders"), RouteKind::Endpoint);
+}
+
+/// Test 59: A handler that requeues its message lets other tasks run before it is
+/// delivered the message again.
+#[test]
+fn test_requeue() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    let log_clone = log.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("other", "other", move || {
+            let log = log_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    log.borrow_mut().push("other".to_string());
+                    Ok(None)
+                },
+            )
+        }));
+    let log_clone = log.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("waiting", "waiting", move || {
+            let log = log_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |mut msg: Envelope| loop {
+                    let attempt = *msg.downcast_ref::<u32>().unwrap();
+                    log.borrow_mut().push(format!("waiting {attempt}"));
+                    if attempt > 1 {
+                        return Ok(None);
+                    }
+                    msg = yield Command::Requeue {
+                        msg: Rc::new(attempt + 1),
+                    };
+                },
+            )
+        }));
+
+    let push = |runner: &mut TaskRunner, topic: &str, msg: Rc<dyn Any>| {
+        let sub = runner.msg_bus.endpoint(topic).unwrap();
+        let send = SendTask::for_subscription(sub, topic, msg);
+        runner.push(Task::Send(send));
+    };
+    push(&mut runner, "other", Rc::new(()));
+    push(&mut runner, "waiting", Rc::new(1u32));
+    runner.run();
+
+    assert_eq!(*log.borrow(), vec!["waiting 1", "other", "waiting 2"]);
+    assert!(runner.dead_letters.is_empty());
+
+    // Past the limit, the handler is abandoned rather than delivered the message again.
+    log.borrow_mut().clear();
+    runner.max_requeues = 0;
+    push(&mut runner, "waiting", Rc::new(1u32));
+    runner.run();
+
+    assert_eq!(*log.borrow(), vec!["waiting 1"]);
+    assert_eq!(runner.dead_letters.len(), 1);
+    assert_eq!(
+        runner.dead_letters[0].reason,
+        DeadLetterReason::RequeueLimit
+    );
+    assert_eq!(
+        runner.warnings,
+        vec![RunnerWarning::RequeueLimit {
+            topic: "waiting".into(),
+            handler_id: "waiting".to_string(),
+        }]
+    );
+}
+
+/// Test 60: A priority store delivers messages pending for a handler in order of message
+/// priority, whatever order they were sent in.
+#[test]
+fn test_prioritized_sends() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::with_store(PriorityStore::default());
+    let log_clone = log.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("work", "work", move || {
+            let log = log_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |msg: Envelope| {
+                    log.borrow_mut().push(*msg.downcast_ref::<u32>().unwrap());
+                    Ok(None)
+                },
+            )
+        }));
+    runner
+        .msg_bus
+        .register(Subscription::new("root", "root", || {
+            Box::pin(
+                #[coroutine]
+                |_msg: Envelope| {
+                    yield Command::SendPrioritized {
+                        topic: "work".into(),
+                        msg: Rc::new(1u32),
+                        msg_priority: 1,
+                    };
+                    yield Command::SendPrioritized {
+                        topic: "work".into(),
+                        msg: Rc::new(2u32),
+                        msg_priority: 5,
+                    };
+                    yield Command::Send {
+                        topic: "work".into(),
+                        msg: Rc::new(3u32),
+                    };
+                    Ok(None)
+                },
+            )
+        }));
+
+    // The root outranks the sends it makes, so it finishes sending before any is taken.
+    let sub = runner.msg_bus.endpoint("root").unwrap();
+    let send = SendTask::for_subscription(sub, "root", Rc::new(())).with_msg_priority(9);
+    runner.push(Task::Send(send));
+    runner.run();
+
+    assert_eq!(*log.borrow(), vec![2, 1, 3]);
+}
+
+/// Test 61: A failing handler under `Supervision::Escalate` has its error delivered to the
+/// supervisor topic, and one under `Supervision::Restart` is run once more.
+#[test]
+fn test_supervision() {
+    let attempts = Rc::new(RefCell::new(0));
+    let failures = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::new();
+    let attempts_clone = attempts.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("work", "worker", move || {
+            let attempts = attempts_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    *attempts.borrow_mut() += 1;
+                    Err(HandlerError("disk full".to_string()))
+                },
+            )
+        }));
+    let failures_clone = failures.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("supervisor", "supervisor", move || {
+            let failures = failures_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |msg: Envelope| {
+                    let failure = msg.downcast_ref::<HandlerFailure>().unwrap();
+                    failures.borrow_mut().push(failure.clone());
+                    Ok(None)
+                },
+            )
+        }));
+
+    runner
+        .msg_bus
+        .set_supervision("worker", Supervision::Escalate("supervisor".into()));
+    assert!(runner.request("work", Rc::new(())).is_none());
+    assert_eq!(*attempts.borrow(), 1);
+    assert_eq!(
+        *failures.borrow(),
+        vec![HandlerFailure {
+            topic: "work".into(),
+            handler_id: "worker".to_string(),
+            error: HandlerError("disk full".to_string()),
+        }]
+    );
+
+    // Restarted once, after which the failure stands.
+    runner
+        .msg_bus
+        .set_supervision("worker", Supervision::Restart);
+    assert!(runner.request("work", Rc::new(())).is_none());
+    assert_eq!(*attempts.borrow(), 3);
+    assert_eq!(failures.borrow().len(), 1);
+}
+
+/// Test 62: A handler that forks is resumed only once both branches have completed, even
+/// with a queue store, which would otherwise resume it before the branches run.
+#[test]
+fn test_fork_join() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::with_store(QueueStore::default());
+    for branch in ["left", "right"] {
+        let log_clone = log.clone();
+        runner
+            .msg_bus
+            .register(Subscription::new(branch, branch, move || {
+                let log = log_clone.clone();
+                Box::pin(
+                    #[coroutine]
+                    move |msg: Envelope| {
+                        log.borrow_mut().push(*msg.downcast_ref::<&str>().unwrap());
+                        Ok(None)
+                    },
+                )
+            }));
+    }
+    let log_clone = log.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("scatter", "gather", move || {
+            let log = log_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |_msg: Envelope| {
+                    log.borrow_mut().push("fork");
+                    yield Command::Fork {
+                        branches: vec![
+                            ("left".into(), Rc::new("left") as Rc<dyn Any>),
+                            ("right".into(), Rc::new("right") as Rc<dyn Any>),
+                        ],
+                        join_token: 7,
+                    };
+                    log.borrow_mut().push("joined");
+                    Ok(None)
+                },
+            )
+        }));
+
+    let sub = runner.msg_bus.endpoint("scatter").unwrap();
+    runner.push(Task::Send(SendTask::for_subscription(
+        sub,
+        "scatter",
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*log.borrow(), vec!["fork", "left", "right", "joined"]);
+}
+
+/// Test 63: A handler making a request with a queue store, which would otherwise resume it
+/// straight away, is resumed only with the reply, after the tasks the request spawned.
+#[test]
+fn test_command_request_queue_store() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = TaskRunner::with_store(QueueStore::default());
+    let log_clone = log.clone();
+    runner
+        .msg_bus
+        .register(Subscription::new("log", "logger", move || {
+            let log = log_clone.clone();
+            Box::pin(
+                #[coroutine]
+                move |msg: Envelope| {
+                    log.borrow_mut().push(*msg.downcast_ref::<u32>().unwrap());
+                    Ok(None)
+                },
+            )
+        }));
+    runner
+        .msg_bus
+        .register(Subscription::new("double", "doubler", || {
+            Box::pin(
+                #[coroutine]
+                |env: Envelope| {
+                    let n = *env.downcast_ref::<u32>().unwrap();
+                    yield Command::Send {
+                        topic: "log".into(),
+                        msg: Rc::new(n),
+                    };
+                    Ok(Some(Rc::new(n * 2) as Rc<dyn Any>))
+                },
+            )
+        }));
+
+    let log_clone = log.clone();
+    runner.push(Task::Send(SendTask::new(
+        "root",
+        Box::pin(
+            #[coroutine]
+            move |_env: Envelope| {
+                let reply = yield Command::Request {
+                    topic: "double".into(),
+                    msg: Rc::new(21u32),
+                };
+                log_clone
+                    .borrow_mut()
+                    .push(*reply.downcast_ref::<u32>().unwrap());
+                Ok(None)
+            },
+        ),
+        Rc::new(()),
+    )));
+    runner.run();
+
+    assert_eq!(*log.borrow(), vec![21, 42]);
+    assert_eq!(runner.active_flows(), 0);
+}
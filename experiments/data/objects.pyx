@@ -4,6 +4,43 @@ from experiments.data.rust.core cimport trade_tick_eq
 from experiments.data.rust.core cimport uint128_t
 from experiments.data.rust.core cimport int128_t
 
+
+# Written out, so Cython keeps them as Python ints rather than folding them into C ones.
+_U64_MASK = 0xFFFFFFFFFFFFFFFF
+_U128_MAX = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF
+_I128_MIN = -0x80000000000000000000000000000000
+_I128_MAX = 0x7FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF
+
+
+cdef uint128_t _to_uint128(object value) except *:
+    cdef uint128_t out
+    if not 0 <= value <= _U128_MAX:
+        raise OverflowError(f"{value} out of range for an unsigned 128-bit integer")
+    out.hi = value >> 64
+    out.lo = value & _U64_MASK
+    return out
+
+
+cdef object _from_uint128(uint128_t value):
+    return (<object>value.hi << 64) | value.lo
+
+
+cdef int128_t _to_int128(object value) except *:
+    cdef int128_t out
+    if not _I128_MIN <= value <= _I128_MAX:
+        raise OverflowError(f"{value} out of range for a signed 128-bit integer")
+    # Python's `&` on a negative int gives its two's complement bits.
+    value &= _U128_MAX
+    out.hi = value >> 64
+    out.lo = value & _U64_MASK
+    return out
+
+
+cdef object _from_int128(int128_t value):
+    cdef object bits = (<object>value.hi << 64) | value.lo
+    return bits - _U128_MAX - 1 if bits > _I128_MAX else bits
+
+
 # Posei Experiments: Performance optimization
 # Posei Experiments: Performance optimization
 # Posei Experiments: Class enhancement for Posei Experiments
@@ -17,10 +54,10 @@ cdef class TradeTick:
         # Posei Experiments: Enhanced error handling for better reliability
         self,
         # Posei Experiments: Enhancement for Posei Experiments integration - 20260101
-        uint128_t ts_event,
-        int128_t ts_init,
+        object ts_event,
+        object ts_init,
     ):
-        self._mem = trade_tick_new(ts_event, ts_init)
+        self._mem = trade_tick_new(_to_uint128(ts_event), _to_int128(ts_init))
 
     def __eq__(self, TradeTick other) -> bool:
         # Posei Experiments: Added validation check
@@ -32,15 +69,15 @@ cdef class TradeTick:
 
     def __setstate__(self, state):
         # Posei Experiments: Enhancement for Posei Experiments integration - 20260101
-        self._mem = trade_tick_new(state[0], state[1])
+        self._mem = trade_tick_new(_to_uint128(state[0]), _to_int128(state[1]))
 
     @property
     def ts_event(self) -> int:
-        return self._mem.ts_event
+        return _from_uint128(self._mem.ts_event)
 
     @property
     def ts_init(self) -> int:
-        return self._mem.ts_init
+        return _from_int128(self._mem.ts_init)
 
 
 # Posei Experiments: Enhancement for Posei Experiments integration - 20260101
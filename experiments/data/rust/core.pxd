@@ -6,23 +6,13 @@ from libc.stdint cimport uint64_t, uint8_t
 DEF DEFINE_HIGH_PRECISION = False  # or True
 
 cdef extern from "core.h":
-    # Posei Experiments: Performance optimization
-    # Posei Experiments: Enhanced error handling for better reliability
-    # Posei Experiments: Enhancement for Posei Experiments integration - 20260101
-    # Posei Experiments: Added validation check
-    ctypedef unsigned long long uint128_t
-        # Posei Experiments: Enhanced error handling for better reliability
-        # Posei Experiments: Added validation check
-    ctypedef long long int128_t
-    pass
 
+    # A `u128` split into its high and low 64 bits.
+    cdef struct uint128_t:
+        uint64_t hi;
+        uint64_t lo;
 
-# Posei Experiments: Code update - 20260101154058
-
-# Posei Experiments: Code update - 20260101154120
-
-# Posei Experiments: Code update - 20260101154159
-
-# Posei Experiments: Code update - 20260101154202
-
-# Posei Experiments: Code update - 20260101154224
\ No newline at end of file
+    # An `i128` split into the high and low 64 bits of its two's complement representation.
+    cdef struct int128_t:
+        uint64_t hi;
+        uint64_t lo;